@@ -0,0 +1,410 @@
+use proc_macro::TokenStream;
+use quote::{format_ident, quote};
+use syn::ext::IdentExt;
+use syn::punctuated::Punctuated;
+use syn::{
+	Attribute, Data, DeriveInput, Expr, Fields, Ident, Lit, LitStr, Meta, Path, Token, Type,
+	parse_macro_input,
+};
+
+// api の Collection を実装する
+//
+// #[derive(Collection)]
+// #[collection(name = "page", migrations = video_migrations)]
+// struct Video {
+//     #[collection(id)]
+//     id: Uuid,
+//     #[collection(parent = Channel)]
+//     channel: String,
+//     #[collection(index)]
+//     name: String,
+//     #[collection(unique)]
+//     slug: String,
+// }
+//
+// 構造体に付けるもの
+//   name: コレクションの名前、省略すると構造体の名前を snake_case にしたもの
+//   migrations: Collection::migrations() として返す &'static [Migration]
+// フィールドに付けるもの
+//   id: ドキュメントIDにするフィールド、省略すると id という名前のフィールド、to_string() で文字列にする
+//   parent = 型: このフィールドの値をIDとする 型 のドキュメントの下のサブコレクションに書く
+//   index: クエリに使うフィールド、名前を大文字にした Field の定数を作る
+//   unique: 同じ値のドキュメントが2つあってはいけないフィールド、index も兼ねる
+// Field の定数と unique_fields のフィールド名は保存される JSON のものにするので、
+// #[serde(rename = ..)] と構造体の #[serde(rename_all = ..)] を読む、r#type のような名前は r# を外す
+#[proc_macro_derive(Collection, attributes(collection))]
+pub fn derive_collection(input: TokenStream) -> TokenStream {
+	let input = parse_macro_input!(input as DeriveInput);
+	match expand(&input) {
+		Ok(tokens) => tokens.into(),
+		Err(e) => e.to_compile_error().into(),
+	}
+}
+
+struct Field<'a> {
+	ident: &'a Ident,
+	// 保存される JSON でのフィールド名
+	name: String,
+	ty: &'a Type,
+	id: bool,
+	parent: Option<Path>,
+	index: bool,
+	unique: bool,
+}
+
+fn expand(input: &DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+	let ident = &input.ident;
+	let mut name = LitStr::new(&snake_case(&ident.to_string()), ident.span());
+	let mut migrations: Option<Path> = None;
+	for attr in input
+		.attrs
+		.iter()
+		.filter(|a| a.path().is_ident("collection"))
+	{
+		attr.parse_nested_meta(|meta| {
+			if meta.path.is_ident("name") {
+				name = meta.value()?.parse()?;
+			} else if meta.path.is_ident("migrations") {
+				migrations = Some(meta.value()?.parse()?);
+			} else {
+				return Err(meta.error("expected `name` or `migrations`"));
+			}
+			Ok(())
+		})?;
+	}
+	let Data::Struct(data) = &input.data else {
+		return Err(syn::Error::new_spanned(
+			ident,
+			"Collection can only be derived for structs",
+		));
+	};
+	let Fields::Named(named) = &data.fields else {
+		return Err(syn::Error::new_spanned(
+			ident,
+			"Collection needs named fields",
+		));
+	};
+	let rename_all = serde_name(&input.attrs, "rename_all")?;
+	let mut fields = vec![];
+	for field in &named.named {
+		let ident = field.ident.as_ref().expect("named field");
+		let name = match serde_name(&field.attrs, "rename")? {
+			Some(name) => name.value(),
+			None => match &rename_all {
+				Some(rule) => rename(&ident.unraw().to_string(), rule)?,
+				None => ident.unraw().to_string(),
+			},
+		};
+		let mut f = Field {
+			ident,
+			name,
+			ty: &field.ty,
+			id: false,
+			parent: None,
+			index: false,
+			unique: false,
+		};
+		for attr in field
+			.attrs
+			.iter()
+			.filter(|a| a.path().is_ident("collection"))
+		{
+			attr.parse_nested_meta(|meta| {
+				if meta.path.is_ident("id") {
+					f.id = true;
+				} else if meta.path.is_ident("parent") {
+					f.parent = Some(meta.value()?.parse()?);
+				} else if meta.path.is_ident("index") {
+					f.index = true;
+				} else if meta.path.is_ident("unique") {
+					f.unique = true;
+				} else {
+					return Err(meta.error("expected `id`, `parent`, `index` or `unique`"));
+				}
+				Ok(())
+			})?;
+		}
+		fields.push(f);
+	}
+
+	let id = match fields.iter().filter(|f| f.id).collect::<Vec<_>>()[..] {
+		[f] => f.ident,
+		[] => match fields.iter().find(|f| f.ident.unraw() == "id") {
+			Some(f) => f.ident,
+			None => {
+				return Err(syn::Error::new_spanned(
+					ident,
+					"no `id` field, mark the document id with #[collection(id)]",
+				));
+			}
+		},
+		[_, f, ..] => {
+			return Err(syn::Error::new_spanned(
+				f.ident,
+				"only one field can be #[collection(id)]",
+			));
+		}
+	};
+	let parent = match fields
+		.iter()
+		.filter(|f| f.parent.is_some())
+		.collect::<Vec<_>>()[..]
+	{
+		[] => None,
+		[f] => {
+			let field = f.ident;
+			let ty = &f.parent;
+			Some(quote! {
+				fn parent(&self) -> crate::collection::Parent {
+					crate::collection::Parent::of::<#ty>(&self.#field.to_string())
+				}
+			})
+		}
+		[_, f, ..] => {
+			return Err(syn::Error::new_spanned(
+				f.ident,
+				"only one field can be #[collection(parent)]",
+			));
+		}
+	};
+	let migrations = migrations.map(|path| {
+		quote! {
+			fn migrations() -> &'static [crate::migration::Migration] {
+				#path
+			}
+		}
+	});
+	let unique: Vec<_> = fields
+		.iter()
+		.filter(|f| f.unique)
+		.map(|f| &f.name)
+		.collect();
+	let unique = (!unique.is_empty()).then(|| {
+		quote! {
+			fn unique_fields() -> &'static [&'static str] {
+				&[#(#unique),*]
+			}
+		}
+	});
+	let consts = fields.iter().filter(|f| f.index || f.unique).map(|f| {
+		let name = &f.name;
+		let constant = format_ident!("{}", f.ident.unraw().to_string().to_uppercase());
+		let ty = f.ty;
+		quote! {
+			pub const #constant: crate::query::Field<Self, #ty> = crate::query::Field::new(#name);
+		}
+	});
+
+	let (generics, arguments, bounds) = input.generics.split_for_impl();
+	Ok(quote! {
+		impl #generics crate::collection::Collection for #ident #arguments #bounds {
+			fn collection_name() -> &'static str {
+				#name
+			}
+			fn document_id(&self) -> String {
+				self.#id.to_string()
+			}
+			#migrations
+			#parent
+			#unique
+		}
+		impl #generics #ident #arguments #bounds {
+			#(#consts)*
+		}
+	})
+}
+
+// #[serde(key = "...")] か #[serde(key(serialize = "..."))] の値、serde の他の設定は読み飛ばす
+fn serde_name(attrs: &[Attribute], key: &str) -> syn::Result<Option<LitStr>> {
+	let mut name = None;
+	for attr in attrs.iter().filter(|a| a.path().is_ident("serde")) {
+		let metas = attr.parse_args_with(Punctuated::<Meta, Token![,]>::parse_terminated)?;
+		for meta in metas {
+			match meta {
+				Meta::NameValue(m) if m.path.is_ident(key) => name = Some(lit_str(&m.value)?),
+				Meta::List(m) if m.path.is_ident(key) => {
+					let inner =
+						m.parse_args_with(Punctuated::<Meta, Token![,]>::parse_terminated)?;
+					for meta in inner {
+						if let Meta::NameValue(m) = meta
+							&& m.path.is_ident("serialize")
+						{
+							name = Some(lit_str(&m.value)?);
+						}
+					}
+				}
+				_ => {}
+			}
+		}
+	}
+	Ok(name)
+}
+
+fn lit_str(expr: &Expr) -> syn::Result<LitStr> {
+	match expr {
+		Expr::Lit(e) => match &e.lit {
+			Lit::Str(s) => Ok(s.clone()),
+			lit => Err(syn::Error::new_spanned(lit, "expected a string")),
+		},
+		e => Err(syn::Error::new_spanned(e, "expected a string")),
+	}
+}
+
+// serde の rename_all と同じ規則で snake_case のフィールド名を書き換える
+fn rename(field: &str, rule: &LitStr) -> syn::Result<String> {
+	let pascal = || {
+		field
+			.split('_')
+			.map(|word| {
+				let mut chars = word.chars();
+				chars
+					.next()
+					.map(|c| c.to_uppercase().chain(chars).collect::<String>())
+					.unwrap_or_default()
+			})
+			.collect::<String>()
+	};
+	Ok(match rule.value().as_str() {
+		"lowercase" | "snake_case" => field.to_string(),
+		"UPPERCASE" | "SCREAMING_SNAKE_CASE" => field.to_uppercase(),
+		"PascalCase" => pascal(),
+		"camelCase" => {
+			let pascal = pascal();
+			let mut chars = pascal.chars();
+			chars
+				.next()
+				.map(|c| c.to_lowercase().chain(chars).collect())
+				.unwrap_or_default()
+		}
+		"kebab-case" => field.replace('_', "-"),
+		"SCREAMING-KEBAB-CASE" => field.to_uppercase().replace('_', "-"),
+		_ => return Err(syn::Error::new_spanned(rule, "unknown rename_all rule")),
+	})
+}
+
+// VideoComment → video_comment
+fn snake_case(name: &str) -> String {
+	let mut snake = String::new();
+	for (i, c) in name.chars().enumerate() {
+		if c.is_uppercase() {
+			if i > 0 {
+				snake.push('_');
+			}
+			snake.extend(c.to_lowercase());
+		} else {
+			snake.push(c);
+		}
+	}
+	snake
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use syn::parse_quote;
+
+	fn error(input: DeriveInput) -> String {
+		expand(&input).unwrap_err().to_string()
+	}
+
+	#[test]
+	fn test_expand() {
+		let tokens = expand(&parse_quote! {
+			#[collection(name = "page")]
+			struct Video {
+				#[collection(index)]
+				id: Uuid,
+				#[collection(unique)]
+				slug: String,
+			}
+		})
+		.unwrap()
+		.to_string();
+		assert!(tokens.contains("\"page\""));
+		assert!(tokens.contains("const ID"));
+		assert!(tokens.contains("const SLUG"));
+		assert!(tokens.contains("unique_fields"));
+		// serde で名前を変えたフィールドは保存される名前を使い、定数の名前は Rust のフィールド名から作る
+		let tokens = expand(&parse_quote! {
+			#[serde(rename_all = "camelCase", deny_unknown_fields)]
+			struct Video {
+				id: Uuid,
+				#[collection(index)]
+				#[serde(rename = "title", default)]
+				name: String,
+				#[collection(index)]
+				r#type: String,
+				#[collection(unique)]
+				#[serde(rename(serialize = "slugValue", deserialize = "slug"))]
+				slug: String,
+				#[collection(index)]
+				id_root: String,
+			}
+		})
+		.unwrap()
+		.to_string();
+		assert!(tokens.contains("const NAME"));
+		assert!(tokens.contains("Field :: new (\"title\")"));
+		assert!(tokens.contains("const TYPE"));
+		assert!(tokens.contains("Field :: new (\"type\")"));
+		assert!(!tokens.contains("r#type\""));
+		assert!(tokens.contains("& [\"slugValue\"]"));
+		assert!(tokens.contains("const ID_ROOT"));
+		assert!(tokens.contains("Field :: new (\"idRoot\")"));
+	}
+
+	#[test]
+	fn test_errors() {
+		assert_eq!(
+			error(parse_quote! {
+				struct Video {
+					#[collection(id)]
+					a: String,
+					#[collection(id)]
+					b: String,
+				}
+			}),
+			"only one field can be #[collection(id)]"
+		);
+		assert_eq!(
+			error(parse_quote! {
+				struct Video {
+					name: String,
+				}
+			}),
+			"no `id` field, mark the document id with #[collection(id)]"
+		);
+		assert_eq!(
+			error(parse_quote! {
+				enum Video {
+					A,
+				}
+			}),
+			"Collection can only be derived for structs"
+		);
+		assert_eq!(
+			error(parse_quote! {
+				struct Video(String);
+			}),
+			"Collection needs named fields"
+		);
+		assert_eq!(
+			error(parse_quote! {
+				struct Video {
+					#[collection(primary)]
+					id: String,
+				}
+			}),
+			"expected `id`, `parent`, `index` or `unique`"
+		);
+		assert_eq!(
+			error(parse_quote! {
+				#[serde(rename_all = "Title Case")]
+				struct Video {
+					id: String,
+				}
+			}),
+			"unknown rename_all rule"
+		);
+	}
+}
@@ -1,39 +1,99 @@
 use crate::auth::TokenJwtGenerator;
 use crate::auth::{self, OAuth};
-use crate::collection::Collection;
+use crate::backup;
+use crate::cache::Cached;
+use crate::collection::{self, Collection, Event, transaction};
+use crate::config::{self, Config};
+use crate::counter::ShardedCounter;
+use crate::error::Error;
+use crate::metrics::{self, Metered};
+use crate::migration::{self, Migration};
 use crate::out;
-use firestore;
+use crate::query::Field;
+use crate::search::{self, Searchable};
+use crate::storage::{self, Storage, Version};
+use crate::unique;
+use axum::response::IntoResponse;
 use uuid::Uuid;
 
-pub struct Api {
+#[derive(Clone)]
+pub struct Api<S = Cached<Metered<storage::Firestore>>> {
 	google: auth::OAuth,
-	db: firestore::FirestoreDb,
+	db: S,
+	// 動画の全文検索、起動したら follow_search で作る
+	search: search::Index<out::Video>,
+	email: config::Email,
 }
-impl Api {
-	pub async fn new() -> Result<Self, String> {
+impl Api<Cached<Metered<storage::Firestore>>> {
+	pub async fn new(config: &Config) -> Result<Self, Error> {
+		let settings = &config.firestore;
+		// エミュレータに繋ぐときは Google の認証情報を読まない
+		if let Some(host) = &settings.emulator_host {
+			let db = storage::Firestore::emulator(host, &settings.project).await?;
+			return Ok(Self::offline(Self::cache(db)).email(config.email.clone()));
+		}
 		Ok(Self {
-			google: OAuth::load(
-				"secret/sarod_oauth_google_676186616609-tvidvbklos7q5poilss55ookecj6vr14.apps.googleusercontent.com.json",
-				Some("web"),
-			)?,
-			db: firestore::FirestoreDb::with_options_service_account_key_file(
-				firestore::FirestoreDbOptions::new("lzpel-net".into())
-					.with_database_id("sarod".into()),
-				"secret/sarod_firestore.json".into(),
-			)
-			.await
-			.map_err(|v| v.to_string())?,
+			google: OAuth::load(&config.google.oauth_file, Some("web"))?,
+			db: Self::cache(storage::Firestore::new(
+				firestore::FirestoreDb::with_options_service_account_key_file(
+					firestore::FirestoreDbOptions::new(settings.project.clone())
+						.with_database_id(settings.database.clone()),
+					settings.key_file.clone().into(),
+				)
+				.await?,
+			)),
+			search: search::Index::new(),
+			email: config.email.clone(),
 		})
 	}
-	pub fn jwt_get(
-		req: impl AsRef<axum::http::Request<axum::body::Body>>,
-	) -> Option<auth::TokenJwt> {
+	// 動画は多少古くてもよいので読み出しを覚えておく、user は本人の変更がすぐ見えないと困るので覚えない
+	// ログインの取り消しはリクエストのたびに読むので覚えておく、取り消しが効くまで ttl だけ遅れる
+	// Firestore への問い合わせは Metered で数と時間を数える
+	fn cache(db: storage::Firestore) -> Cached<Metered<storage::Firestore>> {
+		Cached::new(Metered::new(db), 1024)
+			.ttl(
+				out::Video::collection_name(),
+				std::time::Duration::from_secs(30),
+			)
+			.ttl(
+				Revocation::collection_name(),
+				std::time::Duration::from_secs(30),
+			)
+	}
+}
+impl Api<storage::Memory> {
+	// Google の認証情報も Firestore も使わずにメモリ上で動かす、テストやオフラインでの開発用
+	pub fn memory() -> Self {
+		Self::offline(storage::Memory::new())
+	}
+}
+impl<S: Storage> Api<S> {
+	// Google ログインを使わない Api、メモリやエミュレータで動かすとき用
+	fn offline(db: S) -> Self {
+		Self {
+			google: OAuth {
+				client_id: "memory".into(),
+				client_secret: "memory".into(),
+				token_uri: "http://localhost/token".into(),
+				auth_uri: "http://localhost/auth".into(),
+			},
+			db,
+			search: search::Index::new(),
+			email: Default::default(),
+		}
+	}
+	// authapi_email で送るメールの送信元など
+	pub fn email(mut self, email: config::Email) -> Self {
+		self.email = email;
+		self
+	}
+}
+impl<S: Storage> Api<S> {
+	pub fn jwt_from_headers(headers: &axum::http::HeaderMap) -> Option<auth::TokenJwt> {
 		// AuthorizationヘッダではなくCookieのtokenで認証する：取得関数
 
 		// Cookie ヘッダを文字列として取得
-		let cookie_header = req
-			.as_ref()
-			.headers()
+		let cookie_header = headers
 			.get(axum::http::header::COOKIE)
 			.and_then(|v| v.to_str().ok())?;
 
@@ -49,6 +109,210 @@ impl Api {
 			})?;
 		out::User::validate_jwt(token).ok()
 	}
+	// Cookie のトークンを確かめ、revoke_sessions で取り消されていないかも見る
+	// 取り消しを読めなかったときはトークンを通さずにエラーを返す
+	async fn session(
+		&self,
+		headers: &axum::http::HeaderMap,
+	) -> Result<Option<auth::TokenJwt>, Error> {
+		let Some(jwt) = Self::jwt_from_headers(headers) else {
+			return Ok(None);
+		};
+		let issued = jwt.iat.unwrap_or_default();
+		for id in [jwt.sub.as_str(), Revocation::ALL] {
+			match Revocation::get(&self.db, id).await {
+				// 取り消したのと同じ秒に発行したトークンも使えなくする
+				Ok(r) if issued <= r.before => return Ok(None),
+				Ok(_) | Err(Error::NotFound(_)) => {}
+				Err(e) => return Err(e),
+			}
+		}
+		tracing::Span::current().record("subject", jwt.sub.as_str());
+		Ok(Some(jwt))
+	}
+	// 動画 id を利用者 user_id が書き換えてよいか、作った本人か管理者なら true
+	async fn may_edit(&self, id: &str, user_id: Uuid) -> Result<bool, Error> {
+		let video = out::Video::get(&self.db, id).await?;
+		if !video.id_user.is_nil() && video.id_user == user_id {
+			return Ok(true);
+		}
+		match out::User::get(&self.db, &user_id.to_string()).await {
+			Ok(user) => Ok(user.is_admin && user.is_active),
+			Err(Error::NotFound(_)) => Ok(false),
+			Err(e) => Err(e),
+		}
+	}
+	// 保存されているドキュメントを今の版に書き換える、コレクションごとの結果を返す
+	pub async fn migrate(
+		&self,
+		dry_run: bool,
+	) -> Result<Vec<(&'static str, migration::Report)>, Error> {
+		Ok(vec![
+			(
+				out::User::collection_name(),
+				migration::migrate::<out::User>(&self.db, dry_run).await?,
+			),
+			(
+				out::Video::collection_name(),
+				migration::migrate::<out::Video>(&self.db, dry_run).await?,
+			),
+		])
+	}
+	// 一意なフィールドの重複を探し、索引をドキュメントに合わせる
+	// 一意制約を足したときや、索引を通さずに書き込んだ後に実行する、import は自分で実行する
+	pub async fn repair(
+		&self,
+		dry_run: bool,
+	) -> Result<Vec<(&'static str, unique::Report)>, Error> {
+		Ok(vec![(
+			out::User::collection_name(),
+			unique::repair::<out::User>(&self.db, dry_run).await?,
+		)])
+	}
+	// email で登録している利用者を管理者にする、いなければ管理者として作る
+	// 作った利用者は email のリンクからログインする
+	pub async fn create_admin(&self, email: &str, name: &str) -> Result<out::User, Error> {
+		let existing = out::User::select()
+			.filter(out::User::AUTH_EMAIL.eq(email))
+			.limit(1);
+		let page = out::User::query(&self.db, &existing, None).await?;
+		if let Some(user) = page.items.into_iter().next() {
+			let user = out::User {
+				is_admin: true,
+				is_active: true,
+				..user.into_inner()
+			};
+			user.patch(&self.db, &["is_admin", "is_active"]).await?;
+			return Ok(user);
+		}
+		let user = out::User {
+			id: Uuid::now_v7(),
+			name: name.to_string(),
+			auth_email: email.to_string(),
+			is_active: true,
+			is_admin: true,
+			..Default::default()
+		};
+		// 同時に同じ email で登録されていれば auth_email の一意制約で AlreadyExists になる
+		user.push(&self.db).await?;
+		Ok(user)
+	}
+	// user_ids の利用者がこれまでに受け取ったトークンを使えなくする、空ならすべての利用者
+	// 他のプロセスは Revocation を覚えている間 (Api::cache の ttl) だけ古いトークンを受け付ける
+	pub async fn revoke_sessions(&self, user_ids: &[&str]) -> Result<(), Error> {
+		let before = auth::timestamp();
+		let ids = if user_ids.is_empty() {
+			&[Revocation::ALL][..]
+		} else {
+			user_ids
+		};
+		for id in ids {
+			Revocation {
+				id: id.to_string(),
+				before,
+			}
+			.upsert(&self.db)
+			.await?;
+		}
+		Ok(())
+	}
+	// 設定の送信元から to にメールを送り、メールの設定を確かめる
+	pub async fn send_test_email(&self, to: &str) -> Result<(), Error> {
+		let subject = format!("{} test email", self.email.service_name);
+		let body = format!(
+			"This is a test email from {}.\nSupport: {}",
+			self.email.service_name, self.email.support
+		);
+		self.send_email(to, &subject, &body).await
+	}
+	// 設定の送信元からメールを送り、送った数と時間を数える
+	async fn send_email(&self, to: &str, subject: &str, body: &str) -> Result<(), Error> {
+		let started = std::time::Instant::now();
+		let result = ngoni::ses::send_email(&self.email.from, to, subject, body)
+			.await
+			.map(|_| ())
+			.map_err(|e| Error::Unavailable(e.to_string()));
+		metrics::EMAILS.inc(&[metrics::outcome(&result)]);
+		metrics::EMAIL_DURATION.observe(&[], started.elapsed());
+		result
+	}
+	// バックアップの対象にするコレクション
+	pub fn collections() -> [&'static str; 2] {
+		[out::User::collection_name(), out::Video::collection_name()]
+	}
+	// collections が空ならすべてのコレクションを書き出す
+	// 動画を書き出すときは、まだ rollup していない数が残るように動画ごとのカウンタの shard も書き出す
+	pub async fn export(
+		&self,
+		collections: &[&str],
+		output: &mut impl std::io::Write,
+	) -> Result<usize, Error> {
+		use futures::TryStreamExt;
+		let mut paths: Vec<String> = if collections.is_empty() {
+			Self::collections().map(str::to_string).to_vec()
+		} else {
+			collections.iter().map(|c| c.to_string()).collect()
+		};
+		if collections.is_empty() || collections.contains(&out::Video::collection_name()) {
+			let select = out::Video::select();
+			let mut videos = std::pin::pin!(out::Video::stream(&self.db, &select));
+			while let Some(video) = videos.try_next().await? {
+				paths.push(VIDEO_COUNT.path(&video.document_id()));
+			}
+		}
+		let paths: Vec<&str> = paths.iter().map(String::as_str).collect();
+		backup::export(&self.db, &paths, output).await
+	}
+	pub async fn import(
+		&self,
+		input: impl std::io::BufRead,
+		options: &backup::Import,
+	) -> Result<backup::ImportReport, Error> {
+		let mut report = backup::import(&self.db, input, options).await?;
+		// backup::import は一意制約の索引を通さずに書き込むので、読み込んだ後に索引を作り直す
+		if report.written > 0 {
+			for (collection, repaired) in self.repair(false).await? {
+				for (field, value, ids) in repaired.duplicates {
+					report
+						.duplicates
+						.push((format!("{collection}.{field}"), value, ids));
+				}
+			}
+		}
+		Ok(report)
+	}
+	// 動画を読み直して検索のインデックスを作り、その後の変更を反映し続ける
+	pub async fn follow_search(&self) -> Result<(), Error> {
+		self.search.clone().follow(self.db.clone()).await
+	}
+	// 動画の閲覧数などの shard を合計して、各動画の Count に書き戻す、書き戻した動画の数を返す
+	// 1日に何度実行してもよく、実行するたびにその時点までの数になる
+	pub async fn rollup(&self, today: chrono::NaiveDate) -> Result<usize, Error> {
+		use futures::TryStreamExt;
+		let select = out::Video::select();
+		let mut videos = std::pin::pin!(out::Video::stream(&self.db, &select));
+		let mut rolled = 0;
+		while let Some(video) = videos.try_next().await? {
+			let id = video.document_id();
+			let mut logs = VIDEO_COUNT
+				.daily(&self.db, &id, &["view", "star", "text"], today, COUNT_DAYS)
+				.await?
+				.into_iter()
+				.map(out::Count::from_log);
+			let video = out::Video {
+				count_view: logs.next().unwrap_or_default(),
+				count_star: logs.next().unwrap_or_default(),
+				count_text: logs.next().unwrap_or_default(),
+				..video.into_inner()
+			};
+			video
+				.patch(&self.db, &["count_view", "count_star", "count_text"])
+				.await?;
+			VIDEO_COUNT.prune(&self.db, &id, today, COUNT_DAYS).await?;
+			rolled += 1;
+		}
+		Ok(rolled)
+	}
 	pub fn jwt_set(v: Option<impl TokenJwtGenerator>) -> axum::http::Response<axum::body::Body> {
 		// AuthorizationヘッダではなくCookieのtokenで認証する：設定関数
 		let jwt = v
@@ -65,7 +329,152 @@ impl Api {
 			.unwrap()
 	}
 }
-impl out::ApiInterface for Api {
+// 変更の通知を Server-Sent Events で流すエンドポイント
+// main.tsp では表せないので out::axum_router とは別に組み立てて merge する
+// 再接続したブラウザは最後に受け取った id を Last-Event-ID で送ってくるので、その続きから流す
+impl<S: Storage + 'static> Api<S> {
+	pub fn watch_router(self) -> axum::Router {
+		axum::Router::new()
+			.route("/api/video/watch", axum::routing::get(Self::video_watch))
+			.route(
+				"/api/video/{id}/watch",
+				axum::routing::get(Self::video_watch_one),
+			)
+			.with_state(self)
+	}
+	// ホームに並ぶ動画すべての変更
+	async fn video_watch(
+		axum::extract::State(api): axum::extract::State<Self>,
+		headers: axum::http::HeaderMap,
+	) -> axum::response::Response {
+		api.video_watch_select(&headers, out::Video::select()).await
+	}
+	// 1つの動画の変更
+	async fn video_watch_one(
+		axum::extract::State(api): axum::extract::State<Self>,
+		axum::extract::Path(id): axum::extract::Path<String>,
+		headers: axum::http::HeaderMap,
+	) -> axum::response::Response {
+		let Ok(id) = Uuid::parse_str(&id) else {
+			return Error::Invalid(format!("invalid video id: {id}")).into_response();
+		};
+		let select = out::Video::select().filter(out::Video::ID.eq(id));
+		api.video_watch_select(&headers, select).await
+	}
+	async fn video_watch_select(
+		&self,
+		headers: &axum::http::HeaderMap,
+		select: crate::query::Select<out::Video>,
+	) -> axum::response::Response {
+		match self.session(headers).await {
+			Ok(Some(_)) => {}
+			Ok(None) => return axum::http::StatusCode::FORBIDDEN.into_response(),
+			Err(e) => return e.into_response(),
+		}
+		let after = headers
+			.get("last-event-id")
+			.and_then(|v| v.to_str().ok())
+			.filter(|v| !v.is_empty())
+			.map(|v| Version(v.to_string()));
+		match out::Video::watch(&self.db, &select, after).await {
+			Ok(watch) => sse(watch),
+			Err(e) => e.into_response(),
+		}
+	}
+}
+
+// /readyz で1つの依存先を待つ長さ、遅い依存先があっても probe は止まらずに失敗を返す
+const READY_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(2);
+// /readyz で読むドキュメント、無くてよい、読めれば保存先に繋がっている
+const READY_COLLECTION: &str = "readyz";
+
+// ロードバランサや Lambda Web Adapter が調べるエンドポイント
+// /healthz はプロセスが応答するかだけ、/readyz は依存先をそれぞれ確かめて JSON で返し、1つでも駄目なら 503
+impl<S: Storage + 'static> Api<S> {
+	pub fn health_router(self) -> axum::Router {
+		axum::Router::new()
+			.route("/healthz", axum::routing::get(Self::healthz))
+			.route("/readyz", axum::routing::get(Self::readyz))
+			.with_state(self)
+	}
+	async fn healthz() -> axum::Json<serde_json::Value> {
+		axum::Json(serde_json::json!({ "status": "ok" }))
+	}
+	async fn readyz(
+		axum::extract::State(api): axum::extract::State<Self>,
+	) -> axum::response::Response {
+		let (storage, oauth, email) = futures::join!(
+			ready(api.db.get(READY_COLLECTION, "readyz"), READY_TIMEOUT),
+			ready(async { api.google.check() }, READY_TIMEOUT),
+			ready(async { api.email.check() }, READY_TIMEOUT),
+		);
+		let checks = [("storage", storage), ("oauth", oauth), ("email", email)];
+		let ok = checks.iter().all(|(_, check)| check["status"] == "ok");
+		let body = serde_json::json!({
+			"status": if ok { "ok" } else { "unavailable" },
+			"checks": checks.into_iter().map(|(name, check)| (name.to_string(), check)).collect::<serde_json::Map<_, _>>(),
+		});
+		let status = if ok {
+			axum::http::StatusCode::OK
+		} else {
+			axum::http::StatusCode::SERVICE_UNAVAILABLE
+		};
+		(status, axum::Json(body)).into_response()
+	}
+}
+
+// check を timeout まで待ち、結果とかかった時間を {"status", "latency_ms", "error"} にする
+async fn ready<T>(
+	check: impl Future<Output = Result<T, Error>>,
+	timeout: std::time::Duration,
+) -> serde_json::Value {
+	let started = std::time::Instant::now();
+	let result = match tokio::time::timeout(timeout, check).await {
+		Ok(result) => result.map(|_| ()),
+		Err(_) => Err(Error::Unavailable(format!("timed out after {timeout:?}"))),
+	};
+	let latency_ms = started.elapsed().as_millis() as u64;
+	match result {
+		Ok(()) => serde_json::json!({ "status": "ok", "latency_ms": latency_ms }),
+		Err(e) => {
+			tracing::warn!(error = %e, "not ready");
+			serde_json::json!({ "status": "error", "latency_ms": latency_ms, "error": e.to_string() })
+		}
+	}
+}
+
+// Event を put / delete / reset の SSE に変換して流す、id はドキュメントの版
+// 流している途中の読み出しの失敗は error として送り、接続は保ったまま続ける
+fn sse<C: Collection + 'static>(watch: collection::Watch<C>) -> axum::response::Response {
+	use axum::response::sse::{Event as Sse, KeepAlive};
+	let stream = futures::stream::unfold(watch, |mut watch| async move {
+		let event = match watch.next().await? {
+			Ok(Event::Put(v)) => Sse::default()
+				.event("put")
+				.id(v.version.to_string())
+				.json_data(&v.value),
+			Ok(Event::Delete(id, version)) => Sse::default()
+				.event("delete")
+				.id(version.to_string())
+				.json_data(serde_json::json!({ "id": id })),
+			Ok(Event::Reset) => Ok(Sse::default().event("reset").data("")),
+			Err(e) => Ok(Sse::default().event("error").data(e.to_string())),
+		};
+		Some((event, watch))
+	});
+	axum::response::sse::Sse::new(stream)
+		.keep_alive(KeepAlive::default())
+		.into_response()
+}
+
+impl IntoResponse for Error {
+	fn into_response(self) -> axum::response::Response {
+		self.log();
+		(self.status(), self.message().to_string()).into_response()
+	}
+}
+
+impl<S: Storage> out::ApiInterface for Api<S> {
 	async fn authorize(
 		&self,
 		req: axum::http::Request<axum::body::Body>,
@@ -99,7 +508,7 @@ impl out::ApiInterface for Api {
 		let origin = out::origin_from_request(&req.request).unwrap_or_default();
 		let language = language_from_headers(&req.request.headers()).unwrap_or_default();
 		let (subject, body) = auth::email::validate_email(
-			"Plant Mimamori",
+			&self.email.service_name,
 			&language,
 			&origin,
 			&format!(
@@ -107,11 +516,11 @@ impl out::ApiInterface for Api {
 				auth::email::jwt_from_email(&req.body.email)
 			),
 			"2025-12-20",
-			"support@surfic.com",
+			&self.email.support,
 		);
-		match ngoni::ses::send_email("info@surfic.com", &req.body.email, &subject, &body).await {
+		match self.send_email(&req.body.email, &subject, &body).await {
 			Ok(_) => return out::AuthapiEmailResponse::Status204,
-			Err(e) => return out::AuthapiEmailResponse::Status400(e.to_string()),
+			Err(e) => return e.into(),
 		}
 	}
 	async fn authapi_signup(&self, _req: out::AuthapiSignupRequest) -> out::AuthapiSignupResponse {
@@ -135,35 +544,69 @@ impl out::ApiInterface for Api {
 		&self,
 		req: out::AuthapiCallbackOauthRequest,
 	) -> out::AuthapiCallbackOauthResponse {
-		let inner = async || -> Result<_, String> {
+		let inner = async || -> Result<_, Error> {
 			let a = self.google.callback(&req.state, &req.code).await?;
 			let b = a.jwt()?;
-			let c = out::User::query(
-				&self.db,
-				|q: crate::collection::FilterBuilder| q.field("auth_google").eq(&b.sub),
-				None,
-				None,
-				None,
-			)
-			.await?;
-			let w = if let Some(d) = c.first() {
-				d.clone()
-			} else {
+			// 同じアカウントで同時にログインしても利用者が二重に作られないように、探すのと作るのを一つのトランザクションで行う
+			// 探した後に他で作られていれば競合してやり直しになり、2回目で見つかる、auth_google の一意制約でも弾かれる
+			let w = transaction(&self.db, async move |tx| {
+				let c = tx
+					.query(
+						&out::User::select()
+							.filter(out::User::AUTH_GOOGLE.eq(&b.sub))
+							.limit(1),
+					)
+					.await?;
+				if let Some(d) = c.into_iter().next() {
+					return Ok(d.into_inner());
+				}
+				// メールで作られた利用者 (create-admin など) がいれば、新しく作らずに Google のアカウントを紐付ける
+				// auth_email は一意なので、作ろうとすると AlreadyExists になってログインできない
+				if b.email_verified && !b.email.is_empty() {
+					let c = tx
+						.query(
+							&out::User::select()
+								.filter(out::User::AUTH_EMAIL.eq(&b.email))
+								.limit(1),
+						)
+						.await?;
+					if let Some(d) = c.into_iter().next() {
+						// 一意なフィールドを書き換えるので get で読み直しておく
+						let mut u = tx
+							.get::<out::User>(&d.into_inner().id.to_string())
+							.await?
+							.into_inner();
+						u.auth_google = b.sub.clone();
+						let mut fields = vec!["auth_google"];
+						if u.name.is_empty() {
+							u.name = b.name.clone();
+							fields.push("name");
+						}
+						if u.picture.is_empty() {
+							u.picture = b.picture.clone().unwrap_or_default();
+							fields.push("picture");
+						}
+						tx.patch(&u, &fields)?;
+						return Ok(u);
+					}
+				}
 				let r = out::User {
 					id: Uuid::now_v7(),
-					name: b.name,
-					picture: b.picture.unwrap(),
-					auth_email: b.email,
-					auth_google: b.sub,
+					name: b.name.clone(),
+					picture: b.picture.clone().unwrap_or_default(),
+					auth_email: b.email.clone(),
+					auth_google: b.sub.clone(),
 					is_active: true,
 					..Default::default()
 				};
-				r.push(&self.db).await.map(|_| r)?
-			};
+				tx.push(&r)?;
+				Ok(r)
+			})
+			.await?;
 			Ok(w)
 		};
 		match inner().await {
-			Err(e) => out::AuthapiCallbackOauthResponse::Status400(e),
+			Err(e) => e.into(),
 			Ok(v) => {
 				let jwt = v.jwt();
 				let jwt_str = v.signed_jwt();
@@ -191,30 +634,159 @@ impl out::ApiInterface for Api {
 		&self,
 		req: out::UserapiUserPopRequest,
 	) -> out::UserapiUserPopResponse {
-		let Some(v) = Self::jwt_get(req) else {
-			return out::UserapiUserPopResponse::Status403;
+		let v = match self.session(req.as_ref().headers()).await {
+			Ok(Some(v)) => v,
+			Ok(None) => return out::UserapiUserPopResponse::Status403,
+			Err(e) => return e.into(),
 		};
 		match out::User::pop(&self.db, &v.sub).await {
 			Ok(_) => out::UserapiUserPopResponse::Status204,
-			Err(e) => out::UserapiUserPopResponse::Status400(e),
+			Err(e) => e.into(),
 		}
 	}
 	async fn userapi_user_get(
 		&self,
 		req: out::UserapiUserGetRequest,
 	) -> out::UserapiUserGetResponse {
-		let Some(v) = Self::jwt_get(req) else {
-			return out::UserapiUserGetResponse::Status403;
+		let v = match self.session(req.as_ref().headers()).await {
+			Ok(Some(v)) => v,
+			Ok(None) => return out::UserapiUserGetResponse::Status403,
+			Err(e) => return e.into(),
 		};
 		match out::User::get(&self.db, &v.sub).await {
-			Ok(u) => out::UserapiUserGetResponse::Status200(u),
-			Err(e) => out::UserapiUserGetResponse::Status400(e),
+			Ok(u) => out::UserapiUserGetResponse::Raw(json_with_etag(&u.value, &u.version)),
+			Err(e) => e.into(),
+		}
+	}
+	async fn userapi_user_set(
+		&self,
+		req: out::UserapiUserSetRequest,
+	) -> out::UserapiUserSetResponse {
+		let v = match self.session(req.as_ref().headers()).await {
+			Ok(Some(v)) => v,
+			Ok(None) => return out::UserapiUserSetResponse::Status403,
+			Err(e) => return e.into(),
+		};
+		let Ok(id) = Uuid::parse_str(&v.sub) else {
+			return out::UserapiUserSetResponse::Status403;
+		};
+		let version = match if_match(&req) {
+			Ok(version) => version,
+			Err(m) => return out::UserapiUserSetResponse::Status412(m),
+		};
+		// 本人のドキュメントの、本人が変えてよいフィールドだけを書き換える
+		let user = out::User {
+			id,
+			..req.body.user
+		};
+		let fields = ["name", "picture"];
+		let result = match &version {
+			Some(version) => user.patch_if(&self.db, &fields, version).await,
+			None => user.patch(&self.db, &fields).await,
+		};
+		let result = match result {
+			Ok(_) => out::User::get(&self.db, &v.sub).await,
+			Err(e) => Err(e),
+		};
+		match result {
+			Ok(u) => out::UserapiUserSetResponse::Raw(json_with_etag(&u.value, &u.version)),
+			// If-Match の版から書き換えられていた
+			Err(Error::Conflict(m)) if version.is_some() => {
+				out::UserapiUserSetResponse::Status412(m)
+			}
+			Err(e) => e.into(),
+		}
+	}
+	async fn videoapi_home(&self, req: out::VideoapiHomeRequest) -> out::VideoapiHomeResponse {
+		let limit = req.limit.map_or(storage::Query::DEFAULT_LIMIT, |v| {
+			v.clamp(1, storage::Query::DEFAULT_LIMIT as i32) as u32
+		});
+		let select = out::Video::select().limit(limit);
+		match out::Video::query(&self.db, &select, req.cursor.as_deref()).await {
+			Ok(page) => {
+				let page = page.map(|v| v.into_inner());
+				out::VideoapiHomeResponse::Status200(out::VideoList {
+					items: page.items,
+					next_cursor: page.next_cursor,
+				})
+			}
+			Err(e) => e.into(),
+		}
+	}
+	async fn videoapi_search(
+		&self,
+		req: out::VideoapiSearchRequest,
+	) -> out::VideoapiSearchResponse {
+		let limit = req.limit.map_or(storage::Query::DEFAULT_LIMIT, |v| {
+			v.clamp(1, storage::Query::DEFAULT_LIMIT as i32) as u32
+		});
+		match self
+			.search
+			.search(&self.db, &req.q, req.cursor.as_deref(), limit)
+			.await
+		{
+			Ok(page) => {
+				let page = page.map(|v| v.into_inner());
+				out::VideoapiSearchResponse::Status200(out::VideoList {
+					items: page.items,
+					next_cursor: page.next_cursor,
+				})
+			}
+			Err(e) => e.into(),
 		}
 	}
-	async fn videoapi_home(&self, _req: out::VideoapiHomeRequest) -> out::VideoapiHomeResponse {
-		match out::Video::query(&self.db, crate::collection::none_filter, None, None, None).await {
-			Ok(u) => out::VideoapiHomeResponse::Status200(u),
-			Err(e) => out::VideoapiHomeResponse::Status400(e),
+	async fn videoapi_push(&self, req: out::VideoapiPushRequest) -> out::VideoapiPushResponse {
+		let session = match self.session(req.as_ref().headers()).await {
+			Ok(Some(v)) => v,
+			Ok(None) => return out::VideoapiPushResponse::Status403,
+			Err(e) => return e.into(),
+		};
+		let Ok(user_id) = Uuid::parse_str(&session.sub) else {
+			return out::VideoapiPushResponse::Status403;
+		};
+		let version = match if_match(&req) {
+			Ok(version) => version,
+			Err(m) => return out::VideoapiPushResponse::Status412(m),
+		};
+		let video = req.body.video;
+		let result = if video.id.is_nil() {
+			// id が無ければ追加、作った利用者は本人、カウンタはクライアントから受け取らない
+			let video = out::Video {
+				id: Uuid::now_v7(),
+				id_user: user_id,
+				count_view: Default::default(),
+				count_star: Default::default(),
+				count_text: Default::default(),
+				..video
+			};
+			video.push(&self.db).await.map(|version| (video, version))
+		} else {
+			// 書き換えられるのは作った本人か管理者だけ、id_user は書き換えないので確かめた後に変わることはない
+			match self.may_edit(&video.id.to_string(), user_id).await {
+				Ok(true) => {}
+				Ok(false) => return out::VideoapiPushResponse::Status403,
+				Err(e) => return e.into(),
+			}
+			// 更新は編集できるフィールドだけ、カウンタは別の経路で更新されるので触らない
+			let fields = ["id_root", "id_node", "name", "content"];
+			let result = match &version {
+				Some(version) => video.patch_if(&self.db, &fields, version).await,
+				None => video.patch(&self.db, &fields).await,
+			};
+			match result {
+				Ok(_) => out::Video::get(&self.db, &video.id.to_string())
+					.await
+					.map(|v| (v.value, v.version)),
+				Err(e) => Err(e),
+			}
+		};
+		match result {
+			Ok((v, version)) => {
+				self.search.put(&v);
+				out::VideoapiPushResponse::Raw(json_with_etag(&v, &version))
+			}
+			Err(Error::Conflict(m)) if version.is_some() => out::VideoapiPushResponse::Status412(m),
+			Err(e) => e.into(),
 		}
 	}
 }
@@ -230,20 +802,145 @@ pub fn language_from_headers(
 		.map(str::to_string)
 }
 
-impl Collection for out::User {
-	fn collection_name() -> &'static str {
-		"user"
+// If-Match ヘッダの版、無いか * なら None で版を問わず書き換える
+// 弱い ETag や複数指定などの版として読めない値は、どの版とも一致しないので Err で 412 にする
+fn if_match(
+	req: impl AsRef<axum::http::Request<axum::body::Body>>,
+) -> Result<Option<Version>, String> {
+	let Some(value) = req.as_ref().headers().get(axum::http::header::IF_MATCH) else {
+		return Ok(None);
+	};
+	let value = value.to_str().unwrap_or_default();
+	if value.trim() == "*" {
+		return Ok(None);
 	}
-	fn document_id(&self) -> String {
-		self.id.to_string()
+	Version::from_if_match(value)
+		.map(Some)
+		.ok_or_else(|| format!("If-Match does not match: {value}"))
+}
+
+// 本文を JSON で、ドキュメントの版を ETag で返す
+// クライアントは次の更新でこの ETag を If-Match に入れると、その間の他の更新を上書きせずに 412 を受け取る
+fn json_with_etag(value: &impl serde::Serialize, version: &Version) -> axum::response::Response {
+	match serde_json::to_vec(value) {
+		Ok(body) => axum::response::Response::builder()
+			.status(axum::http::StatusCode::OK)
+			.header(axum::http::header::CONTENT_TYPE, "application/json")
+			.header(axum::http::header::ETAG, version.etag())
+			.body(axum::body::Body::from(body))
+			.unwrap(),
+		Err(e) => axum::response::IntoResponse::into_response((
+			axum::http::StatusCode::INTERNAL_SERVER_ERROR,
+			e.to_string(),
+		)),
 	}
 }
-impl Collection for out::Video {
-	fn collection_name() -> &'static str {
-		"page"
+
+// Error を各エンドポイントのレスポンスに変換する
+// main.tsp の ErrorResponse を返すエンドポイントはすべてここに並べる
+macro_rules! impl_from_error {
+	($($response:ty),* $(,)?) => {$(
+		impl From<Error> for $response {
+			fn from(e: Error) -> Self {
+				e.log();
+				match e {
+					Error::NotFound(_) => Self::Status404,
+					Error::AlreadyExists(m) | Error::Conflict(m) => Self::Status409(m),
+					Error::Invalid(m) => Self::Status400(m),
+					Error::Internal(m) => Self::Status500(m),
+					Error::Unavailable(m) => Self::Status503(m),
+				}
+			}
+		}
+	)*};
+}
+impl_from_error!(
+	out::AuthapiEmailResponse,
+	out::AuthapiSignupResponse,
+	out::AuthapiCallbackOauthResponse,
+	out::UserapiUserPopResponse,
+	out::UserapiUserGetResponse,
+	out::UserapiUserSetResponse,
+	out::VideoapiHomeResponse,
+	out::VideoapiSearchResponse,
+	out::VideoapiPushResponse,
+);
+
+// out.rs の User と Video は make generate で collections.sed が #[derive(Collection)] を付ける
+// 入れ子のフィールドの定数は derive では作れないのでここに書く
+impl out::Video {
+	pub const COUNT_VIEW_DAY: Field<Self, i32> = Field::new("count_view.day");
+	pub const COUNT_STAR_DAY: Field<Self, i32> = Field::new("count_star.day");
+}
+
+// 動画ごとの閲覧数 (view)、スター数 (star)、コメント数 (text)
+// 人気の動画に書き込みが集中しても Video のドキュメントを直接書き換えないように、page/{id}/count に分けて数える
+pub const VIDEO_COUNT: ShardedCounter<out::Video> = ShardedCounter::new("count", 10);
+// Count.log に残す日数
+const COUNT_DAYS: usize = 30;
+
+impl out::Count {
+	// log[0] が今日の、log[i] が i 日前の数
+	fn from_log(log: Vec<i64>) -> Self {
+		let log: Vec<i32> = log
+			.into_iter()
+			.map(|n| i32::try_from(n).unwrap_or(i32::MAX))
+			.collect();
+		let sum = |days: usize| {
+			log.iter()
+				.take(days)
+				.fold(0i32, |a, n| a.saturating_add(*n))
+		};
+		Self {
+			day: sum(1),
+			week: sum(7),
+			month: sum(COUNT_DAYS),
+			log,
+		}
 	}
-	fn document_id(&self) -> String {
-		self.id.to_string()
+}
+
+// out::User のスキーマの変更、collections.sed で #[collection(migrations = ...)] に渡す
+pub const USER_MIGRATIONS: &[Migration] = &[
+	// 1: auth_email_password を足した
+	|v| {
+		if v.get("auth_email_password").is_none() {
+			v["auth_email_password"] = "".into();
+		}
+		Ok(())
+	},
+	// 2: is_admin を足した
+	|v| {
+		if v.get("is_admin").is_none() {
+			v["is_admin"] = false.into();
+		}
+		Ok(())
+	},
+];
+// out::Video のスキーマの変更、collections.sed で #[collection(migrations = ...)] に渡す
+pub const VIDEO_MIGRATIONS: &[Migration] = &[
+	// 1: id_user を足した、それより前の動画は誰のものでもなく管理者だけが書き換えられる
+	|v| {
+		if v.get("id_user").is_none() {
+			v["id_user"] = Uuid::nil().to_string().into();
+		}
+		Ok(())
+	},
+];
+// 利用者ごとのログインの取り消し、before 以前に発行したトークンは使えない
+// id が ALL のものはすべての利用者に効く
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize, Collection)]
+struct Revocation {
+	id: String,
+	before: usize,
+}
+impl Revocation {
+	const ALL: &str = "*";
+}
+impl Searchable for out::Video {
+	// 名前に含まれる語は本文の倍に数える
+	fn text(&self) -> Vec<(&str, f64)> {
+		vec![(&self.name, 2.0), (&self.content, 1.0)]
 	}
 }
 
@@ -252,14 +949,808 @@ impl TokenJwtGenerator for out::User {
 		b"abc"
 	}
 	fn jwt(&self) -> auth::TokenJwt {
-		let o = auth::TokenJwt {
+		auth::TokenJwt {
 			sub: self.id.to_string(),
 			email: self.auth_email.clone(),
 			name: self.name.clone(),
 			picture: Some(self.picture.clone()),
 			..Default::default()
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use tower::ServiceExt;
+
+	async fn call<S: Storage + 'static>(
+		api: Api<S>,
+		method: &str,
+		uri: &str,
+		token: Option<&str>,
+		body: Option<serde_json::Value>,
+	) -> (axum::http::StatusCode, Vec<u8>) {
+		let mut builder = axum::http::Request::builder().method(method).uri(uri);
+		if let Some(token) = token {
+			builder = builder.header(axum::http::header::COOKIE, format!("token={token}"));
+		}
+		let body = match body {
+			Some(body) => {
+				builder = builder.header(axum::http::header::CONTENT_TYPE, "application/json");
+				axum::body::Body::from(body.to_string())
+			}
+			None => axum::body::Body::empty(),
+		};
+		let response = out::axum_router(api)
+			.oneshot(builder.body(body).unwrap())
+			.await
+			.unwrap();
+		let status = response.status();
+		let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+			.await
+			.unwrap();
+		(status, body.to_vec())
+	}
+
+	#[tokio::test]
+	async fn test_health() {
+		let get = async |api: Api<storage::Memory>, uri: &str| {
+			let request = axum::http::Request::builder()
+				.uri(uri)
+				.body(axum::body::Body::empty())
+				.unwrap();
+			let response = api.health_router().oneshot(request).await.unwrap();
+			let status = response.status();
+			let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+				.await
+				.unwrap();
+			(
+				status,
+				serde_json::from_slice::<serde_json::Value>(&body).unwrap(),
+			)
+		};
+		let (status, _) = get(Api::memory(), "/healthz").await;
+		assert_eq!(status, axum::http::StatusCode::OK);
+		// メールの設定が無いので準備できていない、他の依存先はそれぞれ確かめる
+		let (status, body) = get(Api::memory(), "/readyz").await;
+		assert_eq!(status, axum::http::StatusCode::SERVICE_UNAVAILABLE);
+		assert_eq!(body["checks"]["storage"]["status"], "ok");
+		assert_eq!(body["checks"]["oauth"]["status"], "ok");
+		assert_eq!(body["checks"]["email"]["status"], "error");
+		let email = config::Email {
+			service_name: "s".to_string(),
+			from: "info@example.com".to_string(),
+			support: "support@example.com".to_string(),
+		};
+		let (status, body) = get(Api::memory().email(email), "/readyz").await;
+		assert_eq!(status, axum::http::StatusCode::OK);
+		assert_eq!(body["status"], "ok");
+		// 返ってこない依存先は待ちきらずに失敗にする
+		let check = ready(
+			std::future::pending::<Result<(), Error>>(),
+			std::time::Duration::from_millis(10),
+		)
+		.await;
+		assert_eq!(check["status"], "error");
+	}
+
+	fn test_user() -> out::User {
+		out::User {
+			id: Uuid::now_v7(),
+			name: "Test User".to_string(),
+			auth_email: "test@example.com".to_string(),
+			is_active: true,
+			..Default::default()
+		}
+	}
+
+	#[tokio::test]
+	async fn test_user_get_requires_token() {
+		let (status, _) = call(Api::memory(), "GET", "/api/user", None, None).await;
+		assert_eq!(status, axum::http::StatusCode::FORBIDDEN);
+	}
+
+	#[tokio::test]
+	async fn test_user_get_and_pop_in_memory() {
+		let api = Api::memory();
+		let user = test_user();
+		user.push(&api.db).await.unwrap();
+		let token = user.signed_jwt();
+		let (status, body) = call(api.clone(), "GET", "/api/user", Some(&token), None).await;
+		assert_eq!(status, axum::http::StatusCode::OK);
+		let got: out::User = serde_json::from_slice(&body).unwrap();
+		assert_eq!(got.name, user.name);
+		let (status, _) = call(api.clone(), "DELETE", "/api/user", Some(&token), None).await;
+		assert_eq!(status, axum::http::StatusCode::NO_CONTENT);
+		let (status, _) = call(api.clone(), "GET", "/api/user", Some(&token), None).await;
+		assert_eq!(status, axum::http::StatusCode::NOT_FOUND);
+	}
+
+	#[tokio::test]
+	async fn test_revoke_sessions() {
+		let api = Api::memory();
+		let (a, b) = (test_user(), test_user());
+		a.push(&api.db).await.unwrap();
+		let (token_a, token_b) = (a.signed_jwt(), b.signed_jwt());
+		api.revoke_sessions(&[&a.id.to_string()]).await.unwrap();
+		let (status, _) = call(api.clone(), "GET", "/api/user", Some(&token_a), None).await;
+		assert_eq!(status, axum::http::StatusCode::FORBIDDEN);
+		// 他の利用者のトークンはそのまま使える
+		let (status, _) = call(api.clone(), "GET", "/api/user", Some(&token_b), None).await;
+		assert_eq!(status, axum::http::StatusCode::NOT_FOUND);
+		api.revoke_sessions(&[]).await.unwrap();
+		let (status, _) = call(api.clone(), "GET", "/api/user", Some(&token_b), None).await;
+		assert_eq!(status, axum::http::StatusCode::FORBIDDEN);
+		// 取り消した後に発行したトークンは使える
+		let later = auth::TokenJwt {
+			iat: Some(auth::timestamp() + 1),
+			exp: Some(auth::timestamp() + 60),
+			..a.jwt()
+		};
+		let token = jsonwebtoken::encode(
+			&Default::default(),
+			&later,
+			&jsonwebtoken::EncodingKey::from_secret(out::User::secret()),
+		)
+		.unwrap();
+		let (status, _) = call(api.clone(), "GET", "/api/user", Some(&token), None).await;
+		assert_eq!(status, axum::http::StatusCode::OK);
+	}
+
+	#[tokio::test]
+	async fn test_create_admin() {
+		let api = Api::memory();
+		let admin = api
+			.create_admin("admin@example.com", "Admin")
+			.await
+			.unwrap();
+		assert!(admin.is_admin && admin.is_active);
+		let user = test_user();
+		user.push(&api.db).await.unwrap();
+		let promoted = api.create_admin(&user.auth_email, "").await.unwrap();
+		assert_eq!(promoted.id, user.id);
+		let got = out::User::get(&api.db, &user.id.to_string()).await.unwrap();
+		assert!(got.is_admin);
+		assert_eq!(got.name, user.name);
+	}
+
+	#[tokio::test]
+	async fn test_import_rebuilds_unique_indexes() {
+		let api = Api::memory();
+		let user = test_user();
+		let line = serde_json::json!({
+			"collection": out::User::collection_name(),
+			"id": user.id.to_string(),
+			"data": user,
+		})
+		.to_string();
+		let report = api
+			.import(line.as_bytes(), &Default::default())
+			.await
+			.unwrap();
+		assert_eq!(report.written, 1);
+		assert!(report.duplicates.is_empty());
+		// 読み込んだ利用者と同じメールアドレスでは作れない
+		let other = test_user();
+		assert!(matches!(
+			other.push(&api.db).await,
+			Err(Error::AlreadyExists(_))
+		));
+		// 新しいIDで読み込むと同じメールアドレスの利用者が二人になるので知らせる
+		let options = backup::Import {
+			remap: backup::fresh_ids(line.as_bytes()).unwrap(),
+			..Default::default()
 		};
-		println!("{:?}", o);
-		o
+		let report = api.import(line.as_bytes(), &options).await.unwrap();
+		assert_eq!(report.duplicates.len(), 1);
+		let (field, value, ids) = &report.duplicates[0];
+		assert_eq!(field, "user.auth_email");
+		assert_eq!(value, &serde_json::json!(user.auth_email));
+		assert_eq!(ids.len(), 2);
+	}
+
+	// id_token を返すだけのトークンエンドポイントを立てて、Google ログインのコールバックを呼ぶ
+	async fn call_oauth<S: Storage + 'static>(
+		mut api: Api<S>,
+		claims: auth::TokenJwt,
+	) -> axum::http::StatusCode {
+		let id_token = jsonwebtoken::encode(
+			&Default::default(),
+			&claims,
+			&jsonwebtoken::EncodingKey::from_secret(b"google"),
+		)
+		.unwrap();
+		let body = serde_json::json!({
+			"access_token": "access",
+			"expires_in": 3600,
+			"scope": "email profile",
+			"token_type": "Bearer",
+			"id_token": id_token,
+		});
+		let router = axum::Router::new().route(
+			"/token",
+			axum::routing::post(async move || axum::Json(body.clone())),
+		);
+		let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let addr = listener.local_addr().unwrap();
+		tokio::spawn(async move { axum::serve(listener, router).await });
+		api.google.token_uri = format!("http://{addr}/token");
+		let state = auth::encode::url_encode("http://localhost/callback>>>0");
+		let uri = format!("/api/auth/callback_oauth?state={state}&code=code");
+		call(api, "GET", &uri, None, None).await.0
+	}
+
+	#[tokio::test]
+	async fn test_callback_oauth_links_email_user() {
+		let api = Api::memory();
+		let admin = api
+			.create_admin("admin@example.com", "Admin")
+			.await
+			.unwrap();
+		let claims = || auth::TokenJwt {
+			iat: Some(auth::timestamp()),
+			exp: Some(auth::timestamp() + 60),
+			sub: "google-admin".to_string(),
+			email: "admin@example.com".to_string(),
+			name: "Google Name".to_string(),
+			picture: Some("https://example.com/a.png".to_string()),
+			email_verified: true,
+		};
+		let status = call_oauth(api.clone(), claims()).await;
+		assert_eq!(status, axum::http::StatusCode::TEMPORARY_REDIRECT);
+		// 新しい利用者は作られず、管理者に Google のアカウントが紐付く
+		let users = out::User::query(&api.db, &out::User::select(), None)
+			.await
+			.unwrap();
+		assert_eq!(users.items.len(), 1);
+		let got = out::User::get(&api.db, &admin.id.to_string())
+			.await
+			.unwrap()
+			.into_inner();
+		assert_eq!(got.auth_google, "google-admin");
+		assert_eq!(got.name, "Admin");
+		assert_eq!(got.picture, "https://example.com/a.png");
+		assert!(got.is_admin && got.is_active);
+		// 2回目からは auth_google で見つかる
+		let status = call_oauth(api.clone(), claims()).await;
+		assert_eq!(status, axum::http::StatusCode::TEMPORARY_REDIRECT);
+		// 確認されていないメールアドレスでは紐付けない
+		let unverified = auth::TokenJwt {
+			sub: "google-other".to_string(),
+			email_verified: false,
+			..claims()
+		};
+		let status = call_oauth(api.clone(), unverified).await;
+		assert_eq!(status, axum::http::StatusCode::CONFLICT);
+		let got = out::User::get(&api.db, &admin.id.to_string())
+			.await
+			.unwrap()
+			.into_inner();
+		assert_eq!(got.auth_google, "google-admin");
+	}
+
+	#[tokio::test]
+	async fn test_user_set_patches_profile_only() {
+		let api = Api::memory();
+		let user = test_user();
+		user.push(&api.db).await.unwrap();
+		let token = user.signed_jwt();
+		let edited = out::User {
+			name: "Renamed".to_string(),
+			auth_email: "someone@example.com".to_string(),
+			is_active: false,
+			..user.clone()
+		};
+		let (status, body) = call(
+			api.clone(),
+			"POST",
+			"/api/user",
+			Some(&token),
+			Some(serde_json::json!({ "user": edited })),
+		)
+		.await;
+		assert_eq!(status, axum::http::StatusCode::OK);
+		let got: out::User = serde_json::from_slice(&body).unwrap();
+		assert_eq!(got.name, "Renamed");
+		assert_eq!(got.auth_email, user.auth_email);
+		assert!(got.is_active);
+	}
+
+	#[tokio::test]
+	async fn test_user_set_if_match() {
+		let api = Api::memory();
+		let user = test_user();
+		user.push(&api.db).await.unwrap();
+		let token = user.signed_jwt();
+		let set = |name: &str, etag: &str| {
+			axum::http::Request::builder()
+				.method("POST")
+				.uri("/api/user")
+				.header(axum::http::header::COOKIE, format!("token={token}"))
+				.header(axum::http::header::CONTENT_TYPE, "application/json")
+				.header(axum::http::header::IF_MATCH, etag)
+				.body(axum::body::Body::from(
+					serde_json::json!({ "user": out::User { name: name.to_string(), ..user.clone() } })
+						.to_string(),
+				))
+				.unwrap()
+		};
+		let response = out::axum_router(api.clone())
+			.oneshot(
+				axum::http::Request::builder()
+					.uri("/api/user")
+					.header(axum::http::header::COOKIE, format!("token={token}"))
+					.body(axum::body::Body::empty())
+					.unwrap(),
+			)
+			.await
+			.unwrap();
+		let etag = response.headers()[axum::http::header::ETAG]
+			.to_str()
+			.unwrap()
+			.to_string();
+		// 読んだ版のままなら書き換えられて、新しい版が返る
+		let response = out::axum_router(api.clone())
+			.oneshot(set("First", &etag))
+			.await
+			.unwrap();
+		assert_eq!(response.status(), axum::http::StatusCode::OK);
+		assert_ne!(response.headers()[axum::http::header::ETAG], etag.as_str());
+		// 同じ古い版でもう一度書くと、先の書き込みを上書きせずに 412
+		let response = out::axum_router(api.clone())
+			.oneshot(set("Second", &etag))
+			.await
+			.unwrap();
+		assert_eq!(
+			response.status(),
+			axum::http::StatusCode::PRECONDITION_FAILED
+		);
+		let got = out::User::get(&api.db, &user.id.to_string()).await.unwrap();
+		assert_eq!(got.name, "First");
+		// 弱い ETag は強い比較で一致しないので、今の版を指していても 412
+		let current = got.version.etag();
+		let response = out::axum_router(api.clone())
+			.oneshot(set("Weak", &format!("W/{current}")))
+			.await
+			.unwrap();
+		assert_eq!(
+			response.status(),
+			axum::http::StatusCode::PRECONDITION_FAILED
+		);
+		let got = out::User::get(&api.db, &user.id.to_string()).await.unwrap();
+		assert_eq!(got.name, "First");
+		let response = out::axum_router(api.clone())
+			.oneshot(set("Any", "*"))
+			.await
+			.unwrap();
+		assert_eq!(response.status(), axum::http::StatusCode::OK);
+	}
+
+	#[tokio::test]
+	async fn test_video_home_pages() {
+		let api = Api::memory();
+		let user = test_user();
+		let token = user.signed_jwt();
+		for name in ["a", "b", "c"] {
+			out::Video {
+				id: Uuid::now_v7(),
+				name: name.to_string(),
+				..Default::default()
+			}
+			.push(&api.db)
+			.await
+			.unwrap();
+		}
+		let home = async |query: String| {
+			let uri = format!("/api/video/home?{query}");
+			call(api.clone(), "GET", &uri, Some(&token), None).await
+		};
+		let (status, body) = home("limit=2".to_string()).await;
+		assert_eq!(status, axum::http::StatusCode::OK);
+		let first: out::VideoList = serde_json::from_slice(&body).unwrap();
+		assert_eq!(first.items.len(), 2);
+		let cursor = first.next_cursor.unwrap();
+		let (_, body) = home(format!("limit=2&cursor={cursor}")).await;
+		let second: out::VideoList = serde_json::from_slice(&body).unwrap();
+		assert_eq!(second.items.len(), 1);
+		assert_eq!(second.next_cursor, None);
+		// 改ざんしたカーソルは 400
+		let (status, _) = home(format!("limit=2&cursor={cursor}x")).await;
+		assert_eq!(status, axum::http::StatusCode::BAD_REQUEST);
+	}
+
+	// FIRESTORE_EMULATOR_HOST にエミュレータがあるときだけ、ハンドラを Firestore で通しで動かす
+	#[tokio::test]
+	async fn test_emulator() {
+		let Some(db) = storage::Firestore::for_test().await else {
+			return;
+		};
+		let api = Api::offline(db);
+		let user = test_user();
+		user.push(&api.db).await.unwrap();
+		let token = user.signed_jwt();
+		let (status, body) = call(api.clone(), "GET", "/api/user", Some(&token), None).await;
+		assert_eq!(status, axum::http::StatusCode::OK);
+		assert_eq!(serde_json::from_slice::<out::User>(&body).unwrap(), user);
+		let edited = out::User {
+			name: "Renamed".to_string(),
+			..user.clone()
+		};
+		let (status, _) = call(
+			api.clone(),
+			"POST",
+			"/api/user",
+			Some(&token),
+			Some(serde_json::json!({ "user": edited })),
+		)
+		.await;
+		assert_eq!(status, axum::http::StatusCode::OK);
+		let got = out::User::get(&api.db, &user.id.to_string()).await.unwrap();
+		assert_eq!(got.name, "Renamed");
+		for name in ["a", "b", "c"] {
+			out::Video {
+				id: Uuid::now_v7(),
+				name: name.to_string(),
+				..Default::default()
+			}
+			.push(&api.db)
+			.await
+			.unwrap();
+		}
+		let mut names = vec![];
+		let mut uri = "/api/video/home?limit=2".to_string();
+		loop {
+			let (status, body) = call(api.clone(), "GET", &uri, Some(&token), None).await;
+			assert_eq!(status, axum::http::StatusCode::OK);
+			let page: out::VideoList = serde_json::from_slice(&body).unwrap();
+			names.extend(page.items.into_iter().map(|v| v.name));
+			match page.next_cursor {
+				Some(cursor) => uri = format!("/api/video/home?limit=2&cursor={cursor}"),
+				None => break,
+			}
+		}
+		assert_eq!(names, ["a", "b", "c"]);
+		let (status, _) = call(api.clone(), "DELETE", "/api/user", Some(&token), None).await;
+		assert_eq!(status, axum::http::StatusCode::NO_CONTENT);
+		let (status, _) = call(api.clone(), "GET", "/api/user", Some(&token), None).await;
+		assert_eq!(status, axum::http::StatusCode::NOT_FOUND);
+	}
+
+	#[tokio::test]
+	async fn test_video_search() {
+		let api = Api::memory();
+		for (name, content) in [("Rust", "axum"), ("Go", "rust"), ("Zig", "")] {
+			out::Video {
+				id: Uuid::now_v7(),
+				name: name.to_string(),
+				content: content.to_string(),
+				..Default::default()
+			}
+			.push(&api.db)
+			.await
+			.unwrap();
+		}
+		assert_eq!(api.search.rebuild(&api.db).await.unwrap(), 3);
+		let (status, body) = call(api.clone(), "GET", "/api/video/search?q=rust", None, None).await;
+		assert_eq!(status, axum::http::StatusCode::OK);
+		let found: out::VideoList = serde_json::from_slice(&body).unwrap();
+		let names: Vec<_> = found.items.iter().map(|v| v.name.as_str()).collect();
+		assert_eq!(names, ["Rust", "Go"]);
+	}
+
+	// 変更を SSE で受け取るまで読み進め、id と data を返す
+	async fn next_event(
+		body: &mut (impl futures::Stream<Item = Result<axum::body::Bytes, axum::Error>> + Unpin),
+		event: &str,
+	) -> (String, serde_json::Value) {
+		use futures::StreamExt;
+		let mut buffer = String::new();
+		loop {
+			let chunk = body.next().await.unwrap().unwrap();
+			buffer.push_str(std::str::from_utf8(&chunk).unwrap());
+			while let Some((frame, rest)) = buffer.split_once("\n\n") {
+				let field = |name: &str| {
+					frame
+						.lines()
+						.find_map(|l| l.strip_prefix(name))
+						.unwrap_or_default()
+						.to_string()
+				};
+				if field("event: ") == event {
+					return (
+						field("id: "),
+						serde_json::from_str(&field("data: ")).unwrap(),
+					);
+				}
+				buffer = rest.to_string();
+			}
+		}
+	}
+
+	async fn watch(
+		api: &Api<storage::Memory>,
+		uri: &str,
+		headers: &[(&str, &str)],
+	) -> axum::response::Response {
+		let mut builder = axum::http::Request::builder().uri(uri);
+		for (name, value) in headers {
+			builder = builder.header(*name, *value);
+		}
+		api.clone()
+			.watch_router()
+			.oneshot(builder.body(axum::body::Body::empty()).unwrap())
+			.await
+			.unwrap()
+	}
+
+	#[tokio::test]
+	async fn test_video_watch_requires_token() {
+		let response = watch(&Api::memory(), "/api/video/watch", &[]).await;
+		assert_eq!(response.status(), axum::http::StatusCode::FORBIDDEN);
+	}
+
+	#[tokio::test]
+	async fn test_video_watch_resumes() {
+		let api = Api::memory();
+		let cookie = format!("token={}", test_user().signed_jwt());
+		let response = watch(&api, "/api/video/watch", &[("cookie", &cookie)]).await;
+		assert_eq!(response.status(), axum::http::StatusCode::OK);
+		let mut body = response.into_body().into_data_stream();
+		let videos: Vec<_> = ["a", "b"]
+			.map(|name| out::Video {
+				id: Uuid::now_v7(),
+				name: name.to_string(),
+				..Default::default()
+			})
+			.into();
+		for v in &videos {
+			v.push(&api.db).await.unwrap();
+		}
+		let (id, data) = next_event(&mut body, "put").await;
+		assert_eq!(data["name"], "a");
+		drop(body);
+		// 切れた後に受け取った id から再開すると、その次の変更から流れてくる
+		let response = watch(
+			&api,
+			"/api/video/watch",
+			&[("cookie", &cookie), ("last-event-id", &id)],
+		)
+		.await;
+		let mut body = response.into_body().into_data_stream();
+		let (_, data) = next_event(&mut body, "put").await;
+		assert_eq!(data["name"], "b");
+		out::Video::pop(&api.db, &videos[1].id.to_string())
+			.await
+			.unwrap();
+		let (_, data) = next_event(&mut body, "delete").await;
+		assert_eq!(data["id"], videos[1].id.to_string());
+		// 1つの動画を見ているときは他の動画の変更は流れてこない
+		let uri = format!("/api/video/{}/watch", videos[0].id);
+		let response = watch(&api, &uri, &[("cookie", &cookie)]).await;
+		let mut body = response.into_body().into_data_stream();
+		videos[1].push(&api.db).await.unwrap();
+		videos[0].upsert(&api.db).await.unwrap();
+		let (_, data) = next_event(&mut body, "put").await;
+		assert_eq!(data["name"], "a");
+		// 他の動画の削除も流れてこない
+		for v in [&videos[1], &videos[0]] {
+			out::Video::pop(&api.db, &v.id.to_string()).await.unwrap();
+		}
+		let (_, data) = next_event(&mut body, "delete").await;
+		assert_eq!(data["id"], videos[0].id.to_string());
+		let response = watch(&api, "/api/video/x/watch", &[("cookie", &cookie)]).await;
+		assert_eq!(response.status(), axum::http::StatusCode::BAD_REQUEST);
+	}
+
+	#[tokio::test]
+	async fn test_video_push_owner_only() {
+		let api = Api::memory();
+		let (owner, other) = (test_user(), test_user());
+		let push = async |token: &str, video: &out::Video| {
+			call(
+				api.clone(),
+				"POST",
+				"/api/video",
+				Some(token),
+				Some(serde_json::json!({ "video": video })),
+			)
+			.await
+		};
+		let (status, body) = push(
+			&owner.signed_jwt(),
+			&out::Video {
+				name: "a".to_string(),
+				// 作った利用者はクライアントから受け取らない
+				id_user: other.id,
+				..Default::default()
+			},
+		)
+		.await;
+		assert_eq!(status, axum::http::StatusCode::OK);
+		let video: out::Video = serde_json::from_slice(&body).unwrap();
+		assert_eq!(video.id_user, owner.id);
+		// 他の利用者は書き換えられない
+		let edited = out::Video {
+			name: "b".to_string(),
+			..video.clone()
+		};
+		let (status, _) = push(&other.signed_jwt(), &edited).await;
+		assert_eq!(status, axum::http::StatusCode::FORBIDDEN);
+		let got = out::Video::get(&api.db, &video.id.to_string())
+			.await
+			.unwrap();
+		assert_eq!(got.name, "a");
+		// 本人と管理者は書き換えられる
+		let (status, _) = push(&owner.signed_jwt(), &edited).await;
+		assert_eq!(status, axum::http::StatusCode::OK);
+		let admin = api
+			.create_admin("admin@example.com", "Admin")
+			.await
+			.unwrap();
+		let edited = out::Video {
+			name: "c".to_string(),
+			..video.clone()
+		};
+		let (status, _) = push(&admin.signed_jwt(), &edited).await;
+		assert_eq!(status, axum::http::StatusCode::OK);
+		let got = out::Video::get(&api.db, &video.id.to_string())
+			.await
+			.unwrap();
+		assert_eq!((got.name.as_str(), got.id_user), ("c", owner.id));
+		// id_user が無かった頃の動画は管理者だけが書き換えられる
+		let mut old = serde_json::to_value(&video).unwrap();
+		old.as_object_mut().unwrap().remove("id_user");
+		let old_id = Uuid::now_v7();
+		old["id"] = old_id.to_string().into();
+		api.db
+			.write("page", &old_id.to_string(), &old, &Default::default())
+			.await
+			.unwrap();
+		let legacy = out::Video::get(&api.db, &old_id.to_string())
+			.await
+			.unwrap()
+			.into_inner();
+		assert!(legacy.id_user.is_nil());
+		let (status, _) = push(&owner.signed_jwt(), &legacy).await;
+		assert_eq!(status, axum::http::StatusCode::FORBIDDEN);
+		let (status, _) = push(&admin.signed_jwt(), &legacy).await;
+		assert_eq!(status, axum::http::StatusCode::OK);
+	}
+
+	#[tokio::test]
+	async fn test_user_reads_old_document() {
+		let api = Api::memory();
+		let mut old = serde_json::to_value(test_user()).unwrap();
+		old.as_object_mut().unwrap().remove("auth_email_password");
+		old.as_object_mut().unwrap().remove("is_admin");
+		let id = old["id"].as_str().unwrap().to_string();
+		api.db
+			.write("user", &id, &old, &Default::default())
+			.await
+			.unwrap();
+		assert_eq!(
+			out::User::get(&api.db, &id)
+				.await
+				.unwrap()
+				.auth_email_password,
+			""
+		);
+		let reports = api.migrate(false).await.unwrap();
+		assert_eq!(reports[0].1.migrated, [id.clone()]);
+		let stored = api.db.get("user", &id).await.unwrap().unwrap().data;
+		assert_eq!(stored[migration::SCHEMA_VERSION], 2);
+		assert_eq!(stored["is_admin"], false);
+	}
+
+	#[tokio::test]
+	async fn test_video_rollup() {
+		let api = Api::memory();
+		let video = out::Video {
+			id: Uuid::now_v7(),
+			name: "a".to_string(),
+			..Default::default()
+		};
+		video.push(&api.db).await.unwrap();
+		let id = video.id.to_string();
+		let today: chrono::NaiveDate = "2026-03-10".parse().unwrap();
+		for (days, field, n) in [
+			(0, "view", 3),
+			(1, "view", 2),
+			(8, "view", 4),
+			(0, "star", 1),
+		] {
+			for _ in 0..n {
+				let day = today - chrono::Days::new(days);
+				VIDEO_COUNT
+					.increment(&api.db, &id, field, 1, day)
+					.await
+					.unwrap();
+			}
+		}
+		assert_eq!(api.rollup(today).await.unwrap(), 1);
+		let got = out::Video::get(&api.db, &id).await.unwrap().into_inner();
+		assert_eq!(got.name, "a");
+		assert_eq!(
+			(
+				got.count_view.day,
+				got.count_view.week,
+				got.count_view.month
+			),
+			(3, 5, 9)
+		);
+		assert_eq!(got.count_view.log.len(), COUNT_DAYS);
+		assert_eq!(got.count_star.month, 1);
+		assert_eq!(got.count_text.month, 0);
+	}
+
+	#[tokio::test]
+	async fn test_export_import_counts() {
+		let api = Api::memory();
+		let video = out::Video {
+			id: Uuid::now_v7(),
+			name: "a".to_string(),
+			..Default::default()
+		};
+		video.push(&api.db).await.unwrap();
+		let id = video.id.to_string();
+		let today: chrono::NaiveDate = "2026-03-10".parse().unwrap();
+		for field in ["view", "view", "star"] {
+			VIDEO_COUNT
+				.increment(&api.db, &id, field, 1, today)
+				.await
+				.unwrap();
+		}
+		// rollup する前の数も書き出したものから戻る
+		let mut output = vec![];
+		api.export(&[], &mut output).await.unwrap();
+		let restored = Api::memory();
+		restored
+			.import(output.as_slice(), &Default::default())
+			.await
+			.unwrap();
+		assert_eq!(restored.rollup(today).await.unwrap(), 1);
+		let got = out::Video::get(&restored.db, &id)
+			.await
+			.unwrap()
+			.into_inner();
+		assert_eq!((got.count_view.day, got.count_star.day), (2, 1));
+		// 動画だけを書き出すときもカウンタは付いてくる
+		let mut only = vec![];
+		let count = api
+			.export(&[out::Video::collection_name()], &mut only)
+			.await
+			.unwrap();
+		assert!(count > 1);
+	}
+
+	// 定数のフィールド名が実際に書き出されるフィールドにあるか
+	#[test]
+	fn test_fields_exist() {
+		fn exists<C: serde::Serialize + Default>(names: &[&str]) {
+			let value = serde_json::to_value(C::default()).unwrap();
+			for name in names {
+				assert!(
+					name.split('.').try_fold(&value, |v, k| v.get(k)).is_some(),
+					"{name}"
+				);
+			}
+		}
+		exists::<out::User>(&[
+			out::User::ID.name(),
+			out::User::NAME.name(),
+			out::User::PICTURE.name(),
+			out::User::AUTH_EMAIL.name(),
+			out::User::AUTH_GOOGLE.name(),
+			out::User::IS_ACTIVE.name(),
+		]);
+		exists::<out::Video>(&[
+			out::Video::ID.name(),
+			out::Video::ID_ROOT.name(),
+			out::Video::ID_NODE.name(),
+			out::Video::NAME.name(),
+			out::Video::COUNT_VIEW_DAY.name(),
+			out::Video::COUNT_STAR_DAY.name(),
+		]);
 	}
 }
@@ -1,7 +1,9 @@
+use crate::error::Error;
+use crate::metrics;
 use reqwest;
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Deserialize)]
+#[derive(Clone, Deserialize)]
 pub struct OAuth {
 	pub client_id: String,
 	pub client_secret: String,
@@ -9,46 +11,81 @@ pub struct OAuth {
 	pub auth_uri: String,
 }
 
+// ログに出しても秘密が漏れないように伏せる
+impl std::fmt::Debug for OAuth {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("OAuth")
+			.field("client_id", &self.client_id)
+			.field("client_secret", &"[redacted]")
+			.field("token_uri", &self.token_uri)
+			.field("auth_uri", &self.auth_uri)
+			.finish()
+	}
+}
+
 impl OAuth {
 	const SEPRATOR: &str = ">>>";
 	//JSONファイル内の特定のフィールド（またはトップレベル）をターゲットとしてOAuthにパース
-	pub fn load(path: &str, field: Option<&str>) -> Result<Self, String> {
+	pub fn load(path: &str, field: Option<&str>) -> Result<Self, Error> {
 		// 1. ファイルを読み込む
-		let data =
-			std::fs::read_to_string(path).map_err(|e| format!("ファイル読み込みエラー: {}", e))?;
+		let data = std::fs::read_to_string(path)
+			.map_err(|e| Error::Internal(format!("ファイル読み込みエラー: {}", e)))?;
 		// 2. JSON全体をserde_json::Valueとしてパースする
-		let value: serde_json::Value =
-			serde_json::from_str(&data).map_err(|e| format!("JSONパースエラー: {}", e))?;
+		let value: serde_json::Value = serde_json::from_str(&data)
+			.map_err(|e| Error::Invalid(format!("JSONパースエラー: {}", e)))?;
 		let target_value = if let Some(field_name) = field {
 			value.get(field_name).ok_or_else(|| {
-				format!(
+				Error::Invalid(format!(
 					"指定されたフィールド '{}' がJSONに見つかりません",
 					field_name
-				)
+				))
 			})?
 		} else {
 			&value
 		};
 		let oauth: Self = serde_json::from_value(target_value.clone())
-			.map_err(|e| format!("OAuth構造体へのデシリアライズエラー: {}", e))?;
+			.map_err(|e| Error::Invalid(format!("OAuth構造体へのデシリアライズエラー: {}", e)))?;
 		Ok(oauth)
 	}
+	// ログインに使える値がそろっているか、/readyz で確かめる
+	pub fn check(&self) -> Result<(), Error> {
+		if self.client_id.is_empty() || self.client_secret.is_empty() {
+			return Err(Error::Invalid("oauth client is not configured".to_string()));
+		}
+		for uri in [&self.auth_uri, &self.token_uri] {
+			if !uri.starts_with("https://") && !uri.starts_with("http://") {
+				return Err(Error::Invalid(format!("oauth uri is not a url: {uri}")));
+			}
+		}
+		Ok(())
+	}
 	pub fn redirect_uri(&self, redirect_uri: &str) -> String {
 		let start_time = timestamp();
-		format!(
-			"{auth_uri}?client_id={client_id}&redirect_uri={redir}&response_type=code&scope=openid%20email%20profile&state={state}",
-			auth_uri = self.auth_uri,
-			client_id = self.client_id,
-			redir = encode::url_encode(&redirect_uri),
-			state = encode::url_encode(&format!("{redirect_uri}{}{start_time}", Self::SEPRATOR)),
-		)
-	}
-	pub async fn callback(&self, state: &str, code: &str) -> Result<TokenResponse, String> {
-		let state_decoded = encode::url_decode(state).map_err(|e| e.to_string())?;
+		encode::Query::new()
+			.param("client_id", &self.client_id)
+			.param("redirect_uri", redirect_uri)
+			.param("response_type", "code")
+			.param("scope", "openid email profile")
+			.param(
+				"state",
+				format!("{redirect_uri}{}{start_time}", Self::SEPRATOR),
+			)
+			.with_base(&self.auth_uri)
+	}
+	// 認可コードをトークンに換える、換えた数と時間を数える
+	pub async fn callback(&self, state: &str, code: &str) -> Result<TokenResponse, Error> {
+		let started = std::time::Instant::now();
+		let result = self.exchange(state, code).await;
+		metrics::OAUTH_REQUESTS.inc(&[metrics::outcome(&result)]);
+		metrics::OAUTH_DURATION.observe(&[], started.elapsed());
+		result
+	}
+	async fn exchange(&self, state: &str, code: &str) -> Result<TokenResponse, Error> {
+		let state_decoded = encode::url_decode(state)?;
 		let redirect_uri = state_decoded
 			.split(Self::SEPRATOR)
 			.next()
-			.ok_or(format!("Invalid oauth state:{state}"))?;
+			.ok_or(Error::Invalid(format!("Invalid oauth state:{state}")))?;
 		let form = [
 			("code", code),
 			("client_id", &self.client_id),
@@ -61,16 +98,13 @@ impl OAuth {
 			.post(&self.token_uri)
 			.form(&form)
 			.build()
-			.map_err(|e| format!("Invalid request: {e}"))?;
-		let response = client
-			.execute(request)
-			.await
-			.map_err(|e| format!("HTTP request failed: {e}"))?;
+			.map_err(|e| Error::Internal(format!("Invalid request: {e}")))?;
+		let response = client.execute(request).await?;
 		if response.status().is_success() {
 			let tokens = response
 				.json::<TokenResponse>()
 				.await
-				.map_err(|e| format!("Failed to parse JSON: {e}"))?;
+				.map_err(|e| Error::Internal(format!("Failed to parse JSON: {e}")))?;
 			return Ok(tokens);
 		} else {
 			let status = response.status();
@@ -78,12 +112,18 @@ impl OAuth {
 				.text()
 				.await
 				.unwrap_or_else(|_| "<body read error>".into());
-			return Err(format!("token endpoint returned error {status}: {text}"));
+			let message = format!("token endpoint returned error {status}: {text}");
+			// 4xx は code や state が不正、5xx は Google 側の一時的な障害
+			return Err(if status.is_server_error() {
+				Error::Unavailable(message)
+			} else {
+				Error::Invalid(message)
+			});
 		}
 	}
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize)]
 pub struct TokenResponse {
 	access_token: String,
 	expires_in: u64,
@@ -93,11 +133,30 @@ pub struct TokenResponse {
 	id_token: Option<String>, // OIDC の場合これがメイン
 }
 
+impl std::fmt::Debug for TokenResponse {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("TokenResponse")
+			.field("access_token", &"[redacted]")
+			.field("expires_in", &self.expires_in)
+			.field(
+				"refresh_token",
+				&self.refresh_token.as_ref().map(|_| "[redacted]"),
+			)
+			.field("scope", &self.scope)
+			.field("token_type", &self.token_type)
+			.field("id_token", &self.id_token.as_ref().map(|_| "[redacted]"))
+			.finish()
+	}
+}
+
 impl TokenResponse {
 	//jsonwebtoken::でsubを取り出して
-	pub fn jwt(&self) -> Result<TokenJwt, String> {
+	pub fn jwt(&self) -> Result<TokenJwt, Error> {
 		// 1. id_token が存在するか確認
-		let token_str = self.id_token.as_ref().ok_or("no id_token")?;
+		let token_str = self
+			.id_token
+			.as_ref()
+			.ok_or(Error::Invalid("no id_token".to_string()))?;
 
 		// 2. 検証設定を作成 (署名検証を無効化)
 		let mut validation = jsonwebtoken::Validation::new(jsonwebtoken::Algorithm::RS256);
@@ -109,8 +168,7 @@ impl TokenResponse {
 		// 署名検証しないので Key はダミーでOK
 		let dummy_key = jsonwebtoken::DecodingKey::from_secret(&[]);
 
-		let token_data = jsonwebtoken::decode::<TokenJwt>(token_str, &dummy_key, &validation)
-			.map_err(|e| e.to_string())?; // デコード失敗時は None を返す
+		let token_data = jsonwebtoken::decode::<TokenJwt>(token_str, &dummy_key, &validation)?;
 
 		// 4. sub を返す
 		Ok(token_data.claims)
@@ -127,6 +185,9 @@ pub struct TokenJwt {
 	pub email: String,
 	pub name: String,
 	pub picture: Option<String>,
+	// Google が確認済みのメールアドレスか、確認済みのときだけ同じメールの利用者に紐付ける
+	#[serde(default, skip_serializing_if = "std::ops::Not::not")]
+	pub email_verified: bool,
 }
 impl TokenJwt {
 	pub fn age(&self) -> Option<usize> {
@@ -284,13 +345,65 @@ and we hope you enjoy using {service_name}.
 }
 
 pub mod encode {
+	// パーセントエンコーディングのユーティリティ
+	// RFC 3986 形式(空白は%20, '+'はそのまま)と application/x-www-form-urlencoded 形式(空白は'+')の両方を扱う
+	// デコードは一旦バイト列に戻してからUTF-8として検証するので、日本語のパスなどマルチバイト文字も壊れない
+
+	#[derive(Debug, Clone, PartialEq, Eq)]
+	pub enum DecodeError {
+		// '%' の後に2桁の16進数が続かない (位置はバイトオフセット)
+		Incomplete(usize),
+		// '%' の後が16進数ではない
+		InvalidHex(usize),
+		// デコード結果がUTF-8として不正
+		InvalidUtf8(std::string::FromUtf8Error),
+	}
+
+	impl std::fmt::Display for DecodeError {
+		fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+			match self {
+				Self::Incomplete(i) => write!(f, "incomplete percent-encoding at {i}"),
+				Self::InvalidHex(i) => write!(f, "invalid hex in percent-encoding at {i}"),
+				Self::InvalidUtf8(e) => write!(f, "decoded bytes are not valid UTF-8: {e}"),
+			}
+		}
+	}
+
+	impl std::error::Error for DecodeError {}
+
+	#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+	pub enum Mode {
+		// RFC 3986: unreserved 以外はすべて %XX, '+' はリテラル
+		#[default]
+		Rfc3986,
+		// application/x-www-form-urlencoded: 空白は '+', '+' 自体は %2B
+		Form,
+	}
+
 	pub fn url_encode(input: &str) -> String {
-		let mut out = String::new();
+		encode(input, Mode::Rfc3986)
+	}
+
+	pub fn url_decode(input: &str) -> Result<String, DecodeError> {
+		decode(input, Mode::Rfc3986)
+	}
+
+	pub fn form_encode(input: &str) -> String {
+		encode(input, Mode::Form)
+	}
+
+	pub fn form_decode(input: &str) -> Result<String, DecodeError> {
+		decode(input, Mode::Form)
+	}
+
+	pub fn encode(input: &str, mode: Mode) -> String {
+		let mut out = String::with_capacity(input.len());
 		for b in input.bytes() {
-			let c = b as char;
 			// RFC 3986 unreserved: ALPHA / DIGIT / "-" / "." / "_" / "~"
-			if c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.' || c == '~' {
-				out.push(c);
+			if b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.' | b'~') {
+				out.push(b as char);
+			} else if b == b' ' && mode == Mode::Form {
+				out.push('+');
 			} else {
 				out.push('%');
 				out.push(hex_digit(b >> 4));
@@ -300,34 +413,155 @@ pub mod encode {
 		out
 	}
 
-	pub fn url_decode(input: &str) -> Result<String, &'static str> {
-		let mut out = String::new();
+	pub fn decode(input: &str, mode: Mode) -> Result<String, DecodeError> {
+		String::from_utf8(decode_bytes(input, mode)?).map_err(DecodeError::InvalidUtf8)
+	}
+
+	// 文字ではなくバイトとして復元する、UTF-8の検証は呼び出し側で行う
+	pub fn decode_bytes(input: &str, mode: Mode) -> Result<Vec<u8>, DecodeError> {
 		let bytes = input.as_bytes();
+		let mut out = Vec::with_capacity(bytes.len());
 		let mut i = 0;
-
 		while i < bytes.len() {
 			match bytes[i] {
 				b'%' => {
 					if i + 2 >= bytes.len() {
-						return Err("incomplete percent-encoding");
+						return Err(DecodeError::Incomplete(i));
 					}
-
-					let hi = from_hex_digit(bytes[i + 1]).ok_or("invalid hex")?;
-					let lo = from_hex_digit(bytes[i + 2]).ok_or("invalid hex")?;
-
-					out.push(((hi << 4) | lo) as char);
+					let hi = from_hex_digit(bytes[i + 1]).ok_or(DecodeError::InvalidHex(i))?;
+					let lo = from_hex_digit(bytes[i + 2]).ok_or(DecodeError::InvalidHex(i))?;
+					out.push((hi << 4) | lo);
 					i += 3;
 				}
-				_ => {
-					out.push(bytes[i] as char);
+				b'+' if mode == Mode::Form => {
+					out.push(b' ');
+					i += 1;
+				}
+				b => {
+					out.push(b);
 					i += 1;
 				}
 			}
 		}
-
 		Ok(out)
 	}
 
+	// クエリ文字列に載せられる値
+	pub trait QueryValue {
+		// None を返した場合そのパラメータは出力しない
+		fn query_value(&self) -> Option<String>;
+	}
+	impl QueryValue for str {
+		fn query_value(&self) -> Option<String> {
+			Some(self.to_string())
+		}
+	}
+	impl QueryValue for String {
+		fn query_value(&self) -> Option<String> {
+			Some(self.clone())
+		}
+	}
+	impl QueryValue for bool {
+		fn query_value(&self) -> Option<String> {
+			Some(self.to_string())
+		}
+	}
+	impl QueryValue for uuid::Uuid {
+		fn query_value(&self) -> Option<String> {
+			Some(self.to_string())
+		}
+	}
+	macro_rules! query_value_display {
+		($($t:ty),*) => {$(
+			impl QueryValue for $t {
+				fn query_value(&self) -> Option<String> {
+					Some(self.to_string())
+				}
+			}
+		)*};
+	}
+	query_value_display!(i32, i64, u16, u32, u64, usize);
+	impl<T: QueryValue + ?Sized> QueryValue for &T {
+		fn query_value(&self) -> Option<String> {
+			(**self).query_value()
+		}
+	}
+	impl<T: QueryValue> QueryValue for Option<T> {
+		fn query_value(&self) -> Option<String> {
+			self.as_ref().and_then(|v| v.query_value())
+		}
+	}
+
+	// クエリ文字列を組み立てる
+	// Query::new().param("a", "x y").param("n", 1).to_string() == "a=x%20y&n=1"
+	#[derive(Debug, Clone, Default, PartialEq, Eq)]
+	pub struct Query {
+		pairs: Vec<(String, String)>,
+		mode: Mode,
+	}
+
+	impl Query {
+		pub fn new() -> Self {
+			Self::default()
+		}
+		// 既定は RFC 3986、フォーム形式で出力したい場合に指定する
+		pub fn mode(mut self, mode: Mode) -> Self {
+			self.mode = mode;
+			self
+		}
+		pub fn param(mut self, key: &str, value: impl QueryValue) -> Self {
+			if let Some(v) = value.query_value() {
+				self.pairs.push((key.to_string(), v));
+			}
+			self
+		}
+		pub fn pairs(&self) -> &[(String, String)] {
+			&self.pairs
+		}
+		pub fn get(&self, key: &str) -> Option<&str> {
+			self.pairs
+				.iter()
+				.find(|(k, _)| k == key)
+				.map(|(_, v)| v.as_str())
+		}
+		// "https://example.com/path" に "?a=b&c=d" を付けたURLを返す
+		pub fn with_base(&self, base: &str) -> String {
+			if self.pairs.is_empty() {
+				return base.to_string();
+			}
+			let separator = if base.contains('?') { '&' } else { '?' };
+			format!("{base}{separator}{self}")
+		}
+		// "a=b&c=d" または "?a=b&c=d" を解釈する、値は form 形式('+'は空白)としてデコードする
+		pub fn parse(input: &str) -> Result<Self, DecodeError> {
+			let pairs = input
+				.trim_start_matches('?')
+				.split('&')
+				.filter(|s| !s.is_empty())
+				.map(|pair| {
+					let (k, v) = pair.split_once('=').unwrap_or((pair, ""));
+					Ok((form_decode(k)?, form_decode(v)?))
+				})
+				.collect::<Result<Vec<_>, DecodeError>>()?;
+			Ok(Self {
+				pairs,
+				mode: Mode::Form,
+			})
+		}
+	}
+
+	impl std::fmt::Display for Query {
+		fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+			for (i, (k, v)) in self.pairs.iter().enumerate() {
+				if i > 0 {
+					f.write_str("&")?;
+				}
+				write!(f, "{}={}", encode(k, self.mode), encode(v, self.mode))?;
+			}
+			Ok(())
+		}
+	}
+
 	fn hex_digit(n: u8) -> char {
 		match n {
 			0..=9 => (b'0' + n) as char,
@@ -343,6 +577,98 @@ pub mod encode {
 			_ => None,
 		}
 	}
+
+	#[cfg(test)]
+	mod tests {
+		use super::*;
+		use proptest::prelude::*;
+
+		#[test]
+		fn test_decode_multibyte() {
+			let path = "/ホーム/動画?q=日本語";
+			let encoded = url_encode(path);
+			assert!(encoded.is_ascii());
+			assert_eq!(url_decode(&encoded).unwrap(), path);
+			assert_eq!(url_decode("%E5%8B%95%E7%94%BB").unwrap(), "動画");
+		}
+
+		#[test]
+		fn test_decode_plus() {
+			assert_eq!(url_decode("a+b").unwrap(), "a+b");
+			assert_eq!(form_decode("a+b").unwrap(), "a b");
+			assert_eq!(form_decode("a%2Bb").unwrap(), "a+b");
+			assert_eq!(form_encode("a b+c"), "a+b%2Bc");
+			assert_eq!(url_encode("a b+c"), "a%20b%2Bc");
+		}
+
+		#[test]
+		fn test_decode_errors() {
+			assert_eq!(url_decode("abc%"), Err(DecodeError::Incomplete(3)));
+			assert_eq!(url_decode("abc%4"), Err(DecodeError::Incomplete(3)));
+			assert_eq!(url_decode("%zz"), Err(DecodeError::InvalidHex(0)));
+			assert!(matches!(
+				url_decode("%E5%8B"),
+				Err(DecodeError::InvalidUtf8(_))
+			));
+		}
+
+		#[test]
+		fn test_query_builder() {
+			let q = Query::new()
+				.param("client_id", "abc")
+				.param("scope", "openid email profile")
+				.param("limit", 10)
+				.param("cursor", None::<String>);
+			assert_eq!(
+				q.to_string(),
+				"client_id=abc&scope=openid%20email%20profile&limit=10"
+			);
+			assert_eq!(
+				q.with_base("https://example.com/auth"),
+				format!("https://example.com/auth?{q}")
+			);
+			assert_eq!(
+				q.with_base("https://example.com/auth?x=1"),
+				format!("https://example.com/auth?x=1&{q}")
+			);
+			let form = q.clone().mode(Mode::Form);
+			assert_eq!(
+				form.to_string(),
+				"client_id=abc&scope=openid+email+profile&limit=10"
+			);
+		}
+
+		proptest! {
+			#[test]
+			fn prop_url_roundtrip(s in any::<String>()) {
+				prop_assert_eq!(url_decode(&url_encode(&s)).unwrap(), s);
+			}
+
+			#[test]
+			fn prop_form_roundtrip(s in any::<String>()) {
+				prop_assert_eq!(form_decode(&form_encode(&s)).unwrap(), s);
+			}
+
+			#[test]
+			fn prop_encoded_is_unreserved(s in any::<String>()) {
+				let encoded = url_encode(&s);
+				prop_assert!(encoded.bytes().all(|b| b.is_ascii_alphanumeric() || b"-_.~%".contains(&b)));
+			}
+
+			#[test]
+			fn prop_query_roundtrip(pairs in proptest::collection::vec((any::<String>(), any::<String>()), 0..8)) {
+				let q = pairs.iter().fold(Query::new(), |q, (k, v)| q.param(k, v));
+				let parsed = Query::parse(&q.to_string()).unwrap();
+				prop_assert_eq!(parsed.pairs(), pairs.as_slice());
+			}
+
+			#[test]
+			fn prop_decode_never_panics(s in any::<String>()) {
+				let _ = url_decode(&s);
+				let _ = form_decode(&s);
+			}
+		}
+	}
 }
 
 //test
@@ -370,6 +696,7 @@ mod tests {
 				email: self.user_email.clone(),
 				name: self.full_name.clone(),
 				picture: None,
+				email_verified: false,
 			}
 		}
 	}
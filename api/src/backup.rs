@@ -0,0 +1,217 @@
+use crate::error::Error;
+use crate::storage::{self, Precondition, Query, Storage, Write};
+use futures::TryStreamExt;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::io::BufRead;
+use std::time::Duration;
+
+// バックアップの JSON Lines の1行、1つのドキュメント
+// collection はサブコレクションなら "page/{id}/count" のようなパス
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Line {
+	pub collection: String,
+	pub id: String,
+	pub data: Value,
+}
+
+// collections のドキュメントをすべて、1行に1つずつ output に書き出す、書き出した数を返す
+// ページごとに読みながら書き出すので、コレクションが大きくてもメモリに溜めない
+// 版 (更新時刻) は書き出さない、読み込んだドキュメントには新しい版が付く
+pub async fn export(
+	db: &impl Storage,
+	collections: &[&str],
+	output: &mut impl std::io::Write,
+) -> Result<usize, Error> {
+	let mut count = 0;
+	for collection in collections {
+		let documents = storage::stream(db, collection.to_string(), Query::default());
+		let mut documents = std::pin::pin!(documents);
+		while let Some(document) = documents.try_next().await? {
+			let line = Line {
+				collection: collection.to_string(),
+				id: document.id,
+				data: document.data,
+			};
+			serde_json::to_writer(&mut *output, &line)?;
+			output.write_all(b"\n")?;
+			count += 1;
+		}
+	}
+	output.flush()?;
+	Ok(count)
+}
+
+// import の設定
+#[derive(Debug, Clone, Default)]
+pub struct Import {
+	// 既にあるドキュメントは上書きせずに飛ばす、false なら上書きする
+	pub skip_existing: bool,
+	// ドキュメントIDの付け替え、コレクションのパスの中のIDとドキュメントの中の同じ文字列の値も付け替える
+	// 同じデータを何度も読み込んでテスト用のデータを増やすときなどに fresh_ids で作る
+	pub remap: HashMap<String, String>,
+	// 1件書き込むごとに待つ時間、本番の Firestore に流し込むときに書き込みが集中しないようにする
+	pub interval: Duration,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct ImportReport {
+	pub written: usize,
+	// skip_existing で飛ばした数
+	pub skipped: usize,
+	// 読み込んだ後に一意なフィールドの値が重なっていたドキュメント、コレクション.フィールド、値、ドキュメントのID
+	pub duplicates: Vec<(String, Value, Vec<String>)>,
+}
+
+// export で書き出した JSON Lines を読み込んで書き込む
+// 途中の行で失敗したらそこで止まり、それより前の行は書き込まれたまま残る
+// 一意制約の索引は書かない、Api::import が読み込んだ後に作り直す
+pub async fn import(
+	db: &impl Storage,
+	input: impl BufRead,
+	options: &Import,
+) -> Result<ImportReport, Error> {
+	let mut report = ImportReport::default();
+	let write = Write {
+		precondition: if options.skip_existing {
+			Precondition::Missing
+		} else {
+			Precondition::None
+		},
+		mask: None,
+	};
+	for (number, line) in input.lines().enumerate() {
+		let line = line?;
+		if line.trim().is_empty() {
+			continue;
+		}
+		let mut line: Line = serde_json::from_str(&line)
+			.map_err(|e| Error::Invalid(format!("line {}: {e}", number + 1)))?;
+		if !options.remap.is_empty() {
+			line = remap(line, &options.remap);
+		}
+		match db
+			.write(&line.collection, &line.id, &line.data, &write)
+			.await
+		{
+			Ok(_) => report.written += 1,
+			Err(Error::AlreadyExists(_)) if options.skip_existing => report.skipped += 1,
+			Err(e) => return Err(e),
+		}
+		if !options.interval.is_zero() {
+			tokio::time::sleep(options.interval).await;
+		}
+	}
+	Ok(report)
+}
+
+// input の、サブコレクションでないドキュメントのIDすべてに新しいIDを割り当てる
+// サブコレクションのドキュメントは親のIDが付け替わるので、自分のIDはそのままでよい
+pub fn fresh_ids(input: impl BufRead) -> Result<HashMap<String, String>, Error> {
+	let mut remap = HashMap::new();
+	for line in input.lines() {
+		let line = line?;
+		if line.trim().is_empty() {
+			continue;
+		}
+		let line: Line = serde_json::from_str(&line)?;
+		if line.collection.contains('/') {
+			continue;
+		}
+		remap
+			.entry(line.id)
+			.or_insert_with(|| uuid::Uuid::now_v7().to_string());
+	}
+	Ok(remap)
+}
+
+fn remap(line: Line, table: &HashMap<String, String>) -> Line {
+	let id = |s: &str| table.get(s).cloned().unwrap_or_else(|| s.to_string());
+	Line {
+		collection: line
+			.collection
+			.split('/')
+			.map(id)
+			.collect::<Vec<_>>()
+			.join("/"),
+		id: id(&line.id),
+		data: remap_value(line.data, table),
+	}
+}
+
+fn remap_value(value: Value, table: &HashMap<String, String>) -> Value {
+	match value {
+		Value::String(s) => Value::String(table.get(&s).cloned().unwrap_or(s)),
+		Value::Array(a) => a.into_iter().map(|v| remap_value(v, table)).collect(),
+		Value::Object(o) => o
+			.into_iter()
+			.map(|(k, v)| (k, remap_value(v, table)))
+			.collect(),
+		v => v,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::storage::Memory;
+	use serde_json::json;
+
+	#[tokio::test]
+	async fn test_export_import() {
+		let db = Memory::new();
+		let line = |collection: &str, id: &str, data| {
+			serde_json::to_string(&Line {
+				collection: collection.to_string(),
+				id: id.to_string(),
+				data,
+			})
+			.unwrap()
+		};
+		let seed = [
+			line("user", "u1", json!({"id": "u1", "name": "A"})),
+			line("page", "p1", json!({"id": "p1", "id_root": "p1"})),
+			line("page/p1/count", "2026-01-01_0", json!({"view": 3})),
+		]
+		.join("\n");
+		let report = import(&db, seed.as_bytes(), &Import::default())
+			.await
+			.unwrap();
+		assert_eq!(report.written, 3);
+		let mut output = vec![];
+		let count = export(&db, &["user", "page", "page/p1/count"], &mut output)
+			.await
+			.unwrap();
+		assert_eq!(count, 3);
+		assert_eq!(String::from_utf8(output).unwrap().trim(), seed);
+		// 既にあるものは飛ばす
+		let changed = seed.replace("\"A\"", "\"B\"");
+		let options = Import {
+			skip_existing: true,
+			..Default::default()
+		};
+		let report = import(&db, changed.as_bytes(), &options).await.unwrap();
+		assert_eq!((report.written, report.skipped), (0, 3));
+		assert_eq!(
+			db.get("user", "u1").await.unwrap().unwrap().data["name"],
+			"A"
+		);
+		// 新しいIDで読み込むと別のドキュメントになり、中の参照も付け替わる
+		let options = Import {
+			remap: fresh_ids(seed.as_bytes()).unwrap(),
+			..Default::default()
+		};
+		import(&db, seed.as_bytes(), &options).await.unwrap();
+		let p2 = &options.remap["p1"];
+		let page = db.get("page", p2).await.unwrap().unwrap();
+		assert_eq!(page.data, json!({"id": p2, "id_root": p2}));
+		let path = format!("page/{p2}/count");
+		assert!(db.get(&path, "2026-01-01_0").await.unwrap().is_some());
+		assert!(
+			import(&db, "{".as_bytes(), &Import::default())
+				.await
+				.is_err()
+		);
+	}
+}
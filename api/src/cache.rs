@@ -0,0 +1,352 @@
+use crate::error::Error;
+use crate::metrics;
+use crate::storage::{Document, Filter, Query, Storage, Transaction, Version, Watch, Write};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// 読み出しの結果をプロセスの中に覚えておき、同じ読み出しでは保存先に問い合わせない Storage
+// ttl を指定したコレクションだけを覚え、それ以外はそのまま inner に渡す
+// このプロセスからの書き込みはすぐに反映されるが、他のプロセスからの書き込みは ttl の間は見えないことがある
+// 古い読み出しを元にしたトランザクションは commit で競合し、そのとき覚えていたものを捨てるので、やり直せば新しい値を読む
+#[derive(Clone)]
+pub struct Cached<S> {
+	inner: S,
+	ttl: Arc<HashMap<String, Duration>>,
+	lru: Arc<Mutex<Lru>>,
+}
+
+impl<S: Storage> Cached<S> {
+	// capacity はすべてのコレクションを合わせて覚えておく読み出しの数
+	pub fn new(inner: S, capacity: usize) -> Self {
+		Self {
+			inner,
+			ttl: Default::default(),
+			lru: Arc::new(Mutex::new(Lru::new(capacity))),
+		}
+	}
+	// collection の読み出しを ttl の間覚えておく
+	pub fn ttl(mut self, collection: &str, ttl: Duration) -> Self {
+		Arc::make_mut(&mut self.ttl).insert(collection.to_string(), ttl);
+		self
+	}
+	// コレクションごとの当たりと外れの数
+	pub fn stats(&self) -> BTreeMap<String, Stats> {
+		self.lru
+			.lock()
+			.map(|lru| lru.stats.clone())
+			.unwrap_or_default()
+	}
+	fn lookup(&self, key: &Key) -> Result<(Option<Entry>, u64), Error> {
+		let mut lru = self.lru.lock()?;
+		let entry = lru.get(key);
+		let result = if entry.is_some() { "hit" } else { "miss" };
+		metrics::CACHE_LOOKUPS.inc(&[key.collection(), result]);
+		Ok((entry, lru.generation(key.collection())))
+	}
+	fn store(&self, key: Key, entry: Entry, ttl: Duration, generation: u64) -> Result<(), Error> {
+		self.lru.lock()?.insert(key, entry, ttl, generation);
+		Ok(())
+	}
+	fn invalidate(&self, collection: &str) -> Result<(), Error> {
+		self.lru.lock()?.invalidate(collection);
+		Ok(())
+	}
+}
+
+impl<S: Storage> Storage for Cached<S> {
+	async fn get(&self, collection: &str, document_id: &str) -> Result<Option<Document>, Error> {
+		let Some(&ttl) = self.ttl.get(collection) else {
+			return self.inner.get(collection, document_id).await;
+		};
+		let key = Key::Get(collection.to_string(), document_id.to_string());
+		let generation = match self.lookup(&key)? {
+			(Some(Entry::Get(document)), _) => return Ok(document),
+			(_, generation) => generation,
+		};
+		let document = self.inner.get(collection, document_id).await?;
+		self.store(key, Entry::Get(document.clone()), ttl, generation)?;
+		Ok(document)
+	}
+	async fn write(
+		&self,
+		collection: &str,
+		document_id: &str,
+		data: &Value,
+		write: &Write,
+	) -> Result<Version, Error> {
+		let result = self.inner.write(collection, document_id, data, write).await;
+		// 前提条件で失敗したなら覚えている版が古いので、失敗しても捨てる
+		self.invalidate(collection)?;
+		result
+	}
+	async fn query(&self, collection: &str, query: &Query) -> Result<Vec<Document>, Error> {
+		let Some(&ttl) = self.ttl.get(collection) else {
+			return self.inner.query(collection, query).await;
+		};
+		// limit を省いたクエリと既定の limit を書いたクエリは同じものとして扱う
+		let normalized = Query {
+			limit: Some(query.limit()),
+			..query.clone()
+		};
+		let key = Key::Query(collection.to_string(), format!("{normalized:?}"));
+		let generation = match self.lookup(&key)? {
+			(Some(Entry::Query(documents)), _) => return Ok(documents),
+			(_, generation) => generation,
+		};
+		let documents = self.inner.query(collection, query).await?;
+		self.store(key, Entry::Query(documents.clone()), ttl, generation)?;
+		Ok(documents)
+	}
+	// 親ごとのコレクションへの書き込みで捨てられないので覚えない
+	async fn query_group(
+		&self,
+		collection_id: &str,
+		query: &Query,
+	) -> Result<Vec<Document>, Error> {
+		self.inner.query_group(collection_id, query).await
+	}
+	// 集計は覚えずに毎回数える
+	async fn count(&self, collection: &str, filter: Option<&Filter>) -> Result<u64, Error> {
+		self.inner.count(collection, filter).await
+	}
+	async fn sum(
+		&self,
+		collection: &str,
+		filter: Option<&Filter>,
+		field: &str,
+	) -> Result<f64, Error> {
+		self.inner.sum(collection, filter, field).await
+	}
+	async fn delete(&self, collection: &str, document_id: &str) -> Result<(), Error> {
+		let result = self.inner.delete(collection, document_id).await;
+		self.invalidate(collection)?;
+		result
+	}
+	async fn increment(
+		&self,
+		collection: &str,
+		document_id: &str,
+		field: &str,
+		by: i64,
+	) -> Result<(), Error> {
+		let result = self
+			.inner
+			.increment(collection, document_id, field, by)
+			.await;
+		self.invalidate(collection)?;
+		result
+	}
+	async fn commit(&self, transaction: &Transaction) -> Result<(), Error> {
+		let result = self.inner.commit(transaction).await;
+		// 競合したなら読み出しのどれかが古かったので、読み書きしたコレクションはすべて捨てる
+		for collection in transaction.collections() {
+			self.invalidate(collection)?;
+		}
+		result
+	}
+	async fn watch(
+		&self,
+		collection: &str,
+		filter: Option<Filter>,
+		after: Option<Version>,
+	) -> Result<Watch, Error> {
+		self.inner.watch(collection, filter, after).await
+	}
+}
+
+// コレクションごとの読み出しの当たりと外れの数
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Stats {
+	pub hits: u64,
+	pub misses: u64,
+}
+
+impl Stats {
+	pub fn hit_rate(&self) -> f64 {
+		match self.hits + self.misses {
+			0 => 0.0,
+			total => self.hits as f64 / total as f64,
+		}
+	}
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Key {
+	// コレクションとドキュメントID
+	Get(String, String),
+	// コレクションとクエリを文字列にしたもの
+	Query(String, String),
+}
+
+impl Key {
+	fn collection(&self) -> &str {
+		match self {
+			Self::Get(c, _) | Self::Query(c, _) => c,
+		}
+	}
+}
+
+#[derive(Debug, Clone)]
+enum Entry {
+	Get(Option<Document>),
+	Query(Vec<Document>),
+}
+
+struct Slot {
+	entry: Entry,
+	expires: Instant,
+	// 最後に使ったときの tick
+	used: u64,
+}
+
+// いっぱいになったら最も長く使われていないものから捨てる
+struct Lru {
+	capacity: usize,
+	slots: HashMap<Key, Slot>,
+	// used の順に並べた Key
+	order: BTreeMap<u64, Key>,
+	tick: u64,
+	// コレクションごとに、捨てるたびに増やす
+	// 読み出しの途中で書き込まれたら、読み出した値は古いかもしれないので覚えない
+	generations: HashMap<String, u64>,
+	stats: BTreeMap<String, Stats>,
+}
+
+impl Lru {
+	fn new(capacity: usize) -> Self {
+		Self {
+			capacity,
+			slots: Default::default(),
+			order: Default::default(),
+			tick: 0,
+			generations: Default::default(),
+			stats: Default::default(),
+		}
+	}
+	fn get(&mut self, key: &Key) -> Option<Entry> {
+		let now = Instant::now();
+		let entry = match self.slots.get_mut(key) {
+			Some(slot) if slot.expires > now => {
+				self.order.remove(&slot.used);
+				self.tick += 1;
+				slot.used = self.tick;
+				self.order.insert(self.tick, key.clone());
+				Some(slot.entry.clone())
+			}
+			Some(_) => {
+				self.remove(key);
+				None
+			}
+			None => None,
+		};
+		let stats = self.stats.entry(key.collection().to_string()).or_default();
+		match entry {
+			Some(_) => stats.hits += 1,
+			None => stats.misses += 1,
+		}
+		entry
+	}
+	fn generation(&self, collection: &str) -> u64 {
+		self.generations
+			.get(collection)
+			.copied()
+			.unwrap_or_default()
+	}
+	fn insert(&mut self, key: Key, entry: Entry, ttl: Duration, generation: u64) {
+		if self.capacity == 0 || self.generation(key.collection()) != generation {
+			return;
+		}
+		self.remove(&key);
+		while self.slots.len() >= self.capacity {
+			let Some((_, oldest)) = self.order.pop_first() else {
+				break;
+			};
+			self.slots.remove(&oldest);
+		}
+		self.tick += 1;
+		self.order.insert(self.tick, key.clone());
+		self.slots.insert(
+			key,
+			Slot {
+				entry,
+				expires: Instant::now() + ttl,
+				used: self.tick,
+			},
+		);
+	}
+	fn remove(&mut self, key: &Key) {
+		if let Some(slot) = self.slots.remove(key) {
+			self.order.remove(&slot.used);
+		}
+	}
+	fn invalidate(&mut self, collection: &str) {
+		*self.generations.entry(collection.to_string()).or_default() += 1;
+		self.slots.retain(|k, _| k.collection() != collection);
+		self.order.retain(|_, k| k.collection() != collection);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::storage::Memory;
+	use serde_json::json;
+
+	#[tokio::test]
+	async fn test_cached() {
+		let memory = Memory::new();
+		let db = Cached::new(memory.clone(), 2).ttl("page", Duration::from_secs(60));
+		for (collection, id) in [("page", "a"), ("page", "b"), ("user", "a")] {
+			db.write(collection, id, &json!({"n": 1}), &Write::default())
+				.await
+				.unwrap();
+		}
+		let n = async |collection: &str, id: &str| {
+			db.get(collection, id).await.unwrap().unwrap().data["n"].clone()
+		};
+		assert_eq!(n("page", "a").await, 1);
+		assert_eq!(n("user", "a").await, 1);
+		// 他のプロセスからの書き込みは覚えている間は見えない、覚えないコレクションはすぐ見える
+		for collection in ["page", "user"] {
+			memory
+				.write(collection, "a", &json!({"n": 2}), &Write::default())
+				.await
+				.unwrap();
+		}
+		assert_eq!(n("page", "a").await, 1);
+		assert_eq!(n("user", "a").await, 2);
+		let query = Query::default();
+		assert_eq!(db.query("page", &query).await.unwrap().len(), 2);
+		// 自分の書き込みで捨てる
+		db.delete("page", "b").await.unwrap();
+		assert_eq!(n("page", "a").await, 2);
+		assert_eq!(db.query("page", &query).await.unwrap().len(), 1);
+		assert_eq!(db.stats()["page"], Stats { hits: 1, misses: 4 });
+		assert!(!db.stats().contains_key("user"));
+		// いっぱいになったら最も長く使われていないものから捨てる
+		assert_eq!(n("page", "a").await, 2);
+		db.get("page", "c").await.unwrap();
+		assert_eq!(db.stats()["page"].hits, 2);
+		assert_eq!(db.query("page", &query).await.unwrap().len(), 1);
+		assert_eq!(db.stats()["page"].misses, 6);
+	}
+
+	#[tokio::test]
+	async fn test_cached_expires() {
+		let memory = Memory::new();
+		let db = Cached::new(memory.clone(), 16).ttl("page", Duration::ZERO);
+		db.write("page", "a", &json!({"n": 1}), &Write::default())
+			.await
+			.unwrap();
+		db.get("page", "a").await.unwrap();
+		memory
+			.write("page", "a", &json!({"n": 2}), &Write::default())
+			.await
+			.unwrap();
+		let document = db.get("page", "a").await.unwrap().unwrap();
+		assert_eq!(document.data, json!({"n": 2}));
+		assert_eq!(db.stats()["page"].hit_rate(), 0.0);
+	}
+}
@@ -1,99 +1,1046 @@
-use firestore::{
-	FirestoreQueryCursor, FirestoreQueryDirection, FirestoreQueryFilter, FirestoreQueryOrder,
-	FirestoreValue, select_filter_builder::FirestoreQueryFilterBuilder,
+use crate::error::Error;
+use crate::migration::{self, Migration};
+use crate::query::{Field, Select};
+use crate::storage::{
+	self, Change, Cursor, Document, Precondition, Query, Storage, Version, Write,
 };
-pub type FilterBuilder = FirestoreQueryFilterBuilder;
-pub fn none_filter(q: FilterBuilder) -> Option<FirestoreQueryFilter> {
-	None
-}
+use crate::unique;
+// #[derive(Collection)] で下の Collection を実装する、使える属性は collection_derive を参照
+pub use collection_derive::Collection;
+use futures::{Stream, StreamExt};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::marker::PhantomData;
 pub trait Collection: for<'a> serde::Deserialize<'a> + serde::Serialize + Sync + Send {
 	fn collection_name() -> &'static str;
 	fn document_id(&self) -> String;
-	async fn get(db: &firestore::FirestoreDb, document_id: &str) -> Result<Self, String> {
-		let v: Option<Self> = db
-			.fluent()
-			.select()
-			.by_id_in(Self::collection_name())
-			.obj()
-			.one(document_id)
+	// 保存されている古い形のドキュメントを今の構造体の形にする書き換え、migration::Migration を参照
+	// フィールドを足したり名前を変えたりしたときは、既存の要素は変えずに末尾に足す
+	fn migrations() -> &'static [Migration] {
+		&[]
+	}
+	// 同じ値のドキュメントが2つあってはいけないフィールド、空文字列と null は除く
+	// 書き込むときに unique::COLLECTION の索引も同じトランザクションで書き換え、重複すれば AlreadyExists
+	fn unique_fields() -> &'static [&'static str] {
+		&[]
+	}
+	// サブコレクションのドキュメントなら親を返す、push などの書き込みはこの親の下に書く
+	fn parent(&self) -> Parent {
+		Parent::root()
+	}
+	async fn get(db: &impl Storage, document_id: &str) -> Result<Versioned<Self>, Error> {
+		Self::get_in(db, &Parent::root(), document_id).await
+	}
+	// parent の下のサブコレクションから読む
+	async fn get_in(
+		db: &impl Storage,
+		parent: &Parent,
+		document_id: &str,
+	) -> Result<Versioned<Self>, Error> {
+		let collection = parent.path::<Self>();
+		match db.get(&collection, document_id).await? {
+			Some(v) => Versioned::from_document(v),
+			None => Err(Error::NotFound(format!("{collection}/{document_id}"))),
+		}
+	}
+	// 新規作成、既にあれば AlreadyExists
+	async fn push(&self, db: &impl Storage) -> Result<Version, Error> {
+		self.write(db, Precondition::Missing, None).await
+	}
+	// 既存ドキュメントの置き換え、無ければ NotFound
+	async fn update(&self, db: &impl Storage) -> Result<Version, Error> {
+		self.write(db, Precondition::Exists, None).await
+	}
+	// get で読んだ版から変わっていない場合だけ置き換える、変わっていれば Conflict
+	async fn update_if(&self, db: &impl Storage, version: &Version) -> Result<Version, Error> {
+		self.write(db, Precondition::Version(version.clone()), None)
 			.await
-			.map_err(|v| v.to_string())?;
-		match v {
-			Some(v) => Ok(v),
-			None => Err("not found".to_string()),
-		}
-	}
-	async fn push(&self, db: &firestore::FirestoreDb) -> Result<(), String> {
-		db.fluent()
-			.insert()
-			.into(Self::collection_name())
-			.document_id(&self.document_id())
-			.object(self)
-			.execute()
+	}
+	// 作成または置き換え
+	async fn upsert(&self, db: &impl Storage) -> Result<Version, Error> {
+		self.write(db, Precondition::None, None).await
+	}
+	// fields に挙げたフィールドだけを self の値で書き換える、無ければ NotFound
+	// 他のフィールドは読み書きしないので、別のリクエストによる同時の変更を上書きしない
+	async fn patch(&self, db: &impl Storage, fields: &[&str]) -> Result<Version, Error> {
+		self.write(db, Precondition::Exists, Some(mask(fields)))
 			.await
-			.map_err(|v| v.to_string())
-	}
-	async fn query<'a>(
-		db: &firestore::FirestoreDb,
-		filters: impl Fn(FirestoreQueryFilterBuilder) -> Option<FirestoreQueryFilter>,
-		order: Option<OrderBy>,
-		cursor: Option<FirestoreValue>,
-		limit: Option<u32>,
-	) -> Result<Vec<Self>, String> {
-		let builder: _ = db
-			.fluent()
-			.select()
-			.from(Self::collection_name())
-			.filter(filters);
-		let builder = if let Some(order) = order {
-			//特定のフィールドを基準にクエリを並べ替えると、order-by フィールドが存在するドキュメントのみを返すことができます。
-			//id基準でソートするならuuidの一意性を前提に、衝突を考えなくていい、そしてid基準以外でソートするときにページ境界で抜けがあってもいいことにする
-			builder.order_by([order, OrderBy::Desc("__name__")])
-		} else {
-			builder
-		};
-		let builder = if let Some(cursor) = cursor {
-			builder.start_at(FirestoreQueryCursor::AfterValue([cursor].into()))
-		} else {
-			builder
+	}
+	// patch と同じだが、get で読んだ版から変わっていない場合だけ書き換える
+	async fn patch_if(
+		&self,
+		db: &impl Storage,
+		fields: &[&str],
+		version: &Version,
+	) -> Result<Version, Error> {
+		self.write(
+			db,
+			Precondition::Version(version.clone()),
+			Some(mask(fields)),
+		)
+		.await
+	}
+	async fn write(
+		&self,
+		db: &impl Storage,
+		precondition: Precondition,
+		mask: Option<Vec<String>>,
+	) -> Result<Version, Error> {
+		let data = to_document(self, &mask)?;
+		let write = Write { precondition, mask };
+		let collection = self.parent().path::<Self>();
+		let document_id = self.document_id();
+		if !unique::affects(Self::unique_fields(), write.mask.as_deref()) {
+			return db.write(&collection, &document_id, &data, &write).await;
+		}
+		let (c, id) = (collection.clone(), document_id.clone());
+		transaction(db, async move |tx| {
+			tx.write_unique(Self::unique_fields(), &c, &id, data.clone(), write.clone())
+				.await
+		})
+		.await?;
+		// commit は版を返さないので読み直す、その間に他から書き換えられていれば新しい方の版になる
+		match db.get(&collection, &document_id).await? {
+			Some(document) => Ok(document.version),
+			None => Err(Error::NotFound(format!("{collection}/{document_id}"))),
+		}
+	}
+	fn select() -> Select<Self> {
+		Select::new()
+	}
+	// cursor には前のページの next_cursor を渡す、select の条件と並び順は前のページと同じにする
+	async fn query(
+		db: &impl Storage,
+		select: &Select<Self>,
+		cursor: Option<&str>,
+	) -> Result<Page<Versioned<Self>>, Error> {
+		Self::query_in(db, &Parent::root(), select, cursor).await
+	}
+	// parent の下のサブコレクションに対するクエリ
+	async fn query_in(
+		db: &impl Storage,
+		parent: &Parent,
+		select: &Select<Self>,
+		cursor: Option<&str>,
+	) -> Result<Page<Versioned<Self>>, Error> {
+		page(db, &parent.path::<Self>(), false, select, cursor).await
+	}
+	// 親に関わらず、この名前のサブコレクションすべてに対するクエリ (コレクショングループ)
+	// Firestore では select の条件に使うフィールドにコレクショングループ用のインデックスが要る
+	async fn query_group(
+		db: &impl Storage,
+		select: &Select<Self>,
+		cursor: Option<&str>,
+	) -> Result<Page<Versioned<Self>>, Error> {
+		page(db, Self::collection_name(), true, select, cursor).await
+	}
+	// select の条件に合うものをすべて、並び順のとおりに1つずつ返す
+	// select の limit を1ページの件数として読み、受け取る側がページを使い切ってから次のページを読む
+	// 読み出しに失敗したらそのエラーを返して終わる
+	fn stream<'a>(
+		db: &'a impl Storage,
+		select: &'a Select<Self>,
+	) -> impl Stream<Item = Result<Versioned<Self>, Error>> + Send + 'a {
+		storage::stream(db, Parent::root().path::<Self>(), select.query().clone())
+			.map(|document| document.and_then(Versioned::from_document))
+	}
+	fn stream_in<'a>(
+		db: &'a impl Storage,
+		parent: &'a Parent,
+		select: &'a Select<Self>,
+	) -> impl Stream<Item = Result<Versioned<Self>, Error>> + Send + 'a {
+		storage::stream(db, parent.path::<Self>(), select.query().clone())
+			.map(|document| document.and_then(Versioned::from_document))
+	}
+	// select の条件に合うドキュメントの数、ドキュメントは読まない、select の並び順と limit は使わない
+	async fn count(db: &impl Storage, select: &Select<Self>) -> Result<u64, Error> {
+		Self::count_in(db, &Parent::root(), select).await
+	}
+	async fn count_in(
+		db: &impl Storage,
+		parent: &Parent,
+		select: &Select<Self>,
+	) -> Result<u64, Error> {
+		let filter = select.query().filter.as_ref();
+		db.count(&parent.path::<Self>(), filter).await
+	}
+	// select の条件に合うドキュメントの field の合計、数値でない値は足さない
+	async fn sum<T>(
+		db: &impl Storage,
+		select: &Select<Self>,
+		field: Field<Self, T>,
+	) -> Result<f64, Error> {
+		Self::sum_in(db, &Parent::root(), select, field).await
+	}
+	async fn sum_in<T>(
+		db: &impl Storage,
+		parent: &Parent,
+		select: &Select<Self>,
+		field: Field<Self, T>,
+	) -> Result<f64, Error> {
+		let filter = select.query().filter.as_ref();
+		db.sum(&parent.path::<Self>(), filter, field.name()).await
+	}
+	async fn pop(db: &impl Storage, document_id: &str) -> Result<(), Error> {
+		Self::pop_in(db, &Parent::root(), document_id).await
+	}
+	// parent の下のサブコレクションから消す、さらに下のサブコレクションは消えずに残る
+	async fn pop_in(db: &impl Storage, parent: &Parent, document_id: &str) -> Result<(), Error> {
+		let collection = parent.path::<Self>();
+		if Self::unique_fields().is_empty() {
+			return db.delete(&collection, document_id).await;
+		}
+		let id = document_id.to_string();
+		transaction(db, async move |tx| {
+			tx.delete_unique(Self::unique_fields(), &collection, &id)
+				.await
+		})
+		.await
+	}
+	// select の条件に合うドキュメントの変更を待ち受ける、並び順と件数は使わない
+	// after に前に受け取った Event の版を渡すと、その続きから受け取れる
+	// 削除はドキュメントの中身が分からないので、watch の間に条件に合わなかったものと id の条件に合わないもの以外は流れてくる
+	async fn watch(
+		db: &impl Storage,
+		select: &Select<Self>,
+		after: Option<Version>,
+	) -> Result<Watch<Self>, Error> {
+		Self::watch_in(db, &Parent::root(), select, after).await
+	}
+	// parent の下のサブコレクションの変更を待ち受ける、他の親の下の同じ名前のコレクションの変更は来ない
+	async fn watch_in(
+		db: &impl Storage,
+		parent: &Parent,
+		select: &Select<Self>,
+		after: Option<Version>,
+	) -> Result<Watch<Self>, Error> {
+		let filter = select.query().filter.clone();
+		Ok(Watch {
+			inner: db.watch(&parent.path::<Self>(), filter, after).await?,
+			marker: PhantomData,
+		})
+	}
+}
+
+// Collection::watch で受け取る変更
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event<C> {
+	// 作成または更新された
+	Put(Versioned<C>),
+	// 削除された、ドキュメントIDと版
+	Delete(String, Version),
+	// 取りこぼしがあった、query で読み直してから続ける
+	Reset,
+}
+
+impl<C> Event<C> {
+	// 再開するときに Collection::watch の after に渡す版、Reset には無い
+	pub fn version(&self) -> Option<&Version> {
+		match self {
+			Self::Put(v) => Some(&v.version),
+			Self::Delete(_, v) => Some(v),
+			Self::Reset => None,
+		}
+	}
+}
+
+pub struct Watch<C> {
+	inner: storage::Watch,
+	marker: PhantomData<fn() -> C>,
+}
+
+impl<C: Collection> Watch<C> {
+	// 次の変更を待つ、保存先が無くなったら None
+	pub async fn next(&mut self) -> Option<Result<Event<C>, Error>> {
+		Some(match self.inner.next().await? {
+			Change::Put(d) => Versioned::from_document(d).map(Event::Put),
+			Change::Delete(id, version) => Ok(Event::Delete(id, version)),
+			Change::Reset => Ok(Event::Reset),
+		})
+	}
+}
+
+// クエリの結果の1ページ
+// next_cursor を次の query に渡すと続きが返る、None なら最後のページ
+#[derive(Debug, Clone, PartialEq)]
+pub struct Page<T> {
+	pub items: Vec<T>,
+	pub next_cursor: Option<String>,
+}
+
+impl<T> Page<T> {
+	pub fn map<U>(self, f: impl FnMut(T) -> U) -> Page<U> {
+		Page {
+			items: self.items.into_iter().map(f).collect(),
+			next_cursor: self.next_cursor,
+		}
+	}
+}
+
+// collection のクエリの1ページ、group ならコレクショングループに対するクエリ
+async fn page<C: Collection>(
+	db: &impl Storage,
+	collection: &str,
+	group: bool,
+	select: &Select<C>,
+	cursor: Option<&str>,
+) -> Result<Page<Versioned<C>>, Error> {
+	// 同じ名前のコレクションへの普通のクエリとカーソルを取り違えないようにする
+	let key = if group {
+		format!("*/{collection}")
+	} else {
+		collection.to_string()
+	};
+	let mut query = select.query().clone();
+	if let Some(token) = cursor {
+		query.cursor = Some(Cursor::from_token(token, &key, &query)?);
+	}
+	let limit = query.limit() as usize;
+	// 続きがあるかを知るために1件多く読む
+	let extended = Query {
+		limit: Some(limit as u32 + 1),
+		..query.clone()
+	};
+	let mut documents = if group {
+		db.query_group(collection, &extended).await?
+	} else {
+		db.query(collection, &extended).await?
+	};
+	let next_cursor = if documents.len() > limit {
+		documents.truncate(limit);
+		documents
+			.last()
+			.map(|d| Cursor::of(d, &query.order).token(&key, &query))
+	} else {
+		None
+	};
+	Ok(Page {
+		items: documents
+			.into_iter()
+			.map(Versioned::from_document)
+			.collect::<Result<_, _>>()?,
+		next_cursor,
+	})
+}
+
+// サブコレクションの親ドキュメントへのパス
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Parent(String);
+
+impl Parent {
+	// 親の無いコレクション
+	pub fn root() -> Self {
+		Self::default()
+	}
+	// P のドキュメント document_id の下
+	pub fn of<P: Collection>(document_id: &str) -> Self {
+		Self::root().child::<P>(document_id)
+	}
+	// この親の下にある P のドキュメント document_id の下、何段も入れ子になったサブコレクションに使う
+	pub fn child<P: Collection>(&self, document_id: &str) -> Self {
+		Self(format!("{}{}/{document_id}/", self.0, P::collection_name()))
+	}
+	// この親の下の C のコレクションのパス
+	pub fn path<C: Collection>(&self) -> String {
+		self.collection(C::collection_name())
+	}
+	// この親の下の、Collection を実装していないコレクションのパス
+	pub fn collection(&self, name: &str) -> String {
+		format!("{}{name}", self.0)
+	}
+}
+
+fn mask(fields: &[&str]) -> Vec<String> {
+	fields.iter().map(|f| f.to_string()).collect()
+}
+
+fn to_document<C: Collection>(value: &C, mask: &Option<Vec<String>>) -> Result<Value, Error> {
+	let mut data = serde_json::to_value(value)?;
+	if mask.is_none() {
+		migration::stamp::<C>(&mut data);
+	}
+	Ok(data)
+}
+
+// 読み出したドキュメントとその版
+// 版は update_if や patch_if に渡すと、読んでから書くまでの間に他で書き換えられていないことを確かめられる
+#[derive(Debug, Clone, PartialEq)]
+pub struct Versioned<T> {
+	pub value: T,
+	pub version: Version,
+}
+
+impl<C: Collection> Versioned<C> {
+	fn from_document(mut document: Document) -> Result<Self, Error> {
+		migration::upgrade::<C>(&mut document.data)?;
+		Ok(Self {
+			value: serde_json::from_value(document.data)?,
+			version: document.version,
+		})
+	}
+}
+
+impl<T> Versioned<T> {
+	pub fn into_inner(self) -> T {
+		self.value
+	}
+}
+
+impl<T> std::ops::Deref for Versioned<T> {
+	type Target = T;
+	fn deref(&self) -> &T {
+		&self.value
+	}
+}
+
+// f をトランザクションの中で実行して commit する
+// f の中で読んだドキュメントが commit までに他から書き換えられていた場合は、f を最初からやり直す
+// f は何度か呼ばれることがあるので、f の外に副作用を残さないようにする
+// ハンドラの中で使うときは async move で値を f に移す、参照を捕まえると返す Future が Send にならない
+pub async fn transaction<S: Storage, R>(
+	db: &S,
+	mut f: impl AsyncFnMut(&mut Transaction<S>) -> Result<R, Error>,
+) -> Result<R, Error> {
+	let mut attempt = 0;
+	loop {
+		let mut tx = Transaction {
+			db: db.clone(),
+			inner: Default::default(),
+			seen: Default::default(),
 		};
-		let builder = if let Some(limit) = limit {
-			builder.limit(limit)
-		} else {
-			// https://note.com/etet_etet/n/n4ed0d6f59416
-			// collectionに対してlimitかけずに get() してしまうことで、コレクション内のドキュメント（開発環境だと数千レコード）がすべて read されてしまっていたのです。結果は配列の先頭のみ返すので、1件だけで良かったのに、毎回毎回ユーザーがアクセスするたびに数千件を引っ張り出して、最初の1件だけ返していたことが判明しました。
-			builder.limit(100)
+		let result = match f(&mut tx).await {
+			Ok(r) => db.commit(&tx.inner).await.map(|_| r),
+			Err(e) => Err(e),
 		};
-		let output: Vec<Self> = builder.obj().query().await.map_err(|v| v.to_string())?;
-		Ok(output)
-	}
-	async fn pop(db: &firestore::FirestoreDb, document_id: &str) -> Result<(), String> {
-		db.fluent()
-			.delete()
-			.from(Self::collection_name())
-			.document_id(document_id)
-			.execute()
-			.await
-			.map_err(|v| v.to_string())
+		match result {
+			Err(Error::Conflict(_)) if attempt + 1 < TRANSACTION_ATTEMPTS => {
+				attempt += 1;
+				tokio::time::sleep(std::time::Duration::from_millis(10 << attempt)).await;
+			}
+			result => return result,
+		}
+	}
+}
+
+// 競合したトランザクションをやり直す回数の上限、待ち時間は 20ms から倍々に増える
+const TRANSACTION_ATTEMPTS: u32 = 5;
+
+// transaction() の中で Collection を読み書きする
+// 書き込みは commit まで反映されないので、読み出しはすべて書き込みより前に行う
+pub struct Transaction<S> {
+	db: S,
+	inner: storage::Transaction,
+	// get で読んだ一意なフィールドのあるドキュメント、書き換えるときに古い値の索引を消すのに使う
+	seen: HashMap<(String, String), Value>,
+}
+
+impl<S: Storage> Transaction<S> {
+	pub async fn get<C: Collection>(&mut self, document_id: &str) -> Result<Versioned<C>, Error> {
+		self.get_in(&Parent::root(), document_id).await
+	}
+	// parent の下のサブコレクションから読む
+	pub async fn get_in<C: Collection>(
+		&mut self,
+		parent: &Parent,
+		document_id: &str,
+	) -> Result<Versioned<C>, Error> {
+		let collection = parent.path::<C>();
+		let document = self.inner.get(&self.db, &collection, document_id).await?;
+		if let Some(v) = &document
+			&& !C::unique_fields().is_empty()
+		{
+			let key = (collection.clone(), document_id.to_string());
+			self.seen.insert(key, v.data.clone());
+		}
+		match document {
+			Some(v) => Versioned::from_document(v),
+			None => Err(Error::NotFound(format!("{collection}/{document_id}"))),
+		}
+	}
+	pub async fn query<C: Collection>(
+		&mut self,
+		select: &Select<C>,
+	) -> Result<Vec<Versioned<C>>, Error> {
+		self.query_in(&Parent::root(), select).await
+	}
+	// parent の下のサブコレクションに対するクエリ
+	pub async fn query_in<C: Collection>(
+		&mut self,
+		parent: &Parent,
+		select: &Select<C>,
+	) -> Result<Vec<Versioned<C>>, Error> {
+		self.inner
+			.query(&self.db, &parent.path::<C>(), select.query())
+			.await?
+			.into_iter()
+			.map(Versioned::from_document)
+			.collect()
+	}
+	pub fn push(&mut self, value: &impl Collection) -> Result<(), Error> {
+		self.write(value, Precondition::Missing, None)
+	}
+	pub fn update(&mut self, value: &impl Collection) -> Result<(), Error> {
+		self.write(value, Precondition::Exists, None)
+	}
+	pub fn upsert(&mut self, value: &impl Collection) -> Result<(), Error> {
+		self.write(value, Precondition::None, None)
+	}
+	pub fn patch(&mut self, value: &impl Collection, fields: &[&str]) -> Result<(), Error> {
+		self.write(value, Precondition::Exists, Some(mask(fields)))
+	}
+	// get で読んでいないドキュメントの一意なフィールドの索引は残る、unique::repair で消える
+	pub fn pop<C: Collection>(&mut self, document_id: &str) {
+		self.pop_in::<C>(&Parent::root(), document_id)
+	}
+	// parent の下のサブコレクションから消す
+	pub fn pop_in<C: Collection>(&mut self, parent: &Parent, document_id: &str) {
+		let collection = parent.path::<C>();
+		let key = (collection.clone(), document_id.to_string());
+		if let Some(old) = self.seen.get(&key) {
+			let changes = unique::changes(&collection, C::unique_fields(), Some(old), None, None);
+			unique::apply(
+				&mut self.inner,
+				&collection,
+				document_id,
+				&changes,
+				Precondition::None,
+			);
+		}
+		self.inner.delete(&collection, document_id);
+	}
+	// 一意なフィールドを書き換えるときは古い値の索引を消すために、先に get で読んでおく
+	// 読み出しより後には索引を読めないので、消し損ねた古い索引と重なっても AlreadyExists になる
+	fn write<C: Collection>(
+		&mut self,
+		value: &C,
+		precondition: Precondition,
+		mask: Option<Vec<String>>,
+	) -> Result<(), Error> {
+		let data = to_document(value, &mask)?;
+		let collection = value.parent().path::<C>();
+		let document_id = value.document_id();
+		if unique::affects(C::unique_fields(), mask.as_deref()) {
+			let key = (collection.clone(), document_id.clone());
+			let old = match (&precondition, self.seen.get(&key)) {
+				(Precondition::Missing, _) => None,
+				(_, Some(old)) => Some(old),
+				(_, None) => {
+					return Err(Error::Internal(format!(
+						"{collection}/{document_id} has unique fields, get it in the transaction before writing"
+					)));
+				}
+			};
+			let changes = unique::changes(
+				&collection,
+				C::unique_fields(),
+				old,
+				Some(&data),
+				mask.as_deref(),
+			);
+			unique::apply(
+				&mut self.inner,
+				&collection,
+				&document_id,
+				&changes,
+				Precondition::Missing,
+			);
+		}
+		let write = Write { precondition, mask };
+		self.inner.write(&collection, &document_id, data, write);
+		Ok(())
+	}
+	// 今のドキュメントと新しい値の索引を読んでから、索引と一緒に書く
+	async fn write_unique(
+		&mut self,
+		fields: &[&'static str],
+		collection: &str,
+		document_id: &str,
+		data: Value,
+		write: Write,
+	) -> Result<(), Error> {
+		let old = self.inner.get(&self.db, collection, document_id).await?;
+		let changes = unique::changes(
+			collection,
+			fields,
+			old.as_ref().map(|d| &d.data),
+			Some(&data),
+			write.mask.as_deref(),
+		);
+		for (field, index) in &changes.added {
+			unique::check(
+				&mut self.inner,
+				&self.db,
+				collection,
+				document_id,
+				field,
+				index,
+			)
+			.await?;
+		}
+		unique::apply(
+			&mut self.inner,
+			collection,
+			document_id,
+			&changes,
+			Precondition::None,
+		);
+		self.inner.write(collection, document_id, data, write);
+		Ok(())
+	}
+	async fn delete_unique(
+		&mut self,
+		fields: &[&'static str],
+		collection: &str,
+		document_id: &str,
+	) -> Result<(), Error> {
+		let old = self.inner.get(&self.db, collection, document_id).await?;
+		let changes = unique::changes(
+			collection,
+			fields,
+			old.as_ref().map(|d| &d.data),
+			None,
+			None,
+		);
+		unique::apply(
+			&mut self.inner,
+			collection,
+			document_id,
+			&changes,
+			Precondition::None,
+		);
+		self.inner.delete(collection, document_id);
+		Ok(())
 	}
 }
 
-pub enum OrderBy {
-	Asc(&'static str),
-	Desc(&'static str),
+// 読み出しを伴わない書き込みをまとめて送る、一括の追加や削除に使う
+// Firestore の1回の commit は 500 件までなので、LIMIT 件を超えないように分けて送る
+// 一意なフィールドの索引も同じ commit に入れるので、1つのドキュメントで数件を使うことがある
+// 分けた単位ごとに、すべて反映されるか何も反映されないかのどちらかになる
+#[derive(Default)]
+pub struct Batch {
+	transactions: Vec<storage::Transaction>,
+	// 加えたドキュメントの数
+	len: usize,
+	// 最後の transactions に入れた書き込みの数
+	size: usize,
 }
 
-impl From<OrderBy> for FirestoreQueryOrder {
-	fn from(value: OrderBy) -> Self {
-		match value {
-			OrderBy::Asc(field) => Self {
-				field_name: field.to_string(),
-				direction: FirestoreQueryDirection::Ascending,
-			},
-			OrderBy::Desc(field) => Self {
-				field_name: field.to_string(),
-				direction: FirestoreQueryDirection::Descending,
-			},
+impl Batch {
+	pub const LIMIT: usize = 500;
+	pub fn new() -> Self {
+		Self::default()
+	}
+	pub fn len(&self) -> usize {
+		self.len
+	}
+	pub fn is_empty(&self) -> bool {
+		self.len == 0
+	}
+	pub fn push(&mut self, value: &impl Collection) -> Result<&mut Self, Error> {
+		self.write(value, Precondition::Missing)
+	}
+	pub fn upsert(&mut self, value: &impl Collection) -> Result<&mut Self, Error> {
+		self.write(value, Precondition::None)
+	}
+	// 一意なフィールドの索引は残る、unique::repair で消える
+	pub fn pop<C: Collection>(&mut self, document_id: &str) -> &mut Self {
+		self.pop_in::<C>(&Parent::root(), document_id)
+	}
+	// parent の下のサブコレクションから消す
+	pub fn pop_in<C: Collection>(&mut self, parent: &Parent, document_id: &str) -> &mut Self {
+		self.next(1).delete(&parent.path::<C>(), document_id);
+		self
+	}
+	// 読まずに書くので、一意なフィールドのあるものは新規作成 (push) だけを受け付ける
+	fn write<C: Collection>(
+		&mut self,
+		value: &C,
+		precondition: Precondition,
+	) -> Result<&mut Self, Error> {
+		let data = to_document(value, &None)?;
+		let collection = value.parent().path::<C>();
+		let document_id = value.document_id();
+		let changes = unique::changes(&collection, C::unique_fields(), None, Some(&data), None);
+		if !changes.added.is_empty() && precondition != Precondition::Missing {
+			return Err(Error::Internal(format!(
+				"{collection}/{document_id} has unique fields, it cannot be overwritten in a batch"
+			)));
+		}
+		let write = Write {
+			precondition,
+			mask: None,
+		};
+		// 本体と索引を同じ commit に入れる
+		let next = self.next(1 + changes.added.len());
+		unique::apply(
+			next,
+			&collection,
+			&document_id,
+			&changes,
+			Precondition::Missing,
+		);
+		next.write(&collection, &document_id, data, write);
+		Ok(self)
+	}
+	// 次の writes 件の書き込みを入れる先、入りきらなければ新しくする
+	fn next(&mut self, writes: usize) -> &mut storage::Transaction {
+		if self.transactions.is_empty() || self.size + writes > Self::LIMIT {
+			self.transactions.push(Default::default());
+			self.size = 0;
+		}
+		self.size += writes;
+		self.len += 1;
+		self.transactions.last_mut().unwrap()
+	}
+	pub async fn commit(self, db: &impl Storage) -> Result<(), Error> {
+		for transaction in &self.transactions {
+			db.commit(transaction).await?;
 		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::storage::Memory;
+	use serde::{Deserialize, Serialize};
+
+	#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, Collection)]
+	struct Counter {
+		id: String,
+		#[collection(index)]
+		n: i32,
+	}
+
+	#[tokio::test]
+	async fn test_transaction_retries_on_conflict() {
+		let db = Memory::new();
+		let counter = Counter {
+			id: "a".to_string(),
+			n: 0,
+		};
+		counter.push(&db).await.unwrap();
+		let mut attempts = 0;
+		let n = transaction(&db, async |tx| {
+			attempts += 1;
+			let c = tx.get::<Counter>("a").await?;
+			if attempts == 1 {
+				// 読んだ後に他から書き換えられたので、この回の commit は競合してやり直しになる
+				Counter {
+					n: 10,
+					..counter.clone()
+				}
+				.update(&db)
+				.await?;
+			}
+			let c = Counter {
+				n: c.n + 1,
+				..c.into_inner()
+			};
+			tx.update(&c)?;
+			Ok(c.n)
+		})
+		.await
+		.unwrap();
+		assert_eq!(attempts, 2);
+		assert_eq!(n, 11);
+		assert_eq!(Counter::get(&db, "a").await.unwrap().n, 11);
+	}
+
+	#[tokio::test]
+	async fn test_batch() {
+		let db = Memory::new();
+		let mut batch = Batch::new();
+		for i in 0..Batch::LIMIT + 1 {
+			batch
+				.push(&Counter {
+					id: format!("{i:04}"),
+					n: 0,
+				})
+				.unwrap();
+		}
+		batch.commit(&db).await.unwrap();
+		let all = Counter::query(&db, &Counter::select().limit(1000), None)
+			.await
+			.unwrap();
+		assert_eq!(all.items.len(), Batch::LIMIT + 1);
+		let mut batch = Batch::new();
+		batch.pop::<Counter>("0000").pop::<Counter>("0001");
+		batch
+			.upsert(&Counter {
+				id: "0002".to_string(),
+				n: 2,
+			})
+			.unwrap();
+		batch.commit(&db).await.unwrap();
+		assert!(matches!(
+			Counter::get(&db, "0000").await,
+			Err(Error::NotFound(_))
+		));
+		assert_eq!(Counter::get(&db, "0002").await.unwrap().n, 2);
+	}
+
+	#[tokio::test]
+	async fn test_query_pages() {
+		let db = Memory::new();
+		let mut batch = Batch::new();
+		for i in 0..7 {
+			batch
+				.push(&Counter {
+					id: format!("{i}"),
+					n: i % 2,
+				})
+				.unwrap();
+		}
+		batch.commit(&db).await.unwrap();
+		// 同じ n のものがページ境界をまたいでも、抜けも重複もなく最後まで辿れる
+		let mut ids = vec![];
+		let mut cursor = None;
+		loop {
+			let select = Counter::select().order_by(Counter::N.desc()).limit(2);
+			let page = Counter::query(&db, &select, cursor.as_deref())
+				.await
+				.unwrap();
+			ids.extend(page.items.iter().map(|c| c.id.clone()));
+			match page.next_cursor {
+				Some(next) => cursor = Some(next),
+				None => break,
+			}
+		}
+		assert_eq!(ids, ["5", "3", "1", "6", "4", "2", "0"]);
+		// 別の並び順のクエリに渡したカーソルは弾かれる
+		let select = Counter::select().order_by(Counter::N.desc()).limit(2);
+		let page = Counter::query(&db, &select, None).await.unwrap();
+		let other = Counter::select().order_by(Counter::N.asc()).limit(2);
+		assert!(matches!(
+			Counter::query(&db, &other, page.next_cursor.as_deref()).await,
+			Err(Error::Invalid(_))
+		));
+	}
+
+	#[tokio::test]
+	async fn test_stream() {
+		use futures::TryStreamExt;
+		let db = Memory::new();
+		let mut batch = Batch::new();
+		for i in 0..7 {
+			batch
+				.push(&Counter {
+					id: format!("{i}"),
+					n: i % 2,
+				})
+				.unwrap();
+		}
+		batch.commit(&db).await.unwrap();
+		// ページの件数で割り切れてもそうでなくても最後まで返る
+		for limit in [2, 7] {
+			let select = Counter::select().order_by(Counter::N.desc()).limit(limit);
+			let counters: Vec<_> = Counter::stream(&db, &select).try_collect().await.unwrap();
+			let ids: Vec<_> = counters.iter().map(|c| c.id.as_str()).collect();
+			assert_eq!(ids, ["5", "3", "1", "6", "4", "2", "0"]);
+		}
+	}
+
+	#[tokio::test]
+	async fn test_count_sum() {
+		let db = Memory::new();
+		let mut batch = Batch::new();
+		for i in 0..5 {
+			batch
+				.push(&Counter {
+					id: format!("{i}"),
+					n: i,
+				})
+				.unwrap();
+		}
+		batch.commit(&db).await.unwrap();
+		// limit に関わらずすべて数える
+		let select = Counter::select().limit(1);
+		assert_eq!(Counter::count(&db, &select).await.unwrap(), 5);
+		assert_eq!(Counter::sum(&db, &select, Counter::N).await.unwrap(), 10.0);
+		let select = Counter::select().filter(Counter::N.ge(3));
+		assert_eq!(Counter::count(&db, &select).await.unwrap(), 2);
+		assert_eq!(Counter::sum(&db, &select, Counter::N).await.unwrap(), 7.0);
+		let comment = Comment {
+			counter: "0".to_string(),
+			id: "x".to_string(),
+			n: 1,
+		};
+		comment.push(&db).await.unwrap();
+		let parent = Parent::of::<Counter>("0");
+		let select = Comment::select();
+		assert_eq!(Comment::count_in(&db, &parent, &select).await.unwrap(), 1);
+		let parent = Parent::of::<Counter>("1");
+		assert_eq!(Comment::count_in(&db, &parent, &select).await.unwrap(), 0);
+	}
+
+	// counter/{counter}/comment/{id}
+	#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, Collection)]
+	struct Comment {
+		#[collection(parent = Counter)]
+		counter: String,
+		id: String,
+		#[collection(index)]
+		n: i32,
+	}
+
+	#[tokio::test]
+	async fn test_watch_in() {
+		let db = Memory::new();
+		let a = Parent::of::<Counter>("a");
+		let mut watch = Comment::watch_in(&db, &a, &Comment::select(), None)
+			.await
+			.unwrap();
+		// 他の親の下の変更は来ない
+		for counter in ["b", "a"] {
+			let comment = Comment {
+				counter: counter.to_string(),
+				id: "x".to_string(),
+				n: 1,
+			};
+			comment.push(&db).await.unwrap();
+		}
+		match watch.next().await.unwrap().unwrap() {
+			Event::Put(c) => assert_eq!(c.counter, "a"),
+			e => panic!("unexpected {e:?}"),
+		}
+		Comment::pop_in(&db, &a, "x").await.unwrap();
+		assert!(matches!(
+			watch.next().await.unwrap().unwrap(),
+			Event::Delete(id, _) if id == "x"
+		));
+	}
+
+	#[tokio::test]
+	async fn test_sub_collection() {
+		let db = Memory::new();
+		for (counter, id, n) in [("a", "x", 1), ("a", "y", 2), ("b", "x", 3)] {
+			let comment = Comment {
+				counter: counter.to_string(),
+				id: id.to_string(),
+				n,
+			};
+			comment.push(&db).await.unwrap();
+		}
+		// 親が違えば同じIDでも別のドキュメント
+		let a = Parent::of::<Counter>("a");
+		assert_eq!(Comment::get_in(&db, &a, "x").await.unwrap().n, 1);
+		assert!(Comment::get(&db, "x").await.is_err());
+		let select = Comment::select().order_by(Comment::N.asc());
+		let page = Comment::query_in(&db, &a, &select, None).await.unwrap();
+		assert_eq!(page.map(|c| c.n).items, [1, 2]);
+		// コレクショングループはすべての親を辿る
+		let mut ns = vec![];
+		let mut cursor = None;
+		loop {
+			let select = Comment::select().filter(Comment::N.ge(2)).limit(1);
+			let page = Comment::query_group(&db, &select, cursor.as_deref())
+				.await
+				.unwrap();
+			ns.extend(page.items.iter().map(|c| c.n));
+			match page.next_cursor {
+				Some(next) => cursor = Some(next),
+				None => break,
+			}
+		}
+		assert_eq!(ns, [2, 3]);
+		Comment::pop_in(&db, &a, "x").await.unwrap();
+		let page = Comment::query_group(&db, &Comment::select(), None)
+			.await
+			.unwrap();
+		assert_eq!(page.map(|c| c.n).items, [2, 3]);
+		// トランザクションとバッチでも親の下を読み書きする
+		let b = Parent::of::<Counter>("b");
+		let n = transaction(&db, async |tx| {
+			let x = tx.get_in::<Comment>(&b, "x").await?;
+			let all = tx.query_in(&a, &Comment::select()).await?;
+			tx.pop_in::<Comment>(&a, "y");
+			Ok(x.n + all.len() as i32)
+		})
+		.await
+		.unwrap();
+		assert_eq!(n, 4);
+		assert!(Comment::get_in(&db, &a, "y").await.is_err());
+		let mut batch = Batch::new();
+		batch.pop_in::<Comment>(&b, "x");
+		batch.commit(&db).await.unwrap();
+		assert!(Comment::get_in(&db, &b, "x").await.is_err());
+	}
+
+	// FIRESTORE_EMULATOR_HOST にエミュレータがあるときだけ、Memory と同じことが Firestore でもできるか確かめる
+	#[tokio::test]
+	async fn test_emulator() {
+		use futures::TryStreamExt;
+		let Some(db) = crate::storage::Firestore::for_test().await else {
+			return;
+		};
+		let counter = Counter {
+			id: "a".to_string(),
+			n: 1,
+		};
+		let version = counter.push(&db).await.unwrap();
+		assert!(matches!(
+			counter.push(&db).await,
+			Err(Error::AlreadyExists(_))
+		));
+		assert_eq!(Counter::get(&db, "a").await.unwrap().into_inner(), counter);
+		// 読んだ版が古くなっていれば書けない
+		let counter = Counter { n: 2, ..counter };
+		let stale = version;
+		let version = counter.update_if(&db, &stale).await.unwrap();
+		assert!(matches!(
+			counter.update_if(&db, &stale).await,
+			Err(Error::Conflict(_))
+		));
+		Counter { n: 3, ..counter }
+			.patch_if(&db, &["n"], &version)
+			.await
+			.unwrap();
+		let n = transaction(&db, async |tx| {
+			let c = tx.get::<Counter>("a").await?;
+			let c = Counter {
+				n: c.n + 1,
+				..c.into_inner()
+			};
+			tx.update(&c)?;
+			Ok(c.n)
+		})
+		.await
+		.unwrap();
+		assert_eq!(n, 4);
+		let mut batch = Batch::new();
+		for i in 0..5 {
+			batch
+				.push(&Counter {
+					id: format!("b{i}"),
+					n: i % 2,
+				})
+				.unwrap();
+		}
+		batch.commit(&db).await.unwrap();
+		let select = Counter::select().order_by(Counter::N.desc()).limit(2);
+		let counters: Vec<_> = Counter::stream(&db, &select).try_collect().await.unwrap();
+		let ids: Vec<_> = counters.iter().map(|c| c.id.as_str()).collect();
+		assert_eq!(ids, ["a", "b3", "b1", "b4", "b2", "b0"]);
+		let select = Counter::select().filter(Counter::N.eq(1));
+		assert_eq!(Counter::count(&db, &select).await.unwrap(), 2);
+		assert_eq!(Counter::sum(&db, &select, Counter::N).await.unwrap(), 2.0);
+		let comment = Comment {
+			counter: "a".to_string(),
+			id: "x".to_string(),
+			n: 1,
+		};
+		comment.push(&db).await.unwrap();
+		let page = Comment::query_group(&db, &Comment::select(), None)
+			.await
+			.unwrap();
+		assert_eq!(page.map(|c| c.into_inner()).items, [comment]);
+		Counter::pop(&db, "a").await.unwrap();
+		assert!(matches!(
+			Counter::get(&db, "a").await,
+			Err(Error::NotFound(_))
+		));
 	}
 }
@@ -0,0 +1,445 @@
+use crate::error::Error;
+use serde::Deserialize;
+
+// 起動時の設定
+// 既定値 → 設定ファイル (CONFIG、省略したら config.json) → 環境変数 の順に上書きする
+// 読み終えたら validate で足りない値や壊れた値をまとめて調べ、あれば起動しない
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+	// PORT
+	pub port: u16,
+	// STORAGE、memory なら Firestore にも Google にも繋がずメモリ上で動かす
+	pub storage: Storage,
+	// SEED、memory のときに export で書き出したデータを読み込んでから動く
+	pub seed: Option<String>,
+	pub firestore: Firestore,
+	pub google: Google,
+	pub email: Email,
+	pub log: Log,
+	pub metrics: Metrics,
+	pub cursor: Cursor,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Storage {
+	#[default]
+	Firestore,
+	Memory,
+}
+
+impl std::str::FromStr for Storage {
+	type Err = String;
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"firestore" => Ok(Self::Firestore),
+			"memory" => Ok(Self::Memory),
+			_ => Err("expected firestore or memory".to_string()),
+		}
+	}
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Firestore {
+	// FIRESTORE_PROJECT、GCP のプロジェクト
+	pub project: String,
+	// FIRESTORE_DATABASE
+	pub database: String,
+	// FIRESTORE_KEY_FILE、サービスアカウントの鍵
+	pub key_file: String,
+	// FIRESTORE_EMULATOR_HOST、localhost:8081 のように書くとエミュレータに繋ぎ、鍵も Google の認証情報も読まない
+	pub emulator_host: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Google {
+	// GOOGLE_OAUTH_FILE、Google Cloud Console からダウンロードした OAuth クライアントの JSON
+	pub oauth_file: String,
+}
+
+// authapi_email で送る確認メール
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Email {
+	// EMAIL_SERVICE_NAME、本文に書くサービスの名前
+	pub service_name: String,
+	// EMAIL_FROM、送信元
+	pub from: String,
+	// EMAIL_SUPPORT、本文に書く問い合わせ先
+	pub support: String,
+}
+
+impl Email {
+	// メールを送れる設定か、/readyz で確かめる
+	pub fn check(&self) -> Result<(), Error> {
+		if self.service_name.is_empty() || self.support.is_empty() {
+			return Err(Error::Invalid("email is not configured".to_string()));
+		}
+		if !self.from.contains('@') {
+			return Err(Error::Invalid(format!(
+				"email.from is not an email address: {:?}",
+				self.from
+			)));
+		}
+		Ok(())
+	}
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Log {
+	// LOG_FORMAT
+	pub format: LogFormat,
+	// RUST_LOG、"info" や "api=debug,info" のような tracing_subscriber::EnvFilter の書式
+	pub filter: String,
+}
+
+// ページングのカーソルの署名
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Cursor {
+	// CURSOR_SECRET_FILE、32バイト以上の鍵を書いたファイル、Firestore の本番では必須
+	// 無ければプロセスごとに鍵を作るので、再起動や別のインスタンスでは前のカーソルが使えない
+	pub secret_file: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Metrics {
+	// METRICS_TOKEN、あれば /metrics に Authorization: Bearer {token} を求める、無ければ誰でも読める
+	pub token: Option<String>,
+}
+
+// text は人が読む形、json は CloudWatch などで検索できるように1行に1つの JSON
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+	#[default]
+	Text,
+	Json,
+}
+
+impl std::str::FromStr for LogFormat {
+	type Err = String;
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"text" => Ok(Self::Text),
+			"json" => Ok(Self::Json),
+			_ => Err("expected text or json".to_string()),
+		}
+	}
+}
+
+impl Default for Log {
+	fn default() -> Self {
+		Self {
+			format: LogFormat::default(),
+			filter: "info".to_string(),
+		}
+	}
+}
+
+impl Default for Config {
+	fn default() -> Self {
+		Self {
+			port: 8080,
+			storage: Storage::default(),
+			seed: None,
+			firestore: Firestore::default(),
+			google: Google::default(),
+			email: Email::default(),
+			log: Log::default(),
+			metrics: Metrics::default(),
+			cursor: Cursor::default(),
+		}
+	}
+}
+
+impl Config {
+	// 設定ファイルと環境変数から読んで確かめる
+	pub fn load() -> Result<Self, Error> {
+		let config = Self::load_unchecked()?;
+		config.validate()?;
+		Ok(config)
+	}
+	// 読むだけで確かめない、print-routes のように秘密も保存先も使わないコマンド用
+	pub fn load_unchecked() -> Result<Self, Error> {
+		let path = std::env::var("CONFIG").ok();
+		Self::read(path.as_deref(), |name| std::env::var(name).ok())
+	}
+	// path が None なら config.json を、無ければ既定値を使う、path を指定したのに無ければエラー
+	// env は環境変数の値を返す、テストで差し替えられるように渡す
+	fn read(path: Option<&str>, env: impl Fn(&str) -> Option<String>) -> Result<Self, Error> {
+		let file = path.unwrap_or("config.json");
+		let mut config = match std::fs::read_to_string(file) {
+			Ok(text) => {
+				serde_json::from_str(&text).map_err(|e| Error::Invalid(format!("{file}: {e}")))?
+			}
+			Err(e) if path.is_none() && e.kind() == std::io::ErrorKind::NotFound => Self::default(),
+			Err(e) => return Err(Error::Invalid(format!("{file}: {e}"))),
+		};
+		let mut env = Overrides {
+			env,
+			errors: vec![],
+		};
+		env.parse("PORT", &mut config.port);
+		env.parse("STORAGE", &mut config.storage);
+		env.optional("SEED", &mut config.seed);
+		env.parse("FIRESTORE_PROJECT", &mut config.firestore.project);
+		env.parse("FIRESTORE_DATABASE", &mut config.firestore.database);
+		env.parse("FIRESTORE_KEY_FILE", &mut config.firestore.key_file);
+		env.optional(
+			"FIRESTORE_EMULATOR_HOST",
+			&mut config.firestore.emulator_host,
+		);
+		env.parse("GOOGLE_OAUTH_FILE", &mut config.google.oauth_file);
+		env.parse("EMAIL_SERVICE_NAME", &mut config.email.service_name);
+		env.parse("EMAIL_FROM", &mut config.email.from);
+		env.parse("EMAIL_SUPPORT", &mut config.email.support);
+		env.parse("LOG_FORMAT", &mut config.log.format);
+		env.parse("RUST_LOG", &mut config.log.filter);
+		env.optional("METRICS_TOKEN", &mut config.metrics.token);
+		env.parse("CURSOR_SECRET_FILE", &mut config.cursor.secret_file);
+		if !env.errors.is_empty() {
+			return Err(Error::Invalid(env.errors.join("\n")));
+		}
+		Ok(config)
+	}
+	// 選んだ保存先で使う値がそろっているか、使う前に分かる間違いがないか
+	// 問題はすべてまとめて1つのエラーにする
+	pub fn validate(&self) -> Result<(), Error> {
+		let mut errors = vec![];
+		// Firestore の本番に繋ぐときだけ鍵と Google の認証情報を読む
+		let credentials =
+			self.storage == Storage::Firestore && self.firestore.emulator_host.is_none();
+		let mut required = vec![];
+		let mut files = vec![("seed (SEED)", self.seed.as_deref().unwrap_or_default())];
+		if self.storage == Storage::Firestore {
+			required.extend([
+				(
+					"firestore.project (FIRESTORE_PROJECT)",
+					&self.firestore.project,
+				),
+				(
+					"email.service_name (EMAIL_SERVICE_NAME)",
+					&self.email.service_name,
+				),
+				("email.from (EMAIL_FROM)", &self.email.from),
+				("email.support (EMAIL_SUPPORT)", &self.email.support),
+			]);
+		}
+		if credentials {
+			required.extend([
+				(
+					"firestore.database (FIRESTORE_DATABASE)",
+					&self.firestore.database,
+				),
+				(
+					"firestore.key_file (FIRESTORE_KEY_FILE)",
+					&self.firestore.key_file,
+				),
+				(
+					"google.oauth_file (GOOGLE_OAUTH_FILE)",
+					&self.google.oauth_file,
+				),
+				(
+					"cursor.secret_file (CURSOR_SECRET_FILE)",
+					&self.cursor.secret_file,
+				),
+			]);
+			files.extend([
+				(
+					"firestore.key_file (FIRESTORE_KEY_FILE)",
+					self.firestore.key_file.as_str(),
+				),
+				(
+					"google.oauth_file (GOOGLE_OAUTH_FILE)",
+					self.google.oauth_file.as_str(),
+				),
+			]);
+		}
+		for (name, value) in required {
+			if value.trim().is_empty() {
+				errors.push(format!("{name} is required"));
+			}
+		}
+		for (name, path) in files {
+			if !path.is_empty() && !std::path::Path::new(path).is_file() {
+				errors.push(format!("{name}: {path} does not exist"));
+			}
+		}
+		// 書いてあるものは、使わない設定でも間違いを知らせる
+		for (name, value) in [
+			("email.from (EMAIL_FROM)", &self.email.from),
+			("email.support (EMAIL_SUPPORT)", &self.email.support),
+		] {
+			if !value.is_empty() && !value.contains('@') {
+				errors.push(format!("{name}: {value:?} is not an email address"));
+			}
+		}
+		if let Err(e) = self.cursor_secret() {
+			errors.push(e.message().to_string());
+		}
+		if let Err(e) = tracing_subscriber::EnvFilter::try_new(&self.log.filter) {
+			errors.push(format!("log.filter (RUST_LOG): {e}"));
+		}
+		if errors.is_empty() {
+			Ok(())
+		} else {
+			Err(Error::Invalid(format!(
+				"invalid configuration\n{}",
+				errors.join("\n")
+			)))
+		}
+	}
+	// カーソルの署名の鍵、secret_file を書いていなければ None
+	pub fn cursor_secret(&self) -> Result<Option<Vec<u8>>, Error> {
+		let file = &self.cursor.secret_file;
+		if file.is_empty() {
+			return Ok(None);
+		}
+		let name = "cursor.secret_file (CURSOR_SECRET_FILE)";
+		let secret =
+			std::fs::read(file).map_err(|e| Error::Invalid(format!("{name}: {file}: {e}")))?;
+		let secret = secret.trim_ascii();
+		if secret.len() < 32 {
+			return Err(Error::Invalid(format!(
+				"{name}: {file} should contain at least 32 bytes"
+			)));
+		}
+		Ok(Some(secret.to_vec()))
+	}
+}
+
+// 環境変数で設定を上書きする、読めなかった値は errors にためてまとめて知らせる
+struct Overrides<F> {
+	env: F,
+	errors: Vec<String>,
+}
+
+impl<F: Fn(&str) -> Option<String>> Overrides<F> {
+	fn parse<T: std::str::FromStr<Err: std::fmt::Display>>(&mut self, name: &str, target: &mut T) {
+		if let Some(value) = (self.env)(name) {
+			match value.parse() {
+				Ok(v) => *target = v,
+				Err(e) => self.errors.push(format!("{name}: {value:?}: {e}")),
+			}
+		}
+	}
+	// 空の環境変数は設定ファイルの値を消す
+	fn optional(&mut self, name: &str, target: &mut Option<String>) {
+		if let Some(value) = (self.env)(name) {
+			*target = (!value.is_empty()).then_some(value);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::collections::HashMap;
+
+	fn read(file: &str, env: &[(&str, &str)]) -> Result<Config, Error> {
+		let path = std::env::temp_dir().join(format!("config-{}.json", uuid::Uuid::now_v7()));
+		std::fs::write(&path, file).unwrap();
+		let env: HashMap<String, String> = env
+			.iter()
+			.map(|(k, v)| (k.to_string(), v.to_string()))
+			.collect();
+		let config = Config::read(path.to_str(), |name| env.get(name).cloned());
+		std::fs::remove_file(&path).unwrap();
+		config
+	}
+
+	#[test]
+	fn test_layers() {
+		let file = r#"{"port": 3000, "firestore": {"project": "p", "database": "d"}, "email": {"from": "a@example.com"}}"#;
+		let config = read(
+			file,
+			&[
+				("FIRESTORE_DATABASE", "e"),
+				("EMAIL_SUPPORT", "b@example.com"),
+			],
+		)
+		.unwrap();
+		assert_eq!(config.port, 3000);
+		assert_eq!(config.storage, Storage::Firestore);
+		assert_eq!(config.firestore.project, "p");
+		assert_eq!(config.firestore.database, "e");
+		assert_eq!(config.email.from, "a@example.com");
+		assert_eq!(config.email.support, "b@example.com");
+		let env = [
+			("PORT", "80"),
+			("STORAGE", "memory"),
+			("SEED", ""),
+			("LOG_FORMAT", "json"),
+			("METRICS_TOKEN", "t"),
+		];
+		let config = read(file, &env).unwrap();
+		assert_eq!(
+			(config.port, config.storage, config.seed, config.log.format),
+			(80, Storage::Memory, None, LogFormat::Json)
+		);
+		assert_eq!(config.metrics.token.as_deref(), Some("t"));
+		// 読めない値は環境変数の名前付きで知らせる
+		let e = read(file, &[("PORT", "http"), ("STORAGE", "disk")]).unwrap_err();
+		assert!(e.message().contains("PORT") && e.message().contains("STORAGE"));
+		// 知らないキーは打ち間違いとして弾く
+		assert!(read(r#"{"prot": 3000}"#, &[]).is_err());
+		assert!(Config::read(Some("/nonexistent/config.json"), |_| None).is_err());
+	}
+
+	#[test]
+	fn test_validate() {
+		let config = Config {
+			storage: Storage::Memory,
+			..Default::default()
+		};
+		config.validate().unwrap();
+		let e = Config::default().validate().unwrap_err();
+		for name in [
+			"FIRESTORE_PROJECT",
+			"FIRESTORE_KEY_FILE",
+			"GOOGLE_OAUTH_FILE",
+			"EMAIL_FROM",
+			"CURSOR_SECRET_FILE",
+		] {
+			assert!(e.message().contains(name), "{name} in {e}");
+		}
+		// エミュレータなら鍵も Google の認証情報も要らない
+		let config = Config {
+			firestore: Firestore {
+				project: "p".to_string(),
+				emulator_host: Some("localhost:8081".to_string()),
+				..Default::default()
+			},
+			email: Email {
+				service_name: "s".to_string(),
+				from: "info".to_string(),
+				support: "support@example.com".to_string(),
+			},
+			..Default::default()
+		};
+		let e = config.validate().unwrap_err();
+		assert_eq!(
+			e.message(),
+			"invalid configuration\nemail.from (EMAIL_FROM): \"info\" is not an email address"
+		);
+		// 推測されやすい短い鍵は使わない
+		let path = std::env::temp_dir().join(format!("cursor-{}", uuid::Uuid::now_v7()));
+		let config = Config {
+			cursor: Cursor {
+				secret_file: path.to_string_lossy().into_owned(),
+			},
+			..Default::default()
+		};
+		std::fs::write(&path, "short\n").unwrap();
+		assert!(config.cursor_secret().is_err());
+		std::fs::write(&path, format!("{}\n", "k".repeat(32))).unwrap();
+		assert_eq!(config.cursor_secret().unwrap(), Some(vec![b'k'; 32]));
+		std::fs::remove_file(&path).unwrap();
+	}
+}
@@ -0,0 +1,177 @@
+use crate::collection::{Collection, Parent};
+use crate::error::Error;
+use crate::storage::{self, Cursor, Query, Storage};
+use chrono::{Days, NaiveDate};
+use futures::TryStreamExt;
+use std::marker::PhantomData;
+
+// 書き込みが1つのドキュメントに集中するカウンタ
+// Firestore の1つのドキュメントは1秒に1回程度しか書き換えられないので、P のドキュメントの下のサブコレクションに
+// 日ごとに shards 個のドキュメントを作り、足すたびにどれかを選んで足す
+// P のドキュメントへの書き戻し (rollup) は呼ぶ側が daily で読んで定期的に行う
+// shard のIDは "{日付}_{番号}" なので、IDの順に並べると日付の順になる
+pub struct ShardedCounter<P> {
+	collection: &'static str,
+	shards: u32,
+	marker: PhantomData<fn() -> P>,
+}
+
+impl<P: Collection> ShardedCounter<P> {
+	// collection は P のドキュメントの下に作るサブコレクションの名前
+	pub const fn new(collection: &'static str, shards: u32) -> Self {
+		Self {
+			collection,
+			shards,
+			marker: PhantomData,
+		}
+	}
+	// P のドキュメント document_id の shard を置くサブコレクションのパス
+	pub fn path(&self, document_id: &str) -> String {
+		Parent::of::<P>(document_id).collection(self.collection)
+	}
+	// P のドキュメント document_id の field を、day の分として by 増やす
+	// field は shard のドキュメントのフィールド名で、1つの shard に複数のカウンタを入れられる
+	pub async fn increment(
+		&self,
+		db: &impl Storage,
+		document_id: &str,
+		field: &str,
+		by: i64,
+		day: NaiveDate,
+	) -> Result<(), Error> {
+		// UUIDv7 の下位のビットは乱数
+		let shard = uuid::Uuid::now_v7().as_u128() % self.shards.max(1) as u128;
+		db.increment(
+			&self.path(document_id),
+			&format!("{day}_{shard}"),
+			field,
+			by,
+		)
+		.await
+	}
+	// fields それぞれの、today を [0] として days 日遡った日ごとの合計
+	pub async fn daily(
+		&self,
+		db: &impl Storage,
+		document_id: &str,
+		fields: &[&str],
+		today: NaiveDate,
+		days: usize,
+	) -> Result<Vec<Vec<i64>>, Error> {
+		let mut totals = vec![vec![0; days]; fields.len()];
+		let Some(first) = first_day(today, days) else {
+			return Ok(totals);
+		};
+		let path = self.path(document_id);
+		// first より前の日の shard は "{first}" より前に並ぶので読まない
+		let query = Query {
+			cursor: Some(Cursor {
+				values: vec![],
+				id: first.to_string(),
+			}),
+			..Default::default()
+		};
+		let mut documents = std::pin::pin!(storage::stream(db, path, query));
+		while let Some(document) = documents.try_next().await? {
+			let Some(day) = day_of(&document.id) else {
+				continue;
+			};
+			// 時計のずれで未来の日付になった shard は数えない
+			let Ok(offset) = usize::try_from((today - day).num_days()) else {
+				continue;
+			};
+			if offset >= days {
+				continue;
+			}
+			for (total, field) in totals.iter_mut().zip(fields) {
+				total[offset] += document
+					.data
+					.get(field)
+					.and_then(|v| v.as_i64())
+					.unwrap_or_default();
+			}
+		}
+		Ok(totals)
+	}
+	// daily で読まなくなった、today から days 日より前の shard を消す、消した数を返す
+	pub async fn prune(
+		&self,
+		db: &impl Storage,
+		document_id: &str,
+		today: NaiveDate,
+		days: usize,
+	) -> Result<usize, Error> {
+		let first = first_day(today, days).unwrap_or(today + Days::new(1));
+		let path = self.path(document_id);
+		let mut pruned = 0;
+		// 次のページはカーソルの後ろから読むので、読んだものを消しても飛ばさない
+		let mut documents = std::pin::pin!(storage::stream(db, path.clone(), Query::default()));
+		while let Some(document) = documents.try_next().await? {
+			match day_of(&document.id) {
+				// shard は日付の順に並ぶので、first 以降の日が出てきたら後ろは消さない
+				Some(day) if day >= first => break,
+				_ => {
+					db.delete(&path, &document.id).await?;
+					pruned += 1;
+				}
+			}
+		}
+		Ok(pruned)
+	}
+}
+
+// today から days 日遡った日のうち最も古い日
+fn first_day(today: NaiveDate, days: usize) -> Option<NaiveDate> {
+	today.checked_sub_days(Days::new(days.checked_sub(1)? as u64))
+}
+
+fn day_of(shard: &str) -> Option<NaiveDate> {
+	let (day, _) = shard.split_once('_')?;
+	day.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::storage::Memory;
+	use serde::{Deserialize, Serialize};
+
+	#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, Collection)]
+	struct Page {
+		id: String,
+	}
+
+	#[tokio::test]
+	async fn test_sharded_counter() {
+		let db = Memory::new();
+		let counter = ShardedCounter::<Page>::new("count", 4);
+		let today: NaiveDate = "2026-03-01".parse().unwrap();
+		let yesterday = today.pred_opt().unwrap();
+		let old = today - Days::new(30);
+		for (day, field, n) in [
+			(today, "view", 20),
+			(today, "star", 3),
+			(yesterday, "view", 5),
+			(old, "view", 100),
+		] {
+			for _ in 0..n {
+				counter.increment(&db, "a", field, 1, day).await.unwrap();
+			}
+		}
+		// 別のドキュメントのカウンタは混ざらない
+		counter.increment(&db, "b", "view", 1, today).await.unwrap();
+		let shards = db.query("page/a/count", &Query::default()).await.unwrap();
+		assert!(shards.len() > 3 && shards.len() <= 12);
+		let daily = counter
+			.daily(&db, "a", &["view", "star"], today, 3)
+			.await
+			.unwrap();
+		assert_eq!(daily, [[20, 5, 0], [3, 0, 0]]);
+		let month = counter.daily(&db, "a", &["view"], today, 31).await.unwrap();
+		assert_eq!(month[0].iter().sum::<i64>(), 125);
+		// 30 日より前の shard を消しても直近の合計は変わらない
+		assert!(counter.prune(&db, "a", today, 30).await.unwrap() > 0);
+		let month = counter.daily(&db, "a", &["view"], today, 31).await.unwrap();
+		assert_eq!(month[0].iter().sum::<i64>(), 25);
+	}
+}
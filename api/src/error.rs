@@ -0,0 +1,186 @@
+use firestore::errors::FirestoreError;
+
+// Collection や OAuth が返すエラー、種類ごとに HTTP のステータスへ対応付ける
+// 中身の String は人が読むための説明
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+	// ドキュメントが存在しない 404
+	NotFound(String),
+	// 作成しようとしたドキュメントが既に存在する 409
+	AlreadyExists(String),
+	// 他の書き込みと競合した、読み直してやり直せば成功しうる 409
+	Conflict(String),
+	// 一時的に使えない、時間をおいて再試行すれば成功しうる 503
+	Unavailable(String),
+	// 入力が不正 400
+	Invalid(String),
+	// それ以外のサーバー側の問題 500
+	Internal(String),
+}
+
+impl Error {
+	pub fn message(&self) -> &str {
+		match self {
+			Self::NotFound(m)
+			| Self::AlreadyExists(m)
+			| Self::Conflict(m)
+			| Self::Unavailable(m)
+			| Self::Invalid(m)
+			| Self::Internal(m) => m,
+		}
+	}
+	// サーバー側の問題を今の span に書き残す、4xx は利用者の入力によるものなので書かない
+	pub fn log(&self) {
+		match self {
+			Self::Internal(m) => tracing::error!(error = m.as_str(), "internal error"),
+			Self::Unavailable(m) => tracing::warn!(error = m.as_str(), "unavailable"),
+			_ => {}
+		}
+	}
+	pub fn status(&self) -> axum::http::StatusCode {
+		match self {
+			Self::NotFound(_) => axum::http::StatusCode::NOT_FOUND,
+			Self::AlreadyExists(_) | Self::Conflict(_) => axum::http::StatusCode::CONFLICT,
+			Self::Unavailable(_) => axum::http::StatusCode::SERVICE_UNAVAILABLE,
+			Self::Invalid(_) => axum::http::StatusCode::BAD_REQUEST,
+			Self::Internal(_) => axum::http::StatusCode::INTERNAL_SERVER_ERROR,
+		}
+	}
+}
+
+impl std::fmt::Display for Error {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		let kind = match self {
+			Self::NotFound(_) => "not found",
+			Self::AlreadyExists(_) => "already exists",
+			Self::Conflict(_) => "conflict",
+			Self::Unavailable(_) => "unavailable",
+			Self::Invalid(_) => "invalid",
+			Self::Internal(_) => "internal error",
+		};
+		write!(f, "{kind}: {}", self.message())
+	}
+}
+
+impl std::error::Error for Error {}
+
+const ABORTED: &str = "Aborted";
+const FAILED_PRECONDITION: &str = "FailedPrecondition";
+
+impl Error {
+	// 書き込みの失敗、書き込みはインデックスを使わないので FAILED_PRECONDITION は更新時刻などの前提条件の違反
+	pub fn from_write(e: FirestoreError) -> Self {
+		match &e {
+			FirestoreError::DatabaseError(d) if d.public.code == FAILED_PRECONDITION => {
+				Self::Conflict(e.to_string())
+			}
+			_ => e.into(),
+		}
+	}
+}
+
+impl From<FirestoreError> for Error {
+	fn from(e: FirestoreError) -> Self {
+		let message = e.to_string();
+		match e {
+			FirestoreError::DataNotFoundError(_) => Self::NotFound(message),
+			// create で既にドキュメントがあると ALREADY_EXISTS がこれになる
+			FirestoreError::DataConflictError(_) => Self::AlreadyExists(message),
+			FirestoreError::InvalidParametersError(_) | FirestoreError::SerializeError(_) => {
+				Self::Invalid(message)
+			}
+			FirestoreError::NetworkError(_) => Self::Unavailable(message),
+			// 競合は ABORTED で返ってくる、コードは tonic の Code を Debug で書いたもの
+			FirestoreError::DatabaseError(e) if e.public.code == ABORTED => Self::Conflict(message),
+			FirestoreError::DatabaseError(e) if e.retry_possible => Self::Unavailable(message),
+			// FAILED_PRECONDITION は複合インデックスが無いクエリでも返るので、ここでは Internal にする
+			// 書き込みの前提条件の違反は Error::from_write で Conflict にする
+			_ => Self::Internal(message),
+		}
+	}
+}
+
+// 保存されているドキュメントを構造体に戻せない、または構造体をドキュメントにできない
+impl From<serde_json::Error> for Error {
+	fn from(e: serde_json::Error) -> Self {
+		Self::Internal(e.to_string())
+	}
+}
+
+impl From<reqwest::Error> for Error {
+	fn from(e: reqwest::Error) -> Self {
+		if e.is_timeout() || e.is_connect() {
+			Self::Unavailable(e.to_string())
+		} else {
+			Self::Internal(e.to_string())
+		}
+	}
+}
+
+impl From<jsonwebtoken::errors::Error> for Error {
+	fn from(e: jsonwebtoken::errors::Error) -> Self {
+		Self::Invalid(e.to_string())
+	}
+}
+
+impl From<crate::auth::encode::DecodeError> for Error {
+	fn from(e: crate::auth::encode::DecodeError) -> Self {
+		Self::Invalid(e.to_string())
+	}
+}
+
+impl<T> From<std::sync::PoisonError<T>> for Error {
+	fn from(e: std::sync::PoisonError<T>) -> Self {
+		Self::Internal(e.to_string())
+	}
+}
+
+// バックアップのファイルや標準入出力の読み書きに失敗した
+impl From<std::io::Error> for Error {
+	fn from(e: std::io::Error) -> Self {
+		Self::Internal(e.to_string())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use firestore::errors::{FirestoreDatabaseError, FirestoreErrorPublicGenericDetails};
+
+	fn database(code: &str, details: &str, retry_possible: bool) -> FirestoreError {
+		FirestoreError::DatabaseError(FirestoreDatabaseError {
+			public: FirestoreErrorPublicGenericDetails {
+				code: code.to_string(),
+			},
+			details: details.to_string(),
+			retry_possible,
+		})
+	}
+
+	#[test]
+	fn test_from_firestore() {
+		assert!(matches!(
+			Error::from(database("Aborted", "", true)),
+			Error::Conflict(_)
+		));
+		assert!(matches!(
+			Error::from(database("Unavailable", "", true)),
+			Error::Unavailable(_)
+		));
+		// 文言に関わらずコードだけで決める
+		for details in ["The query requires an index", "precondition failed"] {
+			assert!(matches!(
+				Error::from(database("FailedPrecondition", details, false)),
+				Error::Internal(_)
+			));
+			assert!(matches!(
+				Error::from_write(database("FailedPrecondition", details, false)),
+				Error::Conflict(_)
+			));
+		}
+		assert!(matches!(
+			Error::from_write(database("Aborted", "", true)),
+			Error::Conflict(_)
+		));
+	}
+}
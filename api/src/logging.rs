@@ -0,0 +1,128 @@
+use crate::config::{Log, LogFormat};
+use axum::body::Body;
+use axum::http::{HeaderValue, Request};
+use axum::middleware::Next;
+use axum::response::Response;
+use tracing::Instrument;
+use tracing::field::Empty;
+
+// リクエストIDのヘッダ、付いて来たらそれを使い、無ければ作る、どちらもレスポンスに付けて返す
+pub const REQUEST_ID: &str = "x-request-id";
+
+// 値を書き残してよいクエリパラメータ、他の値はトークンや認可コードかもしれないので伏せる
+const VISIBLE: &[&str] = &["limit", "cursor"];
+
+// ログを標準エラーに書き出す、標準出力は export などのコマンドの出力に使う
+// json なら1行に1つの JSON で、今の span のフィールドも一緒に書く
+pub fn init(log: &Log) {
+	let builder = tracing_subscriber::fmt()
+		.with_env_filter(tracing_subscriber::EnvFilter::new(&log.filter))
+		.with_writer(std::io::stderr);
+	match log.format {
+		LogFormat::Text => builder.init(),
+		LogFormat::Json => builder
+			.json()
+			.flatten_event(true)
+			.with_current_span(true)
+			.with_span_list(false)
+			.init(),
+	}
+}
+
+// リクエストごとの span を作り、ハンドラをその中で動かす、終わったら結果を1行書く
+// subject はログインしていれば Api::session が記録する
+// Cookie などのヘッダは書かない、クエリの値は VISIBLE 以外伏せる
+pub async fn trace(request: Request<Body>, next: Next) -> Response {
+	let id = request
+		.headers()
+		.get(REQUEST_ID)
+		.and_then(|v| v.to_str().ok())
+		.filter(|v| is_request_id(v))
+		.map_or_else(|| uuid::Uuid::now_v7().to_string(), str::to_string);
+	let span = tracing::info_span!(
+		"request",
+		id = id.as_str(),
+		method = request.method().as_str(),
+		path = request.uri().path(),
+		query = Empty,
+		status = Empty,
+		latency_ms = Empty,
+		subject = Empty,
+	);
+	if let Some(query) = request.uri().query() {
+		span.record("query", redact(query).as_str());
+	}
+	let started = std::time::Instant::now();
+	let mut response = next.run(request).instrument(span.clone()).await;
+	let status = response.status();
+	span.record("status", status.as_u16());
+	span.record("latency_ms", started.elapsed().as_millis() as u64);
+	span.in_scope(|| {
+		if status.is_server_error() {
+			tracing::error!("request failed");
+		} else {
+			tracing::info!("request finished");
+		}
+	});
+	if let Ok(value) = HeaderValue::from_str(&id) {
+		response.headers_mut().insert(REQUEST_ID, value);
+	}
+	response
+}
+
+// 他のシステムから来たIDをそのままログに書いてよいか
+fn is_request_id(value: &str) -> bool {
+	!value.is_empty()
+		&& value.len() <= 64
+		&& value
+			.bytes()
+			.all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+fn redact(query: &str) -> String {
+	query
+		.split('&')
+		.map(|pair| match pair.split_once('=') {
+			Some((key, _)) if !VISIBLE.contains(&key) => format!("{key}=[redacted]"),
+			_ => pair.to_string(),
+		})
+		.collect::<Vec<_>>()
+		.join("&")
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use tower::ServiceExt;
+
+	#[test]
+	fn test_redact() {
+		assert_eq!(
+			redact("code=4%2F0Ab&state=019a&limit=2&cursor=x.y.z"),
+			"code=[redacted]&state=[redacted]&limit=2&cursor=x.y.z"
+		);
+		assert_eq!(redact("token"), "token");
+	}
+
+	#[tokio::test]
+	async fn test_request_id() {
+		let app = axum::Router::new()
+			.route("/", axum::routing::get(async || "ok"))
+			.layer(axum::middleware::from_fn(trace));
+		let request = |id: Option<&str>| {
+			let mut builder = Request::builder().uri("/?token=secret");
+			if let Some(id) = id {
+				builder = builder.header(REQUEST_ID, id);
+			}
+			builder.body(Body::empty()).unwrap()
+		};
+		let response = app.clone().oneshot(request(None)).await.unwrap();
+		let id = response.headers()[REQUEST_ID].to_str().unwrap();
+		assert!(uuid::Uuid::parse_str(id).is_ok());
+		let response = app.clone().oneshot(request(Some("abc-123"))).await.unwrap();
+		assert_eq!(response.headers()[REQUEST_ID], "abc-123");
+		// ログを汚しかねないIDは使わずに作り直す
+		let response = app.oneshot(request(Some("a\tb"))).await.unwrap();
+		assert_ne!(response.headers()[REQUEST_ID], "a\tb");
+	}
+}
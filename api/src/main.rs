@@ -1,21 +1,266 @@
 mod api;
 mod auth;
+mod backup;
+mod cache;
 mod collection;
+mod config;
+mod counter;
+mod error;
+mod logging;
+mod metrics;
+mod migration;
 #[allow(dead_code, unused_variables)]
 mod out;
+mod query;
+mod search;
+mod storage;
+mod unique;
 #[tokio::main]
 async fn main() {
-	let server = api::Api::new().await.expect("cannot connect database");
-	let port: u16 = std::env::var("PORT")
-		.unwrap_or("8080".to_string())
-		.parse()
-		.expect("PORT should be integer");
-	out::print_axum_router(port);
-	let app = out::axum_router(server).fallback(frontend);
+	let args: Vec<String> = std::env::args().skip(1).collect();
+	let exit = |e: error::Error| -> ! {
+		eprintln!("{e}");
+		std::process::exit(2);
+	};
+	// 保存先にも秘密にも触らないコマンド、本番の設定がそろっていなくても動く
+	match args.first().map(String::as_str) {
+		Some("help" | "--help" | "-h") => {
+			println!("{USAGE}");
+			return;
+		}
+		Some("print-routes") => {
+			let config = config::Config::load_unchecked().unwrap_or_else(|e| exit(e));
+			out::print_axum_router(config.port);
+			return;
+		}
+		Some(command) if !COMMANDS.contains(&command) => {
+			usage(&format!("unknown command: {command}"))
+		}
+		_ => {}
+	}
+	// 設定が足りなければ何に繋ぐ前にも止まる
+	let config = config::Config::load().unwrap_or_else(|e| exit(e));
+	logging::init(&config.log);
+	// カーソルの署名の鍵は validate で確かめてある
+	if let Some(secret) = config.cursor_secret().expect("cannot read cursor secret") {
+		storage::Cursor::init(secret).expect("cannot set cursor secret");
+	}
+	match config.storage {
+		config::Storage::Memory => {
+			let api = api::Api::memory().email(config.email.clone());
+			if let Some(path) = &config.seed {
+				let file = std::fs::File::open(path).expect("cannot open seed");
+				api.import(std::io::BufReader::new(file), &Default::default())
+					.await
+					.expect("cannot import seed");
+			}
+			run(api, &config, &args).await
+		}
+		config::Storage::Firestore => {
+			let api = api::Api::new(&config)
+				.await
+				.expect("cannot connect database");
+			run(api, &config, &args).await
+		}
+	}
+}
+
+const USAGE: &str = "usage: api [command]
+  serve                      start the server (default)
+  print-routes               print the API routes
+  migrate [--dry-run]        rewrite stored documents to the current schema
+  rollup                     recount video views, stars and comments
+  repair [--dry-run]         rebuild unique indexes, exit 1 on duplicates
+  export [collection...]     write documents to stdout as JSON Lines
+  import [--skip-existing] [--fresh-ids] [--interval-ms N]
+                             write JSON Lines from stdin and rebuild unique indexes,
+                             exit 1 on duplicates
+  create-admin EMAIL [NAME]  make the user with EMAIL an admin, creating it if missing
+  revoke-sessions [--all | USER_ID...]
+                             invalidate tokens issued so far
+  send-test-email TO         send a test email with the configured sender";
+
+// 設定を読んでから run で動かすコマンド
+const COMMANDS: &[&str] = &[
+	"serve",
+	"migrate",
+	"rollup",
+	"repair",
+	"export",
+	"import",
+	"create-admin",
+	"revoke-sessions",
+	"send-test-email",
+];
+
+// 引数が無ければサーバーとして動く、コマンドの一覧は USAGE
+async fn run<S: storage::Storage + 'static>(
+	api: api::Api<S>,
+	config: &config::Config,
+	args: &[String],
+) {
+	match args.first().map(String::as_str) {
+		None | Some("serve") => serve(api, config).await,
+		Some("migrate") => {
+			let dry_run = args[1..].iter().any(|a| a == "--dry-run");
+			let reports = api.migrate(dry_run).await.expect("cannot migrate");
+			let mut failed = false;
+			for (collection, report) in reports {
+				println!(
+					"{collection}: scanned {}, {} {}",
+					report.scanned,
+					if dry_run { "to migrate" } else { "migrated" },
+					report.migrated.len()
+				);
+				for (id, e) in &report.failed {
+					println!("{collection}/{id}: {e}");
+					failed = true;
+				}
+			}
+			if failed {
+				std::process::exit(1);
+			}
+		}
+		Some("repair") => {
+			let dry_run = args[1..].iter().any(|a| a == "--dry-run");
+			let reports = api.repair(dry_run).await.expect("cannot repair");
+			let mut duplicated = false;
+			for (collection, report) in reports {
+				println!(
+					"{collection}: scanned {}, {} {} indexes, {} {} indexes",
+					report.scanned,
+					if dry_run { "to create" } else { "created" },
+					report.created,
+					if dry_run { "to remove" } else { "removed" },
+					report.removed
+				);
+				for (field, value, ids) in &report.duplicates {
+					println!("{collection}.{field} = {value}: {}", ids.join(", "));
+					duplicated = true;
+				}
+			}
+			if duplicated {
+				std::process::exit(1);
+			}
+		}
+		Some("rollup") => {
+			let today = chrono::Utc::now().date_naive();
+			let rolled = api.rollup(today).await.expect("cannot roll up counts");
+			println!("rolled up {rolled} videos");
+		}
+		Some("export") => {
+			let collections: Vec<&str> = args[1..].iter().map(String::as_str).collect();
+			let mut output = std::io::BufWriter::new(std::io::stdout().lock());
+			let count = api
+				.export(&collections, &mut output)
+				.await
+				.expect("cannot export");
+			eprintln!("exported {count} documents");
+		}
+		Some("import") => {
+			let mut options = backup::Import::default();
+			let mut fresh_ids = false;
+			let mut rest = args[1..].iter();
+			while let Some(arg) = rest.next() {
+				match arg.as_str() {
+					"--skip-existing" => options.skip_existing = true,
+					"--fresh-ids" => fresh_ids = true,
+					"--interval-ms" => {
+						let ms = rest
+							.next()
+							.and_then(|v| v.parse().ok())
+							.expect("--interval-ms should be integer");
+						options.interval = std::time::Duration::from_millis(ms);
+					}
+					_ => usage(&format!("unknown option: {arg}")),
+				}
+			}
+			// 新しいIDを割り当てるには先にすべての行を読む必要がある
+			let mut input = String::new();
+			std::io::Read::read_to_string(&mut std::io::stdin(), &mut input)
+				.expect("cannot read stdin");
+			if fresh_ids {
+				options.remap = backup::fresh_ids(input.as_bytes()).expect("cannot read ids");
+			}
+			let report = api
+				.import(input.as_bytes(), &options)
+				.await
+				.expect("cannot import");
+			eprintln!("imported {}, skipped {}", report.written, report.skipped);
+			for (field, value, ids) in &report.duplicates {
+				eprintln!("{field} = {value}: {}", ids.join(", "));
+			}
+			if !report.duplicates.is_empty() {
+				std::process::exit(1);
+			}
+		}
+		Some("create-admin") => {
+			let Some(email) = args.get(1) else {
+				usage("create-admin needs EMAIL");
+			};
+			let name = args.get(2).map_or("", String::as_str);
+			let user = api
+				.create_admin(email, name)
+				.await
+				.expect("cannot create admin");
+			println!("{} <{}> is an admin", user.id, user.auth_email);
+		}
+		Some("revoke-sessions") => {
+			let ids: Vec<&str> = args[1..].iter().map(String::as_str).collect();
+			// 引数を忘れてすべての利用者を締め出さないように、すべてなら --all を求める
+			let ids = match ids[..] {
+				[] => usage("revoke-sessions needs --all or USER_ID"),
+				["--all"] => vec![],
+				_ => ids,
+			};
+			api.revoke_sessions(&ids)
+				.await
+				.expect("cannot revoke sessions");
+			if ids.is_empty() {
+				println!("revoked sessions of all users");
+			} else {
+				println!("revoked sessions of {}", ids.join(", "));
+			}
+		}
+		Some("send-test-email") => {
+			let Some(to) = args.get(1) else {
+				usage("send-test-email needs TO");
+			};
+			api.send_test_email(to).await.expect("cannot send email");
+			println!("sent a test email to {to}");
+		}
+		Some(command) => usage(&format!("unknown command: {command}")),
+	}
+}
+
+fn usage(message: &str) -> ! {
+	eprintln!("{message}\n{USAGE}");
+	std::process::exit(2);
+}
+
+async fn serve<S: storage::Storage + 'static>(api: api::Api<S>, config: &config::Config) {
+	let port = config.port;
+	// 検索のインデックスは起動のたびに作り直す、失敗したら少し待ってやり直す
+	let search = api.clone();
+	tokio::spawn(async move {
+		loop {
+			if let Err(e) = search.follow_search().await {
+				tracing::error!(error = %e, "search index stopped");
+			}
+			tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+		}
+	});
+	let app = out::axum_router(api.clone())
+		.merge(api.clone().watch_router())
+		.merge(api.health_router())
+		.merge(metrics::router(config.metrics.token.clone()))
+		.fallback(frontend)
+		.layer(axum::middleware::from_fn(metrics::track))
+		.layer(axum::middleware::from_fn(logging::trace));
 	let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{port}"))
 		.await
 		.unwrap();
-	println!("serve on http://localhost:{port}/");
+	tracing::info!("serve on http://localhost:{port}/");
 	axum::serve(listener, app.clone())
 		.with_graceful_shutdown(async move {
 			tokio::signal::ctrl_c().await.unwrap();
@@ -0,0 +1,458 @@
+use crate::error::Error;
+use crate::storage::{Document, Filter, Query, Storage, Transaction, Version, Watch, Write};
+use axum::body::Body;
+use axum::extract::MatchedPath;
+use axum::http::{HeaderMap, Request, StatusCode, header};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// プロセス全体で1つの集計、/metrics で Prometheus のテキスト形式にして返す
+pub static REGISTRY: Registry = Registry::new();
+
+pub const HTTP_REQUESTS: Counter = Counter {
+	name: "http_requests_total",
+	help: "HTTP requests by route and status",
+	labels: &["method", "route", "status"],
+};
+pub const HTTP_DURATION: Histogram = Histogram {
+	name: "http_request_duration_seconds",
+	help: "HTTP request latency",
+	labels: &["method", "route"],
+};
+pub const STORAGE_OPERATIONS: Counter = Counter {
+	name: "storage_operations_total",
+	help: "Storage operations by collection and result",
+	labels: &["operation", "collection", "result"],
+};
+pub const STORAGE_DURATION: Histogram = Histogram {
+	name: "storage_operation_duration_seconds",
+	help: "Storage operation latency",
+	labels: &["operation", "collection"],
+};
+pub const CACHE_LOOKUPS: Counter = Counter {
+	name: "cache_lookups_total",
+	help: "Cached reads by collection, hit or miss",
+	labels: &["collection", "result"],
+};
+pub const OAUTH_REQUESTS: Counter = Counter {
+	name: "oauth_token_requests_total",
+	help: "OAuth token exchanges by result",
+	labels: &["result"],
+};
+pub const OAUTH_DURATION: Histogram = Histogram {
+	name: "oauth_token_request_duration_seconds",
+	help: "OAuth token exchange latency",
+	labels: &[],
+};
+pub const EMAILS: Counter = Counter {
+	name: "emails_sent_total",
+	help: "Emails sent by result",
+	labels: &["result"],
+};
+pub const EMAIL_DURATION: Histogram = Histogram {
+	name: "email_send_duration_seconds",
+	help: "Email send latency",
+	labels: &[],
+};
+
+// ヒストグラムのバケットの上限 (秒)
+const BUCKETS: &[f64] = &[
+	0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+// ラベルの値は labels と同じ順に渡す、値の組ごとに数える
+// ラベルの値の種類が増えるほど系列が増えるので、ID やパスそのものは渡さない
+pub struct Counter {
+	name: &'static str,
+	help: &'static str,
+	labels: &'static [&'static str],
+}
+
+impl Counter {
+	pub fn inc(&self, values: &[&str]) {
+		REGISTRY.inc(self, values);
+	}
+}
+
+pub struct Histogram {
+	name: &'static str,
+	help: &'static str,
+	labels: &'static [&'static str],
+}
+
+impl Histogram {
+	pub fn observe(&self, values: &[&str], elapsed: Duration) {
+		REGISTRY.observe(self, values, elapsed.as_secs_f64());
+	}
+}
+
+pub struct Registry {
+	families: Mutex<BTreeMap<&'static str, Family>>,
+}
+
+struct Family {
+	help: &'static str,
+	labels: &'static [&'static str],
+	// ラベルの値の組 → 系列
+	series: BTreeMap<Vec<String>, Series>,
+}
+
+enum Series {
+	Counter(u64),
+	// バケットごとの数 (累積ではない)、合計、数
+	Histogram(Vec<u64>, f64, u64),
+}
+
+impl Registry {
+	pub const fn new() -> Self {
+		Self {
+			families: Mutex::new(BTreeMap::new()),
+		}
+	}
+	fn series(
+		&self,
+		name: &'static str,
+		help: &'static str,
+		labels: &'static [&'static str],
+		values: &[&str],
+		init: impl FnOnce() -> Series,
+		update: impl FnOnce(&mut Series),
+	) {
+		debug_assert_eq!(labels.len(), values.len(), "{name}");
+		// 数え損ねても処理は止めない
+		let Ok(mut families) = self.families.lock() else {
+			return;
+		};
+		let family = families.entry(name).or_insert_with(|| Family {
+			help,
+			labels,
+			series: BTreeMap::new(),
+		});
+		let key = values.iter().map(|v| v.to_string()).collect();
+		update(family.series.entry(key).or_insert_with(init));
+	}
+	pub fn inc(&self, counter: &Counter, values: &[&str]) {
+		self.series(
+			counter.name,
+			counter.help,
+			counter.labels,
+			values,
+			|| Series::Counter(0),
+			|series| {
+				if let Series::Counter(n) = series {
+					*n += 1;
+				}
+			},
+		);
+	}
+	pub fn observe(&self, histogram: &Histogram, values: &[&str], seconds: f64) {
+		self.series(
+			histogram.name,
+			histogram.help,
+			histogram.labels,
+			values,
+			|| Series::Histogram(vec![0; BUCKETS.len()], 0.0, 0),
+			|series| {
+				if let Series::Histogram(buckets, sum, count) = series {
+					if let Some(i) = BUCKETS.iter().position(|le| seconds <= *le) {
+						buckets[i] += 1;
+					}
+					*sum += seconds;
+					*count += 1;
+				}
+			},
+		);
+	}
+	// Prometheus のテキスト形式
+	pub fn render(&self) -> String {
+		let mut out = String::new();
+		let Ok(families) = self.families.lock() else {
+			return out;
+		};
+		for (name, family) in families.iter() {
+			let kind = match family.series.values().next() {
+				Some(Series::Counter(_)) => "counter",
+				Some(Series::Histogram(..)) => "histogram",
+				None => continue,
+			};
+			let _ = writeln!(out, "# HELP {name} {}", family.help);
+			let _ = writeln!(out, "# TYPE {name} {kind}");
+			for (values, series) in &family.series {
+				let labels = family
+					.labels
+					.iter()
+					.zip(values)
+					.map(|(label, value)| format!("{label}=\"{}\"", escape(value)))
+					.collect::<Vec<_>>();
+				match series {
+					Series::Counter(n) => {
+						let _ = writeln!(out, "{name}{} {n}", braces(&labels));
+					}
+					Series::Histogram(buckets, sum, count) => {
+						let mut cumulative = 0;
+						for (le, n) in BUCKETS.iter().zip(buckets) {
+							cumulative += n;
+							let labels = [&labels[..], &[format!("le=\"{le}\"")]].concat();
+							let _ = writeln!(out, "{name}_bucket{} {cumulative}", braces(&labels));
+						}
+						let inf = [&labels[..], &["le=\"+Inf\"".to_string()]].concat();
+						let _ = writeln!(out, "{name}_bucket{} {count}", braces(&inf));
+						let _ = writeln!(out, "{name}_sum{} {sum}", braces(&labels));
+						let _ = writeln!(out, "{name}_count{} {count}", braces(&labels));
+					}
+				}
+			}
+		}
+		out
+	}
+}
+
+fn braces(labels: &[String]) -> String {
+	if labels.is_empty() {
+		String::new()
+	} else {
+		format!("{{{}}}", labels.join(","))
+	}
+}
+
+fn escape(value: &str) -> String {
+	value
+		.replace('\\', "\\\\")
+		.replace('"', "\\\"")
+		.replace('\n', "\\n")
+}
+
+// result ラベルの値、失敗なら Error の種類
+pub fn outcome<T>(result: &Result<T, Error>) -> &'static str {
+	match result {
+		Ok(_) => "ok",
+		Err(Error::NotFound(_)) => "not_found",
+		Err(Error::AlreadyExists(_)) => "already_exists",
+		Err(Error::Conflict(_)) => "conflict",
+		Err(Error::Unavailable(_)) => "unavailable",
+		Err(Error::Invalid(_)) => "invalid",
+		Err(Error::Internal(_)) => "internal",
+	}
+}
+
+// リクエストの数と時間を数える axum のミドルウェア
+// route はパスそのものではなくルートの定義 ("/api/video/{id}" など)、どのルートにも合わなければ "unmatched"
+pub async fn track(request: Request<Body>, next: Next) -> Response {
+	let method = request.method().to_string();
+	let route = request
+		.extensions()
+		.get::<MatchedPath>()
+		.map_or("unmatched", MatchedPath::as_str)
+		.to_string();
+	let started = Instant::now();
+	let response = next.run(request).await;
+	HTTP_REQUESTS.inc(&[&method, &route, response.status().as_str()]);
+	HTTP_DURATION.observe(&[&method, &route], started.elapsed());
+	response
+}
+
+// GET /metrics、token があれば Authorization: Bearer {token} を求める
+pub fn router(token: Option<String>) -> axum::Router {
+	axum::Router::new().route(
+		"/metrics",
+		axum::routing::get(async move |headers: HeaderMap| {
+			if let Some(token) = &token
+				&& !authorized(&headers, token)
+			{
+				return StatusCode::UNAUTHORIZED.into_response();
+			}
+			(
+				[(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+				REGISTRY.render(),
+			)
+				.into_response()
+		}),
+	)
+}
+
+fn authorized(headers: &HeaderMap, token: &str) -> bool {
+	let Some(given) = headers
+		.get(header::AUTHORIZATION)
+		.and_then(|v| v.to_str().ok())
+		.and_then(|v| v.strip_prefix("Bearer "))
+	else {
+		return false;
+	};
+	// 一致した長さから推測されないように、最後まで比べる
+	given.len() == token.len()
+		&& given
+			.bytes()
+			.zip(token.bytes())
+			.fold(0, |acc, (a, b)| acc | (a ^ b))
+			== 0
+}
+
+// 操作ごとの数と時間を数える Storage、Cached の内側に置いて保存先に問い合わせたものだけを数える
+// collection ラベルはパスの最後 ("video/{id}/comment" なら "comment") にして系列を増やさない
+#[derive(Clone)]
+pub struct Metered<S> {
+	inner: S,
+}
+
+impl<S: Storage> Metered<S> {
+	pub fn new(inner: S) -> Self {
+		Self { inner }
+	}
+}
+
+async fn measure<T>(
+	operation: &str,
+	collection: &str,
+	future: impl Future<Output = Result<T, Error>>,
+) -> Result<T, Error> {
+	let collection = collection.rsplit('/').next().unwrap_or(collection);
+	let started = Instant::now();
+	let result = future.await;
+	STORAGE_OPERATIONS.inc(&[operation, collection, outcome(&result)]);
+	STORAGE_DURATION.observe(&[operation, collection], started.elapsed());
+	result
+}
+
+impl<S: Storage> Storage for Metered<S> {
+	async fn get(&self, collection: &str, document_id: &str) -> Result<Option<Document>, Error> {
+		measure("get", collection, self.inner.get(collection, document_id)).await
+	}
+	async fn write(
+		&self,
+		collection: &str,
+		document_id: &str,
+		data: &Value,
+		write: &Write,
+	) -> Result<Version, Error> {
+		let future = self.inner.write(collection, document_id, data, write);
+		measure("write", collection, future).await
+	}
+	async fn query(&self, collection: &str, query: &Query) -> Result<Vec<Document>, Error> {
+		measure("query", collection, self.inner.query(collection, query)).await
+	}
+	async fn query_group(
+		&self,
+		collection_id: &str,
+		query: &Query,
+	) -> Result<Vec<Document>, Error> {
+		let future = self.inner.query_group(collection_id, query);
+		measure("query_group", collection_id, future).await
+	}
+	async fn count(&self, collection: &str, filter: Option<&Filter>) -> Result<u64, Error> {
+		measure("count", collection, self.inner.count(collection, filter)).await
+	}
+	async fn sum(
+		&self,
+		collection: &str,
+		filter: Option<&Filter>,
+		field: &str,
+	) -> Result<f64, Error> {
+		let future = self.inner.sum(collection, filter, field);
+		measure("sum", collection, future).await
+	}
+	async fn delete(&self, collection: &str, document_id: &str) -> Result<(), Error> {
+		measure(
+			"delete",
+			collection,
+			self.inner.delete(collection, document_id),
+		)
+		.await
+	}
+	async fn increment(
+		&self,
+		collection: &str,
+		document_id: &str,
+		field: &str,
+		by: i64,
+	) -> Result<(), Error> {
+		let future = self.inner.increment(collection, document_id, field, by);
+		measure("increment", collection, future).await
+	}
+	// 複数のコレクションにまたがるので collection は空にする
+	async fn commit(&self, transaction: &Transaction) -> Result<(), Error> {
+		measure("commit", "", self.inner.commit(transaction)).await
+	}
+	// 待ち受けを始めるまでを数える
+	async fn watch(
+		&self,
+		collection: &str,
+		filter: Option<Filter>,
+		after: Option<Version>,
+	) -> Result<Watch, Error> {
+		let future = self.inner.watch(collection, filter, after);
+		measure("watch", collection, future).await
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::storage::Memory;
+	use tower::ServiceExt;
+
+	#[test]
+	fn test_render() {
+		let registry = Registry::new();
+		let counter = Counter {
+			name: "test_total",
+			help: "test",
+			labels: &["a"],
+		};
+		let histogram = Histogram {
+			name: "test_seconds",
+			help: "test",
+			labels: &[],
+		};
+		registry.inc(&counter, &["x\"y"]);
+		registry.inc(&counter, &["x\"y"]);
+		registry.observe(&histogram, &[], 0.02);
+		registry.observe(&histogram, &[], 20.0);
+		let text = registry.render();
+		assert!(text.contains("# TYPE test_total counter\ntest_total{a=\"x\\\"y\"} 2\n"));
+		assert!(text.contains("test_seconds_bucket{le=\"0.01\"} 0\n"));
+		assert!(text.contains("test_seconds_bucket{le=\"0.025\"} 1\n"));
+		assert!(text.contains("test_seconds_bucket{le=\"10\"} 1\n"));
+		assert!(text.contains("test_seconds_bucket{le=\"+Inf\"} 2\n"));
+		assert!(text.contains("test_seconds_count 2\n"));
+	}
+
+	#[tokio::test]
+	async fn test_router() {
+		let app = axum::Router::new()
+			.route("/item/{id}", axum::routing::get(async || "ok"))
+			.merge(router(Some("secret".to_string())))
+			.layer(axum::middleware::from_fn(track));
+		let get = |uri: &str, token: Option<&str>| {
+			let mut builder = Request::builder().uri(uri);
+			if let Some(token) = token {
+				builder = builder.header(header::AUTHORIZATION, format!("Bearer {token}"));
+			}
+			app.clone().oneshot(builder.body(Body::empty()).unwrap())
+		};
+		get("/item/1", None).await.unwrap();
+		let db = Metered::new(Memory::new());
+		db.get("metrics_test/a/child", "b").await.unwrap();
+		let response = get("/metrics", Some("wrong")).await.unwrap();
+		assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+		let response = get("/metrics", Some("secret")).await.unwrap();
+		assert_eq!(response.status(), StatusCode::OK);
+		let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+			.await
+			.unwrap();
+		let text = String::from_utf8(body.to_vec()).unwrap();
+		// パスの ID ではなくルートの定義で数える
+		assert!(
+			text.contains(
+				"http_requests_total{method=\"GET\",route=\"/item/{id}\",status=\"200\"}"
+			)
+		);
+		assert!(text.contains(
+			"storage_operations_total{operation=\"get\",collection=\"child\",result=\"ok\"}"
+		));
+	}
+}
@@ -0,0 +1,217 @@
+use crate::collection::Collection;
+use crate::error::Error;
+use crate::storage::{self, Document, Precondition, Query, Storage, Write};
+use futures::TryStreamExt;
+use serde_json::Value;
+
+// 保存するドキュメントに書き込む、どの版の構造体で書いたかを表すフィールド
+// 無いドキュメントはこの仕組みより前に書かれたもので、版 0 として扱う
+pub const SCHEMA_VERSION: &str = "schema_version";
+
+// ドキュメントを1つ新しい版の形に書き換える
+// Collection::migrations() の i 番目が版 i から版 i+1 への書き換えで、並びの長さが今の版になる
+// 読むたびに適用されるので、同じドキュメントに何度適用しても同じ結果になるように書く
+pub type Migration = fn(&mut Value) -> Result<(), Error>;
+
+// 今の構造体の版
+pub fn current<C: Collection>() -> u64 {
+	C::migrations().len() as u64
+}
+
+// 読み出したドキュメントを今の版の形にして、版のフィールドを取り除く
+// 保存されている中身は書き換えないので、次に書き込むまでは読むたびに適用される
+pub fn upgrade<C: Collection>(data: &mut Value) -> Result<(), Error> {
+	let version = match data.as_object_mut() {
+		Some(map) => map
+			.remove(SCHEMA_VERSION)
+			.and_then(|v| v.as_u64())
+			.unwrap_or_default(),
+		None => return Ok(()),
+	};
+	let migrations = C::migrations();
+	if version > migrations.len() as u64 {
+		// 新しいプログラムが書いたドキュメントを古いプログラムで読んだ、書き戻すと新しいフィールドが消える
+		return Err(Error::Internal(format!(
+			"{} document has schema version {version}, newer than {}",
+			C::collection_name(),
+			migrations.len()
+		)));
+	}
+	for migration in &migrations[version as usize..] {
+		migration(data)?;
+	}
+	Ok(())
+}
+
+// 書き込むドキュメントに今の版を付ける
+// 一部のフィールドだけを書き換える patch では付けない、残りのフィールドは古い版のままなので
+pub fn stamp<C: Collection>(data: &mut Value) {
+	if let Some(map) = data.as_object_mut() {
+		map.insert(SCHEMA_VERSION.to_string(), current::<C>().into());
+	}
+}
+
+// migrate の結果
+#[derive(Debug, Default, PartialEq)]
+pub struct Report {
+	// 読んだドキュメントの数
+	pub scanned: usize,
+	// 今の版に書き換えた(dry_run では書き換えが必要な)ドキュメントのID
+	pub migrated: Vec<String>,
+	// 書き換えられなかったドキュメントのIDと理由
+	// 読んでから書くまでの間に他で書き換えられたものも含む、もう一度実行すれば進む
+	pub failed: Vec<(String, Error)>,
+}
+
+// C のドキュメントをすべて読み、古い版のものを今の版に書き換えて保存する
+// dry_run なら書き換えて構造体に戻せるかを確かめるだけで保存しない
+// 読んだ版を前提条件にして書くので、同時に動いているリクエストの更新は上書きしない
+pub async fn migrate<C: Collection>(db: &impl Storage, dry_run: bool) -> Result<Report, Error> {
+	let collection = C::collection_name();
+	let mut report = Report::default();
+	let documents = storage::stream(db, collection.to_string(), Query::default());
+	let mut documents = std::pin::pin!(documents);
+	while let Some(document) = documents.try_next().await? {
+		report.scanned += 1;
+		let id = document.id.clone();
+		match migrate_document::<C>(db, document, dry_run).await {
+			Ok(true) => report.migrated.push(id),
+			Ok(false) => {}
+			Err(e) => report.failed.push((id, e)),
+		}
+	}
+	Ok(report)
+}
+
+// 書き換えが必要だったかどうかを返す
+async fn migrate_document<C: Collection>(
+	db: &impl Storage,
+	document: Document,
+	dry_run: bool,
+) -> Result<bool, Error> {
+	let version = document.data.get(SCHEMA_VERSION).and_then(Value::as_u64);
+	if version == Some(current::<C>()) {
+		return Ok(false);
+	}
+	let mut data = document.data;
+	upgrade::<C>(&mut data)?;
+	// 構造体を通して書き出し直すと、どの版の書き込みとも同じ形になる
+	let value: C = serde_json::from_value(data)?;
+	if dry_run {
+		return Ok(true);
+	}
+	let mut data = serde_json::to_value(&value)?;
+	stamp::<C>(&mut data);
+	let write = Write {
+		precondition: Precondition::Version(document.version),
+		mask: None,
+	};
+	db.write(C::collection_name(), &document.id, &data, &write)
+		.await?;
+	Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::storage::Memory;
+	use serde::{Deserialize, Serialize};
+	use serde_json::json;
+
+	// 版 0 は name だけ、版 1 で nickname を足し、版 2 で name を display_name に改めた
+	#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Collection)]
+	#[collection(migrations = PROFILE_MIGRATIONS)]
+	struct Profile {
+		id: String,
+		display_name: String,
+		nickname: String,
+	}
+
+	const PROFILE_MIGRATIONS: &[Migration] = &[
+		// patch は版を付けないので、版 0 のまま nickname や display_name を書き込んだドキュメントもある
+		|v| {
+			if v.get("nickname").is_none() {
+				v["nickname"] = json!("");
+			}
+			Ok(())
+		},
+		|v| {
+			if let Some(map) = v.as_object_mut()
+				&& let Some(name) = map.remove("name")
+				&& !map.contains_key("display_name")
+			{
+				map.insert("display_name".to_string(), name);
+			}
+			Ok(())
+		},
+	];
+
+	#[tokio::test]
+	async fn test_migrate() {
+		let db = Memory::new();
+		let old = json!({"id": "a", "name": "Alice"});
+		db.write("profile", "a", &old, &Write::default())
+			.await
+			.unwrap();
+		let newer = json!({"id": "c", "display_name": "C", "nickname": "", SCHEMA_VERSION: 3});
+		db.write("profile", "c", &newer, &Write::default())
+			.await
+			.unwrap();
+		let b = Profile {
+			id: "b".to_string(),
+			display_name: "Bob".to_string(),
+			nickname: "bob".to_string(),
+		};
+		b.push(&db).await.unwrap();
+		assert_eq!(
+			db.get("profile", "b").await.unwrap().unwrap().data[SCHEMA_VERSION],
+			2
+		);
+		// 古いドキュメントも読むときに今の形になる
+		let a = Profile::get(&db, "a").await.unwrap();
+		assert_eq!(
+			(a.display_name.as_str(), a.nickname.as_str()),
+			("Alice", "")
+		);
+		// 新しい版のドキュメントは読めない
+		assert!(matches!(
+			Profile::get(&db, "c").await,
+			Err(Error::Internal(_))
+		));
+		let report = migrate::<Profile>(&db, true).await.unwrap();
+		assert_eq!(report.scanned, 3);
+		assert_eq!(report.migrated, ["a"]);
+		assert_eq!(report.failed.len(), 1);
+		assert_eq!(db.get("profile", "a").await.unwrap().unwrap().data, old);
+		let report = migrate::<Profile>(&db, false).await.unwrap();
+		assert_eq!(report.migrated, ["a"]);
+		assert_eq!(
+			db.get("profile", "a").await.unwrap().unwrap().data,
+			json!({"id": "a", "display_name": "Alice", "nickname": "", SCHEMA_VERSION: 2})
+		);
+		let report = migrate::<Profile>(&db, false).await.unwrap();
+		assert!(report.migrated.is_empty());
+	}
+
+	#[tokio::test]
+	async fn test_patch_old_document() {
+		let db = Memory::new();
+		let old = json!({"id": "a", "name": "Alice"});
+		db.write("profile", "a", &old, &Write::default())
+			.await
+			.unwrap();
+		let a = Profile {
+			display_name: "Alicia".to_string(),
+			nickname: "ali".to_string(),
+			..Profile::get(&db, "a").await.unwrap().into_inner()
+		};
+		a.patch(&db, &["display_name", "nickname"]).await.unwrap();
+		// 版 0 のままでも、書き込んだフィールドは読み直しても戻らない
+		let data = db.get("profile", "a").await.unwrap().unwrap().data;
+		assert!(data.get(SCHEMA_VERSION).is_none());
+		assert_eq!(Profile::get(&db, "a").await.unwrap().into_inner(), a);
+		let report = migrate::<Profile>(&db, false).await.unwrap();
+		assert_eq!(report.migrated, ["a"]);
+		assert_eq!(Profile::get(&db, "a").await.unwrap().into_inner(), a);
+	}
+}
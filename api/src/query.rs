@@ -0,0 +1,224 @@
+use crate::storage::{Filter, Operator, OrderBy, Query};
+use serde::Serialize;
+use serde_json::Value;
+use std::marker::PhantomData;
+
+// コレクション C の、値の型が T のフィールド
+// コレクションごとに定数として並べておき、クエリはフィールド名の文字列ではなくこの定数で組み立てる
+// 定数名を打ち間違えればコンパイルが通らず、比べる値の型が違っても通らない
+pub struct Field<C, T> {
+	name: &'static str,
+	marker: PhantomData<fn() -> (C, T)>,
+}
+
+impl<C, T> Clone for Field<C, T> {
+	fn clone(&self) -> Self {
+		*self
+	}
+}
+
+impl<C, T> Copy for Field<C, T> {}
+
+impl<C, T> Field<C, T> {
+	// name は serde で書き出したときのフィールド名、入れ子は "a.b" 形式
+	pub const fn new(name: &'static str) -> Self {
+		Self {
+			name,
+			marker: PhantomData,
+		}
+	}
+	pub const fn name(&self) -> &'static str {
+		self.name
+	}
+	pub fn asc(&self) -> Order<C> {
+		Order(OrderBy::Asc(self.name), PhantomData)
+	}
+	pub fn desc(&self) -> Order<C> {
+		Order(OrderBy::Desc(self.name), PhantomData)
+	}
+}
+
+impl<C, T: Serialize> Field<C, T> {
+	pub fn eq(&self, value: impl Into<T>) -> Where<C> {
+		self.compare(Operator::Eq, value.into())
+	}
+	pub fn ne(&self, value: impl Into<T>) -> Where<C> {
+		self.compare(Operator::Ne, value.into())
+	}
+	pub fn lt(&self, value: impl Into<T>) -> Where<C> {
+		self.compare(Operator::Lt, value.into())
+	}
+	pub fn le(&self, value: impl Into<T>) -> Where<C> {
+		self.compare(Operator::Le, value.into())
+	}
+	pub fn gt(&self, value: impl Into<T>) -> Where<C> {
+		self.compare(Operator::Gt, value.into())
+	}
+	pub fn ge(&self, value: impl Into<T>) -> Where<C> {
+		self.compare(Operator::Ge, value.into())
+	}
+	pub fn is_in<V: Into<T>>(&self, values: impl IntoIterator<Item = V>) -> Where<C> {
+		self.compare(Operator::In, list(values))
+	}
+	pub fn not_in<V: Into<T>>(&self, values: impl IntoIterator<Item = V>) -> Where<C> {
+		self.compare(Operator::NotIn, list(values))
+	}
+	fn compare(&self, operator: Operator, value: impl Serialize) -> Where<C> {
+		Where(
+			Filter::Compare(self.name, operator, to_value(value)),
+			PhantomData,
+		)
+	}
+}
+
+impl<C, E: Serialize> Field<C, Vec<E>> {
+	// 配列のフィールドが value を要素に含む
+	pub fn contains(&self, value: impl Into<E>) -> Where<C> {
+		self.compare(Operator::ArrayContains, value.into())
+	}
+	// 配列のフィールドが values のどれかを要素に含む
+	pub fn contains_any<V: Into<E>>(&self, values: impl IntoIterator<Item = V>) -> Where<C> {
+		self.compare(Operator::ArrayContainsAny, list(values))
+	}
+}
+
+fn list<T: Serialize, V: Into<T>>(values: impl IntoIterator<Item = V>) -> Vec<T> {
+	values.into_iter().map(Into::into).collect()
+}
+
+// Filter::eq と同じく、JSON にできない値は null として比べずに止める
+fn to_value(value: impl Serialize) -> Value {
+	serde_json::to_value(value).unwrap_or_else(|e| panic!("filter value is not serializable: {e}"))
+}
+
+// コレクション C の検索条件
+pub struct Where<C>(Filter, PhantomData<fn() -> C>);
+
+impl<C> Clone for Where<C> {
+	fn clone(&self) -> Self {
+		Self(self.0.clone(), PhantomData)
+	}
+}
+
+impl<C> std::fmt::Debug for Where<C> {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		self.0.fmt(f)
+	}
+}
+
+impl<C> Where<C> {
+	pub fn and(self, other: Where<C>) -> Self {
+		Self(
+			match self.0 {
+				Filter::And(mut filters) => {
+					filters.push(other.0);
+					Filter::And(filters)
+				}
+				filter => Filter::and([filter, other.0]),
+			},
+			PhantomData,
+		)
+	}
+	pub fn or(self, other: Where<C>) -> Self {
+		Self(
+			match self.0 {
+				Filter::Or(mut filters) => {
+					filters.push(other.0);
+					Filter::Or(filters)
+				}
+				filter => Filter::or([filter, other.0]),
+			},
+			PhantomData,
+		)
+	}
+}
+
+// コレクション C の並び順
+pub struct Order<C>(OrderBy, PhantomData<fn() -> C>);
+
+// コレクション C へのクエリ、Collection::select() から組み立てる
+pub struct Select<C> {
+	query: Query,
+	marker: PhantomData<fn() -> C>,
+}
+
+impl<C> Default for Select<C> {
+	fn default() -> Self {
+		Self {
+			query: Default::default(),
+			marker: PhantomData,
+		}
+	}
+}
+
+impl<C> Clone for Select<C> {
+	fn clone(&self) -> Self {
+		Self {
+			query: self.query.clone(),
+			marker: PhantomData,
+		}
+	}
+}
+
+impl<C> Select<C> {
+	pub fn new() -> Self {
+		Self::default()
+	}
+	// 複数回呼ぶとすべてを満たすものに絞る
+	pub fn filter(mut self, condition: Where<C>) -> Self {
+		self.query.filter = Some(match self.query.filter.take() {
+			Some(filter) => Where(filter, PhantomData).and(condition).0,
+			None => condition.0,
+		});
+		self
+	}
+	// 複数回呼ぶと先に指定したものを優先して並べる
+	pub fn order_by(mut self, order: Order<C>) -> Self {
+		self.query.order.push(order.0);
+		self
+	}
+	pub fn limit(mut self, limit: u32) -> Self {
+		self.query.limit = Some(limit);
+		self
+	}
+	pub fn query(&self) -> &Query {
+		&self.query
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	struct Doc;
+	const NAME: Field<Doc, String> = Field::new("name");
+	const SCORE: Field<Doc, i32> = Field::new("score");
+	const TAGS: Field<Doc, Vec<String>> = Field::new("tags");
+
+	#[test]
+	fn test_select() {
+		let select = Select::<Doc>::new()
+			.filter(NAME.eq("a").or(NAME.is_in(["b", "c"])))
+			.filter(SCORE.ge(1))
+			.filter(TAGS.contains("x"))
+			.order_by(SCORE.desc())
+			.order_by(NAME.asc())
+			.limit(10);
+		assert_eq!(
+			select.query(),
+			&Query {
+				filter: Some(Filter::and([
+					Filter::or([
+						Filter::Compare("name", Operator::Eq, "a".into()),
+						Filter::Compare("name", Operator::In, serde_json::json!(["b", "c"])),
+					]),
+					Filter::Compare("score", Operator::Ge, 1.into()),
+					Filter::Compare("tags", Operator::ArrayContains, "x".into()),
+				])),
+				order: vec![OrderBy::Desc("score"), OrderBy::Asc("name")],
+				cursor: None,
+				limit: Some(10),
+			}
+		);
+	}
+}
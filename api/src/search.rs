@@ -0,0 +1,433 @@
+use crate::collection::{Collection, Event, Page, Versioned};
+use crate::error::Error;
+use crate::storage::{Cursor, OrderBy, Query, Storage};
+use futures::TryStreamExt;
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::sync::{Arc, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+// 全文検索の対象にできるコレクション
+pub trait Searchable: Collection {
+	// 検索の対象にする文字列と、その文字列に含まれる語を数えるときの重み
+	fn text(&self) -> Vec<(&str, f64)>;
+}
+
+// C の転置インデックス、プロセスのメモリ上に持つ
+// Firestore には全文検索が無いので、起動時に follow でコレクションから作り、あとは変更を受け取って更新する
+// 日本語は2文字ずつ区切る (bigram) ので辞書が要らない、点数は BM25
+pub struct Index<C> {
+	inner: Arc<RwLock<Inner>>,
+	marker: PhantomData<fn() -> C>,
+}
+
+impl<C> Clone for Index<C> {
+	fn clone(&self) -> Self {
+		Self {
+			inner: self.inner.clone(),
+			marker: PhantomData,
+		}
+	}
+}
+
+impl<C: Searchable> Index<C> {
+	pub fn new() -> Self {
+		Self {
+			inner: Default::default(),
+			marker: PhantomData,
+		}
+	}
+	// 途中で panic しても壊れるのは一部のドキュメントの語だけで、次の rebuild で直るのでそのまま使う
+	fn read(&self) -> RwLockReadGuard<'_, Inner> {
+		self.inner.read().unwrap_or_else(PoisonError::into_inner)
+	}
+	fn write(&self) -> RwLockWriteGuard<'_, Inner> {
+		self.inner.write().unwrap_or_else(PoisonError::into_inner)
+	}
+	// 追加または更新する、書き込んだプロセスではすぐに検索できるように変更の通知を待たずに呼ぶ
+	pub fn put(&self, value: &C) {
+		self.write().put(value.document_id(), &value.text());
+	}
+	pub fn remove(&self, document_id: &str) {
+		self.write().remove(document_id);
+	}
+	// コレクションをすべて読み直して作り直す、入れたドキュメントの数を返す
+	pub async fn rebuild(&self, db: &impl Storage) -> Result<usize, Error> {
+		let mut inner = Inner::default();
+		let select = C::select();
+		let mut values = std::pin::pin!(C::stream(db, &select));
+		while let Some(v) = values.try_next().await? {
+			inner.put(v.value.document_id(), &v.value.text());
+		}
+		let count = inner.documents.len();
+		*self.write() = inner;
+		Ok(count)
+	}
+	// 作り直してから、変更を受け取って反映し続ける、取りこぼしがあったら作り直す
+	// 保存先が無くなるか読み直しに失敗するまで返らないので tokio::spawn で動かす
+	pub async fn follow(self, db: impl Storage) -> Result<(), Error> {
+		// 作り直している間の変更を取りこぼさないように、先に待ち受けを始める
+		let mut watch = C::watch(&db, &C::select(), None).await?;
+		self.rebuild(&db).await?;
+		while let Some(event) = watch.next().await {
+			match event {
+				Ok(Event::Put(v)) => self.put(&v.value),
+				Ok(Event::Delete(id, _)) => self.remove(&id),
+				Ok(Event::Reset) => {
+					self.rebuild(&db).await?;
+				}
+				// 読めないドキュメントは検索に出なくても困らない
+				Err(e) => tracing::warn!(
+					collection = C::collection_name(),
+					error = %e,
+					"cannot index document"
+				),
+			}
+		}
+		Ok(())
+	}
+	// q の語をすべて含むものを点数の高い順に返す、cursor は前のページの next_cursor
+	pub async fn search(
+		&self,
+		db: &impl Storage,
+		q: &str,
+		cursor: Option<&str>,
+		limit: u32,
+	) -> Result<Page<Versioned<C>>, Error> {
+		let terms = query_terms(q);
+		// 同じ検索語で作ったカーソルだけを受け付ける
+		let key = format!("search/{}/{}", C::collection_name(), terms.join(" "));
+		let order = Query {
+			order: vec![OrderBy::Desc("score")],
+			..Default::default()
+		};
+		let after = match cursor {
+			Some(token) => Some(Cursor::from_token(token, &key, &order)?),
+			None => None,
+		};
+		let mut hits = self.read().score(&terms);
+		// 点数が同じなら Query の並びと同じくドキュメントIDの逆順
+		hits.sort_by(|a, b| b.0.total_cmp(&a.0).then_with(|| b.1.cmp(&a.1)));
+		if let Some(after) = after {
+			let score = after
+				.values
+				.first()
+				.and_then(|v| v.as_u64())
+				.map_or(f64::INFINITY, f64::from_bits);
+			hits.retain(|(s, id)| s.total_cmp(&score).then_with(|| id.cmp(&after.id)).is_lt());
+		}
+		let mut hits = hits.into_iter().peekable();
+		let mut items = vec![];
+		let mut last = None;
+		while items.len() < limit as usize
+			&& let Some((score, id)) = hits.next()
+		{
+			match C::get(db, &id).await {
+				Ok(v) => {
+					items.push(v);
+					last = Some((score, id));
+				}
+				// 消されたがまだ変更が届いていない
+				Err(Error::NotFound(_)) => self.remove(&id),
+				Err(e) => return Err(e),
+			}
+		}
+		let next_cursor = match (hits.peek(), last) {
+			// 点数は f64 のビット列で持つ、JSON の小数は読み書きで値がずれることがある
+			(Some(_), Some((score, id))) => Some(
+				Cursor {
+					values: vec![score.to_bits().into()],
+					id,
+				}
+				.token(&key, &order),
+			),
+			_ => None,
+		};
+		Ok(Page { items, next_cursor })
+	}
+}
+
+#[derive(Default)]
+struct Inner {
+	// 語 → ドキュメントID → 重みを掛けた出現数
+	postings: HashMap<String, HashMap<String, f64>>,
+	// ドキュメントID → 含まれる語と、重みを掛けた語の数
+	documents: HashMap<String, (Vec<String>, f64)>,
+	// documents の語の数の合計
+	total_length: f64,
+}
+
+impl Inner {
+	fn put(&mut self, id: String, text: &[(&str, f64)]) {
+		self.remove(&id);
+		let mut frequencies: HashMap<String, f64> = HashMap::new();
+		let mut length = 0.0;
+		for (text, weight) in text {
+			for term in tokenize(text) {
+				*frequencies.entry(term).or_default() += weight;
+				length += weight;
+			}
+		}
+		for (term, frequency) in &frequencies {
+			self.postings
+				.entry(term.clone())
+				.or_default()
+				.insert(id.clone(), *frequency);
+		}
+		self.total_length += length;
+		self.documents
+			.insert(id, (frequencies.into_keys().collect(), length));
+	}
+	fn remove(&mut self, id: &str) {
+		let Some((terms, length)) = self.documents.remove(id) else {
+			return;
+		};
+		self.total_length -= length;
+		for term in terms {
+			if let Some(posting) = self.postings.get_mut(&term) {
+				posting.remove(id);
+				if posting.is_empty() {
+					self.postings.remove(&term);
+				}
+			}
+		}
+	}
+	// terms をすべて含むドキュメントのIDと BM25 の点数
+	fn score(&self, terms: &[String]) -> Vec<(f64, String)> {
+		const K1: f64 = 1.2;
+		const B: f64 = 0.75;
+		let mut postings = vec![];
+		for term in terms {
+			match self.postings.get(term) {
+				Some(posting) => postings.push(posting),
+				None => return vec![],
+			}
+		}
+		// 最も短いものから候補を絞る
+		postings.sort_by_key(|p| p.len());
+		let Some((first, rest)) = postings.split_first() else {
+			return vec![];
+		};
+		let n = self.documents.len() as f64;
+		let average = self.total_length / n;
+		first
+			.keys()
+			.filter(|id| rest.iter().all(|p| p.contains_key(*id)))
+			.map(|id| {
+				let length = self.documents.get(id).map_or(0.0, |d| d.1);
+				let score = postings
+					.iter()
+					.map(|p| {
+						let df = p.len() as f64;
+						let idf = (1.0 + (n - df + 0.5) / (df + 0.5)).ln();
+						let tf = p[id];
+						idf * tf * (K1 + 1.0) / (tf + K1 * (1.0 - B + B * length / average))
+					})
+					.sum();
+				(score, id.clone())
+			})
+			.collect()
+	}
+}
+
+// 文字の種類、同じ種類が続く間を1つのまとまりとして区切る
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Script {
+	// 英数字など空白で区切って書く文字
+	Word,
+	// 仮名と漢字
+	Cjk,
+	// 空白や記号
+	Other,
+}
+
+fn script(c: char) -> Script {
+	match c {
+		// 中黒
+		'\u{30fb}' => Script::Other,
+		'\u{3040}'..='\u{30ff}'
+		| '\u{3400}'..='\u{4dbf}'
+		| '\u{4e00}'..='\u{9fff}'
+		| '\u{f900}'..='\u{faff}'
+		| '々'
+		| '〆' => Script::Cjk,
+		c if c.is_alphanumeric() => Script::Word,
+		_ => Script::Other,
+	}
+}
+
+// 全角の英数字と空白を半角にして、小文字にする
+fn normalize(text: &str) -> impl Iterator<Item = char> + '_ {
+	text.chars()
+		.map(|c| match c {
+			'\u{ff01}'..='\u{ff5e}' => char::from_u32(c as u32 - 0xfee0).unwrap_or(c),
+			'\u{3000}' => ' ',
+			c => c,
+		})
+		.flat_map(char::to_lowercase)
+}
+
+// 文字の種類が同じ間のまとまり、Other は含めない
+fn runs(text: &str) -> Vec<(Script, Vec<char>)> {
+	let mut runs: Vec<(Script, Vec<char>)> = vec![];
+	let mut previous = Script::Other;
+	for c in normalize(text) {
+		let script = script(c);
+		match runs.last_mut() {
+			Some((s, run)) if *s == script && previous == script => run.push(c),
+			_ if script != Script::Other => runs.push((script, vec![c])),
+			_ => {}
+		}
+		previous = script;
+	}
+	runs
+}
+
+// インデックスに入れる語、英数字は単語ごと、仮名と漢字は1文字と2文字ずつ
+// 1文字の語は1文字での検索のためだけに入れる
+pub fn tokenize(text: &str) -> Vec<String> {
+	let mut terms = vec![];
+	for (script, run) in runs(text) {
+		match script {
+			Script::Cjk => {
+				terms.extend(run.iter().map(|c| c.to_string()));
+				terms.extend(run.windows(2).map(|w| w.iter().collect()));
+			}
+			_ => terms.push(run.into_iter().collect()),
+		}
+	}
+	terms
+}
+
+// 検索語、仮名と漢字は2文字以上なら2文字ずつだけを使う
+// 2文字ずつの語がすべて含まれていれば、ほとんどの場合は続けて書かれている
+fn query_terms(q: &str) -> Vec<String> {
+	let mut terms: Vec<String> = vec![];
+	for (script, run) in runs(q) {
+		match script {
+			Script::Cjk if run.len() > 1 => {
+				terms.extend(run.windows(2).map(|w| w.iter().collect()))
+			}
+			_ => terms.push(run.into_iter().collect()),
+		}
+	}
+	terms.sort();
+	terms.dedup();
+	terms
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::storage::Memory;
+	use serde::{Deserialize, Serialize};
+
+	#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, Collection)]
+	#[collection(name = "page")]
+	struct Article {
+		id: String,
+		name: String,
+		content: String,
+	}
+
+	impl Searchable for Article {
+		fn text(&self) -> Vec<(&str, f64)> {
+			vec![(&self.name, 2.0), (&self.content, 1.0)]
+		}
+	}
+
+	#[test]
+	fn test_tokenize() {
+		assert_eq!(
+			tokenize("東京タワー・Ｒｕｓｔ入門"),
+			[
+				"東", "京", "タ", "ワ", "ー", "東京", "京タ", "タワ", "ワー", "rust", "入", "門",
+				"入門"
+			]
+		);
+		assert_eq!(
+			query_terms("東京 東京タワー  RUST"),
+			["rust", "タワ", "ワー", "京タ", "東京"]
+		);
+		assert_eq!(query_terms("猫"), ["猫"]);
+	}
+
+	#[tokio::test]
+	async fn test_search() {
+		let db = Memory::new();
+		let index = Index::<Article>::new();
+		let page = |id: &str, name: &str, content: &str| Article {
+			id: id.to_string(),
+			name: name.to_string(),
+			content: content.to_string(),
+		};
+		for p in [
+			page("a", "猫の動画", "かわいい猫が寝ている"),
+			page("b", "犬の動画", "猫と犬が遊んでいる"),
+			page("c", "料理", "東京の猫カフェで作るカレー"),
+			page("d", "Rust 入門", "所有権の説明"),
+		] {
+			p.push(&db).await.unwrap();
+		}
+		assert_eq!(index.rebuild(&db).await.unwrap(), 4);
+		let ids = |page: &Page<Versioned<Article>>| {
+			page.items
+				.iter()
+				.map(|v| v.value.id.clone())
+				.collect::<Vec<_>>()
+		};
+		// 名前に含むものが先に並ぶ
+		let first = index.search(&db, "猫", None, 2).await.unwrap();
+		assert_eq!(ids(&first)[0], "a");
+		let rest = index
+			.search(&db, "猫", first.next_cursor.as_deref(), 2)
+			.await
+			.unwrap();
+		assert_eq!(rest.items.len(), 1);
+		assert!(rest.next_cursor.is_none());
+		// 続けて書かれていない語は別々に含んでいても当たらない
+		let found = index.search(&db, "猫カフェ", None, 10).await.unwrap();
+		assert_eq!(ids(&found), ["c"]);
+		let found = index.search(&db, "ｒｕｓｔ", None, 10).await.unwrap();
+		assert_eq!(ids(&found), ["d"]);
+		// 別の検索語のカーソルは受け付けない
+		assert!(matches!(
+			index
+				.search(&db, "犬", first.next_cursor.as_deref(), 2)
+				.await,
+			Err(Error::Invalid(_))
+		));
+		// 更新と削除
+		index.put(&page("d", "Rust 入門", "猫でもわかる"));
+		index.remove("a");
+		let found = index.search(&db, "猫", None, 10).await.unwrap();
+		assert_eq!(found.items.len(), 3);
+		assert!(!ids(&found).contains(&"a".to_string()));
+		// 保存先から消えたものは返さない
+		Article::pop(&db, "b").await.unwrap();
+		let found = index.search(&db, "犬", None, 10).await.unwrap();
+		assert!(found.items.is_empty());
+		assert_eq!(index.read().documents.len(), 2);
+	}
+
+	#[tokio::test]
+	async fn test_follow() {
+		let db = Memory::new();
+		let index = Index::<Article>::new();
+		let task = tokio::spawn(index.clone().follow(db.clone()));
+		let p = Article {
+			id: "a".to_string(),
+			name: "検索".to_string(),
+			content: String::new(),
+		};
+		p.push(&db).await.unwrap();
+		for _ in 0..100 {
+			if index.read().documents.len() == 1 {
+				break;
+			}
+			tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+		}
+		let found = index.search(&db, "検索", None, 10).await.unwrap();
+		assert_eq!(found.items.len(), 1);
+		task.abort();
+	}
+}
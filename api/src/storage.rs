@@ -0,0 +1,1959 @@
+use crate::error::Error;
+use firestore::{
+	FirestoreConsistencySelector, FirestoreDb, FirestoreListenEvent, FirestoreListener,
+	FirestoreListenerTarget, FirestoreMemListenStateStorage, FirestoreQueryCursor,
+	FirestoreQueryDirection, FirestoreQueryFilter, FirestoreQueryOrder, FirestoreReference,
+	FirestoreWritePrecondition, select_filter_builder::FirestoreQueryFilterBuilder,
+};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use tokio::sync::broadcast;
+
+// ドキュメントの保存先を抽象化したtrait
+// Collection はこのtraitを通して読み書きするので、Firestore が無い環境(テストなど)でもメモリ上で動かせる
+// ドキュメントは serde_json::Value としてやり取りする
+// collection はサブコレクションなら "page/{id}/comment" のような親ドキュメントからのパスで渡す
+pub trait Storage: Clone + Send + Sync {
+	fn get(
+		&self,
+		collection: &str,
+		document_id: &str,
+	) -> impl Future<Output = Result<Option<Document>, Error>> + Send;
+	// 書き込んだ後の版を返す
+	fn write(
+		&self,
+		collection: &str,
+		document_id: &str,
+		data: &Value,
+		write: &Write,
+	) -> impl Future<Output = Result<Version, Error>> + Send;
+	fn query(
+		&self,
+		collection: &str,
+		query: &Query,
+	) -> impl Future<Output = Result<Vec<Document>, Error>> + Send;
+	// 親に関わらず collection_id という名前のコレクションすべてに対するクエリ (コレクショングループ)
+	// 返すドキュメントの id は "page/{id}/comment/{id}" のようなルートからのパスで、並びの最後もこの順
+	fn query_group(
+		&self,
+		collection_id: &str,
+		query: &Query,
+	) -> impl Future<Output = Result<Vec<Document>, Error>> + Send;
+	// filter に合うドキュメントの数、ドキュメントは読まずに保存先で数える
+	fn count(
+		&self,
+		collection: &str,
+		filter: Option<&Filter>,
+	) -> impl Future<Output = Result<u64, Error>> + Send;
+	// filter に合うドキュメントの数値のフィールド field ("a.b"形式) の合計
+	// 数値でない値やフィールドの無いドキュメントは足さない
+	fn sum(
+		&self,
+		collection: &str,
+		filter: Option<&Filter>,
+		field: &str,
+	) -> impl Future<Output = Result<f64, Error>> + Send;
+	fn delete(
+		&self,
+		collection: &str,
+		document_id: &str,
+	) -> impl Future<Output = Result<(), Error>> + Send;
+	// 数値のフィールド field ("a.b"形式) に by を足す、ドキュメントもフィールドも無ければ 0 から足す
+	// 読まずに保存先の中で足すので、同時に呼ばれても数え漏れは無い
+	fn increment(
+		&self,
+		collection: &str,
+		document_id: &str,
+		field: &str,
+		by: i64,
+	) -> impl Future<Output = Result<(), Error>> + Send;
+	// transaction で読んだものが今も変わっていなければ、transaction の書き込みをまとめて反映する
+	// 変わっていたら Conflict で失敗して何も書き込まない
+	fn commit(&self, transaction: &Transaction) -> impl Future<Output = Result<(), Error>> + Send;
+	// collection の変更を待ち受ける、filter に合わないドキュメントの書き込みは流さない
+	// after に前に受け取った変更の版を渡すと、その続きから流す
+	fn watch(
+		&self,
+		collection: &str,
+		filter: Option<Filter>,
+		after: Option<Version>,
+	) -> impl Future<Output = Result<Watch, Error>> + Send;
+}
+
+// ドキュメントの版、書き込むたびに変わる
+// Firestore では更新時刻(RFC 3339、マイクロ秒)、メモリ上では書き込みごとに増える番号
+// どちらも文字列として比較すると新しいものほど大きい
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Version(pub String);
+
+impl Version {
+	// HTTP の ETag ヘッダの値
+	pub fn etag(&self) -> String {
+		format!("\"{}\"", self.0)
+	}
+	// If-Match ヘッダの値から版を取り出す、"*" や複数指定は None
+	// If-Match は強い比較なので (RFC 9110 13.1.1)、弱い ETag (W/"...") も None
+	pub fn from_if_match(value: &str) -> Option<Self> {
+		let value = value.trim();
+		let value = value.strip_prefix('"')?.strip_suffix('"')?;
+		(!value.is_empty() && !value.contains('"')).then(|| Self(value.to_string()))
+	}
+	fn firestore(timestamp: &str) -> Result<Self, Error> {
+		let t = chrono::DateTime::parse_from_rfc3339(timestamp)
+			.map_err(|e| Error::Internal(format!("invalid update time {timestamp}: {e}")))?;
+		Ok(Self::from_chrono(t.with_timezone(&chrono::Utc)))
+	}
+	fn from_chrono(t: chrono::DateTime<chrono::Utc>) -> Self {
+		Self(t.format("%Y-%m-%dT%H:%M:%S%.6fZ").to_string())
+	}
+	// listen で受け取る read_time
+	fn from_timestamp(seconds: i64, nanos: i32) -> Option<Self> {
+		chrono::DateTime::from_timestamp(seconds, nanos as u32).map(Self::from_chrono)
+	}
+	fn to_firestore(&self) -> Result<chrono::DateTime<chrono::Utc>, Error> {
+		chrono::DateTime::parse_from_rfc3339(&self.0)
+			.map(|t| t.with_timezone(&chrono::Utc))
+			.map_err(|e| Error::Invalid(format!("invalid version {}: {e}", self.0)))
+	}
+}
+
+impl std::fmt::Display for Version {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.write_str(&self.0)
+	}
+}
+
+// 保存されているドキュメント
+#[derive(Debug, Clone, PartialEq)]
+pub struct Document {
+	pub id: String,
+	pub version: Version,
+	pub data: Value,
+}
+
+// 書き込みの前提条件
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum Precondition {
+	// 無条件、無ければ作る (upsert)
+	#[default]
+	None,
+	// 既にあるドキュメントだけ書き換える、無ければ NotFound (update)
+	Exists,
+	// まだ無い場合だけ作る、あれば AlreadyExists (insert)
+	Missing,
+	// 保存されている版がこれと同じ場合だけ書き換える、違えば Conflict (楽観的排他制御)
+	Version(Version),
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Write {
+	pub precondition: Precondition,
+	// Some の場合は列挙したフィールド("a.b"形式)だけを書き換え、他のフィールドには触れない
+	// data に無いフィールドを列挙するとそのフィールドは削除される
+	pub mask: Option<Vec<String>>,
+}
+
+// コレクションの変更
+#[derive(Debug, Clone, PartialEq)]
+pub enum Change {
+	// 作成または更新された
+	Put(Document),
+	// 削除された、ドキュメントIDと削除を知った時点の版
+	Delete(String, Version),
+	// 覚えていないほど古い版から再開しようとしたか、受け取りが遅れて取りこぼした
+	// 受け取った側は読み直してから続ける
+	Reset,
+}
+
+impl Change {
+	pub fn version(&self) -> Option<&Version> {
+		match self {
+			Self::Put(d) => Some(&d.version),
+			Self::Delete(_, v) => Some(v),
+			Self::Reset => None,
+		}
+	}
+}
+
+// Storage::watch で受け取る変更の列
+pub struct Watch {
+	backlog: VecDeque<Change>,
+	receiver: broadcast::Receiver<Change>,
+	filter: Option<Filter>,
+	// filter があるとき、書き込みを受け取ったドキュメントがそのとき filter に合っていたか
+	// 削除にはデータが無いので、これと id の条件から流すかどうかを決める
+	matched: HashMap<String, bool>,
+}
+
+impl Watch {
+	// 次の変更を待つ、保存先が無くなったら None
+	pub async fn next(&mut self) -> Option<Change> {
+		loop {
+			let change = match self.backlog.pop_front() {
+				Some(change) => change,
+				None => match self.receiver.recv().await {
+					Ok(change) => change,
+					Err(broadcast::error::RecvError::Lagged(_)) => Change::Reset,
+					Err(broadcast::error::RecvError::Closed) => return None,
+				},
+			};
+			let Some(filter) = &self.filter else {
+				return Some(change);
+			};
+			match &change {
+				Change::Put(d) => {
+					let matches = filter.matches(&d.data);
+					self.matched.insert(d.id.clone(), matches);
+					if !matches {
+						continue;
+					}
+				}
+				// 合っていなかったと分かっているものと、id の条件に合わないものは流さない
+				// watch を始める前に書かれたものはどちらか分からないので流す
+				Change::Delete(id, _) => match self.matched.remove(id) {
+					Some(false) => continue,
+					Some(true) => {}
+					None if filter.excludes_id(id) => continue,
+					None => {}
+				},
+				Change::Reset => self.matched.clear(),
+			}
+			return Some(change);
+		}
+	}
+}
+
+// コレクションごとに変更を配り、再開に備えて最近の変更を覚えておく
+// 一度でも watch されたコレクションの変更だけを扱う
+#[derive(Clone, Default)]
+struct Changes(Arc<Mutex<HashMap<String, Channel>>>);
+
+struct Channel {
+	sender: broadcast::Sender<Change>,
+	recent: VecDeque<Change>,
+	// この版より後の変更はすべて recent に残っている
+	// None は受け取り始めた時点の版がまだ分からない、その間に再開しようとしたものは読み直させる
+	since: Option<Version>,
+}
+
+impl Changes {
+	// 覚えておく変更の数、受け取りがこれ以上遅れると取りこぼす
+	const RECENT: usize = 256;
+	fn publish(&self, collection: &str, change: Change) {
+		let Ok(mut channels) = self.0.lock() else {
+			return;
+		};
+		let Some(channel) = channels.get_mut(collection) else {
+			return;
+		};
+		if channel.recent.len() == Self::RECENT
+			&& let Some(v) = channel
+				.recent
+				.pop_front()
+				.as_ref()
+				.and_then(Change::version)
+		{
+			channel.since = Some(v.clone());
+		}
+		channel.recent.push_back(change.clone());
+		// 受け取る側がいなくてもよい
+		let _ = channel.sender.send(change);
+	}
+	// 初めて watch されたコレクションなら2つめが true、since はその時点の版
+	fn subscribe(
+		&self,
+		collection: &str,
+		since: Option<Version>,
+		filter: Option<Filter>,
+		after: Option<Version>,
+	) -> Result<(Watch, bool), Error> {
+		let mut channels = self.0.lock()?;
+		let created = !channels.contains_key(collection);
+		let channel = channels
+			.entry(collection.to_string())
+			.or_insert_with(|| Channel {
+				sender: broadcast::channel(Self::RECENT).0,
+				recent: VecDeque::new(),
+				since,
+			});
+		// 覚えている分の取り出しと受け取りの開始を同じロックの中で行うので、間の変更を取りこぼさない
+		let backlog = match after {
+			None => VecDeque::new(),
+			Some(after) if channel.since.as_ref().is_none_or(|since| after < *since) => {
+				VecDeque::from([Change::Reset])
+			}
+			Some(after) => channel
+				.recent
+				.iter()
+				.filter(|c| c.version().is_some_and(|v| *v > after))
+				.cloned()
+				.collect(),
+		};
+		let watch = Watch {
+			backlog,
+			receiver: channel.sender.subscribe(),
+			filter,
+			matched: HashMap::new(),
+		};
+		Ok((watch, created))
+	}
+	// 受け取り始めた時点の版が分かった、まだ分かっていなければ since にする
+	fn start(&self, collection: &str, since: Version) {
+		if let Ok(mut channels) = self.0.lock()
+			&& let Some(channel) = channels.get_mut(collection)
+		{
+			channel.since.get_or_insert(since);
+		}
+	}
+	// start の後か
+	fn started(&self, collection: &str) -> bool {
+		self.0.lock().is_ok_and(|channels| {
+			channels
+				.get(collection)
+				.is_some_and(|channel| channel.since.is_some())
+		})
+	}
+	fn remove(&self, collection: &str) {
+		if let Ok(mut channels) = self.0.lock() {
+			channels.remove(collection);
+		}
+	}
+}
+
+// 複数のドキュメントをまとめて読み書きするための記録
+// 読んだドキュメントやクエリの結果を覚えておき、commit の時点でそれらが変わっていないことを確かめてから書き込む
+// 書き込みは commit まで貯めておくだけなので、Firestore と同じく読み出しは書き込みより前に済ませる
+#[derive(Debug, Clone, Default)]
+pub struct Transaction {
+	// 読んだドキュメントとその版、無かったものは None
+	reads: Vec<(String, String, Option<Version>)>,
+	// 実行したクエリと、その結果のドキュメントと版
+	queries: Vec<(String, Query, Vec<(String, Version)>)>,
+	// None は削除
+	writes: Vec<(String, String, Option<(Value, Write)>)>,
+}
+
+impl Transaction {
+	pub async fn get(
+		&mut self,
+		db: &impl Storage,
+		collection: &str,
+		document_id: &str,
+	) -> Result<Option<Document>, Error> {
+		let document = db.get(collection, document_id).await?;
+		self.reads.push((
+			collection.to_string(),
+			document_id.to_string(),
+			document.as_ref().map(|d| d.version.clone()),
+		));
+		Ok(document)
+	}
+	pub async fn query(
+		&mut self,
+		db: &impl Storage,
+		collection: &str,
+		query: &Query,
+	) -> Result<Vec<Document>, Error> {
+		let documents = db.query(collection, query).await?;
+		self.queries
+			.push((collection.to_string(), query.clone(), versions(&documents)));
+		Ok(documents)
+	}
+	pub fn write(&mut self, collection: &str, document_id: &str, data: Value, write: Write) {
+		self.writes.push((
+			collection.to_string(),
+			document_id.to_string(),
+			Some((data, write)),
+		));
+	}
+	pub fn delete(&mut self, collection: &str, document_id: &str) {
+		self.writes
+			.push((collection.to_string(), document_id.to_string(), None));
+	}
+	// 読んだドキュメントやクエリの結果が今も同じか、db から読み直して確かめる
+	async fn verify(&self, db: &impl Storage) -> Result<(), Error> {
+		for (collection, document_id, version) in &self.reads {
+			let current = db.get(collection, document_id).await?;
+			if current.map(|d| d.version) != *version {
+				return Err(Self::conflict(collection, document_id));
+			}
+		}
+		for (collection, query, documents) in &self.queries {
+			if versions(&db.query(collection, query).await?) != *documents {
+				return Err(Self::conflict(collection, "(query)"));
+			}
+		}
+		Ok(())
+	}
+	fn conflict(collection: &str, document_id: &str) -> Error {
+		Error::Conflict(format!(
+			"{collection}/{document_id} was modified during the transaction"
+		))
+	}
+	// 読み書きしたコレクション、重複を含む
+	pub fn collections(&self) -> impl Iterator<Item = &str> {
+		let reads = self.reads.iter().map(|(c, _, _)| c.as_str());
+		let queries = self.queries.iter().map(|(c, _, _)| c.as_str());
+		let writes = self.writes.iter().map(|(c, _, _)| c.as_str());
+		reads.chain(queries).chain(writes)
+	}
+}
+
+fn versions(documents: &[Document]) -> Vec<(String, Version)> {
+	documents
+		.iter()
+		.map(|d| (d.id.clone(), d.version.clone()))
+		.collect()
+}
+
+// 保存先に依存しない検索条件
+// 型の付いた組み立ては query モジュールで行い、ここでは Firestore のクエリとメモリ上での判定に変換する
+#[derive(Debug, Clone, PartialEq)]
+pub enum Filter {
+	Compare(&'static str, Operator, Value),
+	And(Vec<Filter>),
+	Or(Vec<Filter>),
+}
+
+// 比較の種類、意味は Firestore のものに合わせる
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operator {
+	Eq,
+	// フィールドが無いか null のドキュメントには一致しない
+	Ne,
+	// 大小の比較は同じ型の値どうしでだけ一致する
+	Lt,
+	Le,
+	Gt,
+	Ge,
+	// 値は配列、そのどれかと等しい
+	In,
+	// 値は配列、フィールドが null 以外でそのどれとも等しくない
+	NotIn,
+	// フィールドが配列で、値を要素に含む
+	ArrayContains,
+	// 値は配列、フィールドが配列でそのどれかを要素に含む
+	ArrayContainsAny,
+}
+
+impl Operator {
+	fn name(&self) -> &'static str {
+		match self {
+			Self::Eq => "==",
+			Self::Ne => "!=",
+			Self::Lt => "<",
+			Self::Le => "<=",
+			Self::Gt => ">",
+			Self::Ge => ">=",
+			Self::In => "in",
+			Self::NotIn => "not-in",
+			Self::ArrayContains => "array-contains",
+			Self::ArrayContainsAny => "array-contains-any",
+		}
+	}
+}
+
+impl Filter {
+	// value が JSON にできないのは呼び出し側の誤り、null として比べると違うドキュメントに一致するので止める
+	pub fn eq(field: &'static str, value: impl serde::Serialize) -> Self {
+		let value = serde_json::to_value(value)
+			.unwrap_or_else(|e| panic!("filter value for {field} is not serializable: {e}"));
+		Self::Compare(field, Operator::Eq, value)
+	}
+	pub fn and(filters: impl IntoIterator<Item = Filter>) -> Self {
+		Self::And(filters.into_iter().collect())
+	}
+	pub fn or(filters: impl IntoIterator<Item = Filter>) -> Self {
+		Self::Or(filters.into_iter().collect())
+	}
+	// id フィールドの条件だけで、ドキュメントIDが id のドキュメントには合わないと分かるか
+	// Collection のドキュメントは id フィールドにドキュメントIDを持つ
+	fn excludes_id(&self, id: &str) -> bool {
+		match self {
+			Self::Compare(field, Operator::Eq | Operator::In, _) if *field == "id" => {
+				!self.matches(&serde_json::json!({ "id": id }))
+			}
+			Self::Compare(..) => false,
+			Self::And(filters) => filters.iter().any(|f| f.excludes_id(id)),
+			Self::Or(filters) => filters.iter().all(|f| f.excludes_id(id)),
+		}
+	}
+	// Query::fingerprint に使う形、Debug の書式は変わりうるので使わない
+	fn canonical(&self) -> Value {
+		match self {
+			Self::Compare(field, operator, value) => {
+				serde_json::json!([field, operator.name(), value])
+			}
+			Self::And(filters) => {
+				serde_json::json!({ "and": filters.iter().map(Self::canonical).collect::<Vec<_>>() })
+			}
+			Self::Or(filters) => {
+				serde_json::json!({ "or": filters.iter().map(Self::canonical).collect::<Vec<_>>() })
+			}
+		}
+	}
+	fn firestore(&self, q: &FirestoreQueryFilterBuilder) -> Option<FirestoreQueryFilter> {
+		match self {
+			Self::Compare(field, operator, value) => {
+				let f = q.field(*field);
+				match operator {
+					Operator::Eq => f.eq(value),
+					Operator::Ne => f.not_equal(value),
+					Operator::Lt => f.less_than(value),
+					Operator::Le => f.less_than_or_equal(value),
+					Operator::Gt => f.greater_than(value),
+					Operator::Ge => f.greater_than_or_equal(value),
+					Operator::In => f.is_in(value),
+					Operator::NotIn => f.is_not_in(value),
+					Operator::ArrayContains => f.array_contains(value),
+					Operator::ArrayContainsAny => f.array_contains_any(value),
+				}
+			}
+			Self::And(filters) => q.for_all(filters.iter().map(|f| f.firestore(q))),
+			Self::Or(filters) => q.for_any(filters.iter().map(|f| f.firestore(q))),
+		}
+	}
+	fn matches(&self, document: &Value) -> bool {
+		match self {
+			Self::Compare(field, operator, value) => {
+				let Some(v) = lookup(document, field) else {
+					return false;
+				};
+				let values = || value.as_array().into_iter().flatten();
+				let elements = || v.as_array().into_iter().flatten();
+				match operator {
+					Operator::Eq => equals(v, value),
+					Operator::Ne => !v.is_null() && !equals(v, value),
+					Operator::Lt => comparable(v, value) && compare(Some(v), Some(value)).is_lt(),
+					Operator::Le => comparable(v, value) && compare(Some(v), Some(value)).is_le(),
+					Operator::Gt => comparable(v, value) && compare(Some(v), Some(value)).is_gt(),
+					Operator::Ge => comparable(v, value) && compare(Some(v), Some(value)).is_ge(),
+					Operator::In => values().any(|x| equals(v, x)),
+					Operator::NotIn => !v.is_null() && !values().any(|x| equals(v, x)),
+					Operator::ArrayContains => elements().any(|e| equals(e, value)),
+					Operator::ArrayContainsAny => {
+						elements().any(|e| values().any(|x| equals(e, x)))
+					}
+				}
+			}
+			Self::And(filters) => filters.iter().all(|f| f.matches(document)),
+			Self::Or(filters) => filters.iter().any(|f| f.matches(document)),
+		}
+	}
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum OrderBy {
+	Asc(&'static str),
+	Desc(&'static str),
+}
+
+impl OrderBy {
+	fn field(&self) -> &'static str {
+		match self {
+			Self::Asc(field) | Self::Desc(field) => field,
+		}
+	}
+	fn canonical(&self) -> Value {
+		match self {
+			Self::Asc(field) => serde_json::json!(["asc", field]),
+			Self::Desc(field) => serde_json::json!(["desc", field]),
+		}
+	}
+	fn apply(&self, ordering: std::cmp::Ordering) -> std::cmp::Ordering {
+		match self {
+			Self::Asc(_) => ordering,
+			Self::Desc(_) => ordering.reverse(),
+		}
+	}
+}
+
+impl From<&OrderBy> for FirestoreQueryOrder {
+	fn from(value: &OrderBy) -> Self {
+		match value {
+			OrderBy::Asc(field) => Self {
+				field_name: field.to_string(),
+				direction: FirestoreQueryDirection::Ascending,
+			},
+			OrderBy::Desc(field) => Self {
+				field_name: field.to_string(),
+				direction: FirestoreQueryDirection::Descending,
+			},
+		}
+	}
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Query {
+	pub filter: Option<Filter>,
+	// 前のものほど優先して並べ替える、同順位のものは最後の向きでドキュメントIDの順に並べる
+	pub order: Vec<OrderBy>,
+	// この位置より後ろから返す
+	pub cursor: Option<Cursor>,
+	pub limit: Option<u32>,
+}
+
+impl Query {
+	// https://note.com/etet_etet/n/n4ed0d6f59416
+	// collectionに対してlimitかけずに get() してしまうことで、コレクション内のドキュメント（開発環境だと数千レコード）がすべて read されてしまっていたのです。結果は配列の先頭のみ返すので、1件だけで良かったのに、毎回毎回ユーザーがアクセスするたびに数千件を引っ張り出して、最初の1件だけ返していたことが判明しました。
+	pub const DEFAULT_LIMIT: u32 = 100;
+	pub fn limit(&self) -> u32 {
+		self.limit.unwrap_or(Self::DEFAULT_LIMIT)
+	}
+	// order の後ろに同順位を決めるドキュメントIDの並びを足したもの
+	// Firestore は order-by の最後と同じ向きで __name__ を暗黙に足すので、それに合わせる
+	fn order_with_name(&self) -> Vec<OrderBy> {
+		let name = match self.order.last() {
+			Some(OrderBy::Desc(_)) => OrderBy::Desc("__name__"),
+			_ => OrderBy::Asc("__name__"),
+		};
+		self.order.iter().cloned().chain([name]).collect()
+	}
+	// 同じ条件で並び順も同じクエリかどうかを見分けるための値、カーソルを別のクエリに使わせない
+	// ツールチェーンを変えても配ったカーソルが使えるように、決まった形の JSON にして SHA-256 を取る
+	fn fingerprint(&self, collection: &str) -> String {
+		let canonical = serde_json::json!([
+			collection,
+			self.filter.as_ref().map(Filter::canonical),
+			self.order
+				.iter()
+				.map(OrderBy::canonical)
+				.collect::<Vec<_>>(),
+		]);
+		let digest = ring::digest::digest(&ring::digest::SHA256, canonical.to_string().as_bytes());
+		digest.as_ref().iter().map(|b| format!("{b:02x}")).collect()
+	}
+}
+
+// クエリの並び順の中での位置、order の各フィールドの値とドキュメントID
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Cursor {
+	pub values: Vec<Value>,
+	pub id: String,
+}
+
+// カーソルの署名に使う鍵、起動するときに Cursor::init で設定から入れる
+// 入れていなければプロセスごとに作った鍵を使う、メモリ上やエミュレータで動かすときとテスト用で、他のプロセスが作ったカーソルは使えない
+static CURSOR_SECRET: OnceLock<Vec<u8>> = OnceLock::new();
+
+impl Cursor {
+	// 署名の鍵を入れる、最初のカーソルを作るより前に1度だけ呼ぶ
+	pub fn init(secret: Vec<u8>) -> Result<(), Error> {
+		CURSOR_SECRET
+			.set(secret)
+			.map_err(|_| Error::Internal("cursor secret is already set".to_string()))
+	}
+	fn secret() -> &'static [u8] {
+		CURSOR_SECRET.get_or_init(|| {
+			let mut secret = vec![0; 32];
+			ring::rand::SecureRandom::fill(&ring::rand::SystemRandom::new(), &mut secret)
+				.expect("cannot generate cursor secret");
+			secret
+		})
+	}
+	// document の位置
+	pub fn of(document: &Document, order: &[OrderBy]) -> Self {
+		Self {
+			values: order
+				.iter()
+				.map(|o| {
+					lookup(&document.data, o.field())
+						.cloned()
+						.unwrap_or_default()
+				})
+				.collect(),
+			id: document.id.clone(),
+		}
+	}
+	// クライアントに渡す文字列にする、署名するので書き換えると from_token で弾かれる
+	pub fn token(&self, collection: &str, query: &Query) -> String {
+		let claims = CursorClaims {
+			query: query.fingerprint(collection),
+			values: self.values.clone(),
+			id: self.id.clone(),
+		};
+		jsonwebtoken::encode(
+			&jsonwebtoken::Header::default(),
+			&claims,
+			&jsonwebtoken::EncodingKey::from_secret(Self::secret()),
+		)
+		.unwrap_or_default()
+	}
+	// token で作った文字列を戻す、改ざんされたものや別のクエリのものは Invalid
+	pub fn from_token(token: &str, collection: &str, query: &Query) -> Result<Self, Error> {
+		let mut validation = jsonwebtoken::Validation::new(jsonwebtoken::Algorithm::HS256);
+		// カーソルに有効期限は無い
+		validation.required_spec_claims.clear();
+		validation.validate_exp = false;
+		let claims = jsonwebtoken::decode::<CursorClaims>(
+			token,
+			&jsonwebtoken::DecodingKey::from_secret(Self::secret()),
+			&validation,
+		)
+		.map_err(|e| Error::Invalid(format!("invalid cursor: {e}")))?
+		.claims;
+		if claims.query != query.fingerprint(collection) || claims.values.len() != query.order.len()
+		{
+			return Err(Error::Invalid(
+				"invalid cursor: issued for another query".to_string(),
+			));
+		}
+		Ok(Self {
+			values: claims.values,
+			id: claims.id,
+		})
+	}
+	// order_with_name の並びで比べる
+	fn compare(&self, other: &Self, order: &[OrderBy]) -> std::cmp::Ordering {
+		self.values
+			.iter()
+			.zip(&other.values)
+			.zip(order)
+			.map(|((a, b), o)| o.apply(compare(Some(a), Some(b))))
+			.chain(order.last().map(|o| o.apply(self.id.cmp(&other.id))))
+			.find(|o| o.is_ne())
+			.unwrap_or(std::cmp::Ordering::Equal)
+	}
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct CursorClaims {
+	query: String,
+	values: Vec<Value>,
+	id: String,
+}
+
+// collection の query に合うドキュメントをすべて、並び順のとおりに1つずつ返す
+// query の limit を1ページの件数として読み、受け取る側がページを使い切ってから次のページを読む
+// 読み出しに失敗したらそのエラーを返して終わる、Collection::stream はこれを型に戻したもの
+pub fn stream(
+	db: &impl Storage,
+	collection: String,
+	query: Query,
+) -> impl futures::Stream<Item = Result<Document, Error>> + Send + '_ {
+	struct State {
+		collection: String,
+		query: Query,
+		buffer: VecDeque<Document>,
+		done: bool,
+	}
+	let state = State {
+		collection,
+		query,
+		buffer: VecDeque::new(),
+		done: false,
+	};
+	futures::stream::unfold(state, move |mut state| async move {
+		loop {
+			if let Some(document) = state.buffer.pop_front() {
+				return Some((Ok(document), state));
+			}
+			if state.done {
+				return None;
+			}
+			match db.query(&state.collection, &state.query).await {
+				Ok(documents) => {
+					// 1ページに満たなければ続きは無い
+					state.done = documents.len() < state.query.limit() as usize;
+					if let Some(last) = documents.last() {
+						state.query.cursor = Some(Cursor::of(last, &state.query.order));
+					}
+					state.buffer.extend(documents);
+				}
+				Err(e) => {
+					state.done = true;
+					return Some((Err(e), state));
+				}
+			}
+		}
+	})
+}
+
+#[derive(Clone)]
+pub struct Firestore {
+	db: FirestoreDb,
+	changes: Changes,
+	// watch しているコレクションの listener、落とすと止まるので持っておく
+	listeners: Arc<Mutex<Vec<FirestoreListener<FirestoreDb, FirestoreMemListenStateStorage>>>>,
+}
+
+impl Firestore {
+	pub fn new(db: FirestoreDb) -> Self {
+		Self {
+			db,
+			changes: Default::default(),
+			listeners: Default::default(),
+		}
+	}
+	// host (localhost:8081 など) で動いている Firestore エミュレータに繋ぐ、認証情報は要らない
+	// エミュレータは project ごとにデータを分けるので、テストでは毎回別の project にする
+	pub async fn emulator(host: &str, project: &str) -> Result<Self, Error> {
+		let options = firestore::FirestoreDbOptions::new(project.to_string())
+			.with_firebase_api_url(format!("http://{host}"));
+		Ok(Self::new(FirestoreDb::with_options(options).await?))
+	}
+	// テスト用、FIRESTORE_EMULATOR_HOST のエミュレータに新しい project で繋ぐ
+	// 設定されていないか繋がらなければ None、呼んだテストは何もせずに通る
+	#[cfg(test)]
+	pub async fn for_test() -> Option<Self> {
+		let Ok(host) = std::env::var("FIRESTORE_EMULATOR_HOST") else {
+			eprintln!("skip: FIRESTORE_EMULATOR_HOST is not set");
+			return None;
+		};
+		let connect = tokio::net::TcpStream::connect(&host);
+		if !matches!(
+			tokio::time::timeout(std::time::Duration::from_secs(1), connect).await,
+			Ok(Ok(_))
+		) {
+			eprintln!("skip: no Firestore emulator at {host}");
+			return None;
+		}
+		let project = format!("test-{}", uuid::Uuid::now_v7().simple());
+		Some(Self::emulator(&host, &project).await.unwrap())
+	}
+	// obj() で serde_json::Value として読むと、_firestore_id や _firestore_updated などの
+	// メタデータもフィールドとして付いてくるので、それを取り除いて Document にする
+	fn document(data: Value) -> Result<Document, Error> {
+		Self::document_with_id(data, "_firestore_id")
+	}
+	// コレクショングループのクエリでは、id をルートからのパスにする
+	fn group_document(&self, data: Value) -> Result<Document, Error> {
+		let mut document = Self::document_with_id(data, "_firestore_full_id")?;
+		let prefix = format!("{}/", self.db.get_documents_path());
+		if let Some(path) = document.id.strip_prefix(&prefix) {
+			document.id = path.to_string();
+		}
+		Ok(document)
+	}
+	fn document_with_id(mut data: Value, id: &str) -> Result<Document, Error> {
+		let map = data
+			.as_object_mut()
+			.ok_or(Error::Internal("document is not a map".to_string()))?;
+		let id = map
+			.get(id)
+			.and_then(Value::as_str)
+			.unwrap_or_default()
+			.to_string();
+		let version = match map.get("_firestore_updated").and_then(Value::as_str) {
+			Some(t) => Version::firestore(t)?,
+			None => Version::default(),
+		};
+		map.retain(|k, _| !k.starts_with("_firestore_"));
+		Ok(Document { id, version, data })
+	}
+	// "page/{id}/comment" のようなコレクションのパスを、親ドキュメントのフルパスとコレクションIDに分ける
+	// 親の無いコレクションの親はデータベースのルート
+	fn split<'a>(&self, collection: &'a str) -> (String, &'a str) {
+		let root = self.db.get_documents_path();
+		match collection.rsplit_once('/') {
+			Some((parent, id)) => (format!("{root}/{parent}"), id),
+			None => (root.clone(), collection),
+		}
+	}
+	// parent の下の collection_id に対するクエリ、group なら parent の下のすべての collection_id が対象
+	// カーソルの id は group なら parent からのパス、そうでなければドキュメントID
+	async fn select(
+		&self,
+		parent: &str,
+		collection_id: &str,
+		query: &Query,
+		group: bool,
+	) -> Result<Vec<Value>, Error> {
+		let builder = self.db.fluent().select().from(collection_id).parent(parent);
+		let builder = if group {
+			builder.all_descendants()
+		} else {
+			builder
+		};
+		let builder = builder.filter(|q| query.filter.as_ref().and_then(|f| f.firestore(&q)));
+		//特定のフィールドを基準にクエリを並べ替えると、order-by フィールドが存在するドキュメントのみを返すことができます。
+		//最後に __name__ で並べるので同順位のドキュメントがあってもページ境界で抜けや重複は起きない
+		let builder = builder.order_by(query.order_with_name().iter());
+		let builder = if let Some(cursor) = &query.cursor {
+			let mut values: Vec<_> = cursor.values.iter().map(|v| v.into()).collect();
+			// __name__ の値はドキュメントのパスを参照型で渡す
+			let path = if group {
+				format!("{parent}/{}", cursor.id)
+			} else {
+				format!("{parent}/{collection_id}/{}", cursor.id)
+			};
+			values.push(FirestoreReference(path).into());
+			builder.start_at(FirestoreQueryCursor::AfterValue(values))
+		} else {
+			builder
+		};
+		Ok(builder.limit(query.limit()).obj().query().await?)
+	}
+	// collection の filter に合うドキュメントの集計、sum があればその合計、無ければ数
+	// Firestore の集計クエリはドキュメントを返さないので、1行だけの結果の value を読む
+	async fn aggregate(
+		&self,
+		collection: &str,
+		filter: Option<&Filter>,
+		sum: Option<&str>,
+	) -> Result<Value, Error> {
+		let (parent, collection_id) = self.split(collection);
+		let rows: Vec<Value> = self
+			.db
+			.fluent()
+			.select()
+			.from(collection_id)
+			.parent(&parent)
+			.filter(|q| filter.and_then(|f| f.firestore(&q)))
+			.aggregate(|a| {
+				a.fields([match sum {
+					Some(field) => a.field("value").sum(field),
+					None => a.field("value").count(),
+				}])
+			})
+			.obj()
+			.query()
+			.await?;
+		Ok(rows
+			.into_iter()
+			.next()
+			.and_then(|mut row| row.get_mut("value").map(Value::take))
+			.unwrap_or_default())
+	}
+	// collection の変更を受け取り始めて changes に流す
+	// 始めた時点で既にあるドキュメントも送られてくるので、最初にすべてのターゲットが揃った時点 (read_time) までのものは捨てる
+	// 版は Firestore の時刻なので、手元の時計とのずれで変更を落としたり古いものを流したりしない
+	async fn listen(&self, collection: &str) -> Result<(), Error> {
+		let mut listener = self
+			.db
+			.create_listener(FirestoreMemListenStateStorage::new())
+			.await?;
+		let (parent, collection_id) = self.split(collection);
+		self.db
+			.fluent()
+			.select()
+			.from(collection_id)
+			.parent(&parent)
+			.listen()
+			.add_target(FirestoreListenerTarget::new(1), &mut listener)?;
+		let changes = self.changes.clone();
+		let collection = collection.to_string();
+		listener
+			.start(move |event| {
+				let changes = changes.clone();
+				let collection = collection.clone();
+				async move {
+					let started = changes.started(&collection);
+					let change = match event {
+						// target_ids が空の read_time はストリーム全体が揃った時点、最初のものから後が変更
+						FirestoreListenEvent::TargetChange(t) if t.target_ids.is_empty() => {
+							if let Some(version) = t
+								.read_time
+								.and_then(|t| Version::from_timestamp(t.seconds, t.nanos))
+							{
+								changes.start(&collection, version);
+							}
+							None
+						}
+						_ if !started => None,
+						FirestoreListenEvent::DocumentChange(c) => match c.document {
+							Some(d) => Some(Change::Put(Self::document(
+								FirestoreDb::deserialize_doc_to(&d)?,
+							)?)),
+							None => None,
+						},
+						FirestoreListenEvent::DocumentDelete(d) => Some(Self::deleted(
+							&d.document,
+							d.read_time.map(|t| (t.seconds, t.nanos)),
+						)),
+						FirestoreListenEvent::DocumentRemove(d) => Some(Self::deleted(
+							&d.document,
+							d.read_time.map(|t| (t.seconds, t.nanos)),
+						)),
+						_ => None,
+					};
+					if let Some(change) = change {
+						changes.publish(&collection, change);
+					}
+					Ok(())
+				}
+			})
+			.await?;
+		self.listeners.lock()?.push(listener);
+		Ok(())
+	}
+	// document は "projects/.../documents/{collection}/{id}" 形式
+	fn deleted(document: &str, read_time: Option<(i64, i32)>) -> Change {
+		let id = document.rsplit('/').next().unwrap_or_default();
+		let version = read_time
+			.and_then(|(seconds, nanos)| Version::from_timestamp(seconds, nanos))
+			.unwrap_or_default();
+		Change::Delete(id.to_string(), version)
+	}
+	fn precondition(write: &Write) -> Result<Option<FirestoreWritePrecondition>, Error> {
+		Ok(match &write.precondition {
+			Precondition::None => None,
+			Precondition::Exists => Some(FirestoreWritePrecondition::Exists(true)),
+			Precondition::Missing => Some(FirestoreWritePrecondition::Exists(false)),
+			Precondition::Version(v) => {
+				Some(FirestoreWritePrecondition::UpdateTime(v.to_firestore()?))
+			}
+		})
+	}
+}
+
+impl Storage for Firestore {
+	async fn get(&self, collection: &str, document_id: &str) -> Result<Option<Document>, Error> {
+		let (parent, collection) = self.split(collection);
+		let v: Option<Value> = self
+			.db
+			.fluent()
+			.select()
+			.by_id_in(collection)
+			.parent(&parent)
+			.obj()
+			.one(document_id)
+			.await?;
+		v.map(Self::document).transpose()
+	}
+	async fn write(
+		&self,
+		collection: &str,
+		document_id: &str,
+		data: &Value,
+		write: &Write,
+	) -> Result<Version, Error> {
+		let (parent, collection) = self.split(collection);
+		if write.precondition == Precondition::Missing {
+			let v: Value = self
+				.db
+				.fluent()
+				.insert()
+				.into(collection)
+				.document_id(document_id)
+				.parent(&parent)
+				.object(data)
+				.execute()
+				.await?;
+			return Ok(Self::document(v)?.version);
+		}
+		// Firestore の update は前提条件が無ければ upsert として振る舞う
+		let builder = self.db.fluent().update();
+		let builder = if let Some(mask) = &write.mask {
+			builder.fields(mask)
+		} else {
+			builder
+		};
+		let builder = builder.in_col(collection);
+		let builder = match Self::precondition(write)? {
+			Some(p) => builder.precondition(p),
+			None => builder,
+		};
+		let v: Value = builder
+			.document_id(document_id)
+			.parent(&parent)
+			.object(data)
+			.execute()
+			.await
+			.map_err(Error::from_write)?;
+		Ok(Self::document(v)?.version)
+	}
+	async fn query(&self, collection: &str, query: &Query) -> Result<Vec<Document>, Error> {
+		let (parent, collection_id) = self.split(collection);
+		let v = self.select(&parent, collection_id, query, false).await?;
+		v.into_iter().map(Self::document).collect()
+	}
+	async fn query_group(
+		&self,
+		collection_id: &str,
+		query: &Query,
+	) -> Result<Vec<Document>, Error> {
+		let root = self.db.get_documents_path().clone();
+		let v = self.select(&root, collection_id, query, true).await?;
+		v.into_iter().map(|v| self.group_document(v)).collect()
+	}
+	async fn count(&self, collection: &str, filter: Option<&Filter>) -> Result<u64, Error> {
+		let value = self.aggregate(collection, filter, None).await?;
+		Ok(value.as_u64().unwrap_or_default())
+	}
+	async fn sum(
+		&self,
+		collection: &str,
+		filter: Option<&Filter>,
+		field: &str,
+	) -> Result<f64, Error> {
+		// 整数だけなら整数、小数が混ざれば小数で返ってくる
+		let value = self.aggregate(collection, filter, Some(field)).await?;
+		Ok(value.as_f64().unwrap_or_default())
+	}
+	async fn delete(&self, collection: &str, document_id: &str) -> Result<(), Error> {
+		let (parent, collection) = self.split(collection);
+		self.db
+			.fluent()
+			.delete()
+			.from(collection)
+			.document_id(document_id)
+			.parent(&parent)
+			.execute()
+			.await
+			.map_err(Error::from)
+	}
+	async fn increment(
+		&self,
+		collection: &str,
+		document_id: &str,
+		field: &str,
+		by: i64,
+	) -> Result<(), Error> {
+		let (parent, collection) = self.split(collection);
+		let _: Value = self
+			.db
+			.fluent()
+			.update()
+			.in_col(collection)
+			.document_id(document_id)
+			.parent(&parent)
+			.transforms(|t| t.fields([t.field(field).increment(by)]))
+			.only_transform()
+			.execute()
+			.await?;
+		Ok(())
+	}
+	async fn commit(&self, transaction: &Transaction) -> Result<(), Error> {
+		let mut tx = self.db.begin_transaction().await?;
+		// 読み直しをこのトランザクションの中で行うと、読んだドキュメントは commit まで他から書き換えられない
+		let db = Firestore::new(self.db.clone_with_consistency_selector(
+			FirestoreConsistencySelector::Transaction(tx.transaction_id().clone()),
+		));
+		if let Err(e) = transaction.verify(&db).await {
+			// 競合を返すので、ロールバックの失敗は無視する
+			let _ = tx.rollback().await;
+			return Err(e);
+		}
+		for (collection, document_id, data) in &transaction.writes {
+			let (parent, collection) = self.split(collection);
+			match data {
+				Some((data, write)) => {
+					// insert はトランザクションに入れられないので、無いことを前提条件にした update で作る
+					let builder = self.db.fluent().update();
+					let builder = if let Some(mask) = &write.mask {
+						builder.fields(mask)
+					} else {
+						builder
+					};
+					let builder = builder.in_col(collection);
+					let builder = match Self::precondition(write)? {
+						Some(p) => builder.precondition(p),
+						None => builder,
+					};
+					builder
+						.document_id(document_id)
+						.parent(&parent)
+						.object(data)
+						.add_to_transaction(&mut tx)?;
+				}
+				None => {
+					self.db
+						.fluent()
+						.delete()
+						.from(collection)
+						.document_id(document_id)
+						.parent(&parent)
+						.add_to_transaction(&mut tx)?;
+				}
+			}
+		}
+		tx.commit().await.map_err(Error::from_write)?;
+		Ok(())
+	}
+	async fn watch(
+		&self,
+		collection: &str,
+		filter: Option<Filter>,
+		after: Option<Version>,
+	) -> Result<Watch, Error> {
+		// 受け取り始めた時点の版は listen が Firestore から受け取って入れる
+		let (watch, created) = self.changes.subscribe(collection, None, filter, after)?;
+		if created && let Err(e) = self.listen(collection).await {
+			// 次の watch でやり直せるように忘れる
+			self.changes.remove(collection);
+			return Err(e);
+		}
+		Ok(watch)
+	}
+}
+
+// テストやローカル開発用のメモリ上の保存先、プロセスが終われば消える
+// Clone したものは同じデータを共有する
+type Collections = HashMap<String, BTreeMap<String, Document>>;
+
+#[derive(Clone, Default)]
+pub struct Memory {
+	collections: Arc<Mutex<Collections>>,
+	clock: Arc<AtomicU64>,
+	changes: Changes,
+}
+
+impl Memory {
+	pub fn new() -> Self {
+		Self::default()
+	}
+	fn next_version(&self) -> Version {
+		Self::version(self.clock.fetch_add(1, Ordering::SeqCst) + 1)
+	}
+	fn version(clock: u64) -> Version {
+		Version(format!("{clock:020}"))
+	}
+	fn put(
+		&self,
+		collections: &mut Collections,
+		collection: &str,
+		document_id: &str,
+		data: &Value,
+		write: &Write,
+	) -> Result<Document, Error> {
+		let documents = collections.entry(collection.to_string()).or_default();
+		let current = documents.get(document_id);
+		match (&write.precondition, current) {
+			(Precondition::Missing, Some(_)) => {
+				return Err(Error::AlreadyExists(format!(
+					"document already exists: {collection}/{document_id}"
+				)));
+			}
+			(Precondition::Exists | Precondition::Version(_), None) => {
+				return Err(Error::NotFound(format!(
+					"document not found: {collection}/{document_id}"
+				)));
+			}
+			(Precondition::Version(v), Some(current)) if *v != current.version => {
+				return Err(Error::Conflict(format!(
+					"document {collection}/{document_id} was modified: expected version {v}, found {}",
+					current.version
+				)));
+			}
+			_ => {}
+		}
+		let next = match &write.mask {
+			Some(mask) => {
+				let mut next = current
+					.map(|d| d.data.clone())
+					.unwrap_or_else(|| Value::Object(Default::default()));
+				for path in mask {
+					assign(&mut next, path, lookup(data, path).cloned());
+				}
+				next
+			}
+			None => data.clone(),
+		};
+		let document = Document {
+			id: document_id.to_string(),
+			version: self.next_version(),
+			data: next,
+		};
+		documents.insert(document_id.to_string(), document.clone());
+		Ok(document)
+	}
+	// 並べずに filter に合うものだけを返す、集計用
+	fn filtered<'a>(
+		collections: &'a Collections,
+		collection: &str,
+		filter: Option<&'a Filter>,
+	) -> impl Iterator<Item = &'a Document> {
+		collections
+			.get(collection)
+			.into_iter()
+			.flat_map(|c| c.values())
+			.filter(move |d| filter.is_none_or(|f| f.matches(&d.data)))
+	}
+	fn select(collections: &Collections, collection: &str, query: &Query) -> Vec<Document> {
+		let documents = collections
+			.get(collection)
+			.into_iter()
+			.flat_map(|c| c.values());
+		Self::sort(documents.cloned(), query)
+	}
+	fn select_group(
+		collections: &Collections,
+		collection_id: &str,
+		query: &Query,
+	) -> Vec<Document> {
+		let documents = collections
+			.iter()
+			.filter(|(path, _)| path.rsplit('/').next() == Some(collection_id))
+			.flat_map(|(path, documents)| {
+				documents.values().map(move |d| Document {
+					id: format!("{path}/{}", d.id),
+					..d.clone()
+				})
+			});
+		Self::sort(documents, query)
+	}
+	fn sort(documents: impl Iterator<Item = Document>, query: &Query) -> Vec<Document> {
+		let order = query.order_with_name();
+		// Firestore と同じく order-by フィールドが存在するドキュメントのみ返す
+		let mut output: Vec<(Cursor, Document)> = documents
+			.filter(|d| query.filter.as_ref().is_none_or(|f| f.matches(&d.data)))
+			.filter(|d| {
+				query
+					.order
+					.iter()
+					.all(|o| lookup(&d.data, o.field()).is_some())
+			})
+			.map(|d| (Cursor::of(&d, &query.order), d))
+			.collect();
+		output.sort_by(|(a, _), (b, _)| a.compare(b, &order));
+		if let Some(cursor) = &query.cursor {
+			output.retain(|(c, _)| c.compare(cursor, &order).is_gt());
+		}
+		output
+			.into_iter()
+			.take(query.limit() as usize)
+			.map(|(_, d)| d)
+			.collect()
+	}
+}
+
+impl Storage for Memory {
+	async fn get(&self, collection: &str, document_id: &str) -> Result<Option<Document>, Error> {
+		let collections = self.collections.lock()?;
+		Ok(collections
+			.get(collection)
+			.and_then(|c| c.get(document_id))
+			.cloned())
+	}
+	async fn write(
+		&self,
+		collection: &str,
+		document_id: &str,
+		data: &Value,
+		write: &Write,
+	) -> Result<Version, Error> {
+		let mut collections = self.collections.lock()?;
+		let document = self.put(&mut collections, collection, document_id, data, write)?;
+		let version = document.version.clone();
+		self.changes.publish(collection, Change::Put(document));
+		Ok(version)
+	}
+	async fn query(&self, collection: &str, query: &Query) -> Result<Vec<Document>, Error> {
+		let collections = self.collections.lock()?;
+		Ok(Self::select(&collections, collection, query))
+	}
+	async fn query_group(
+		&self,
+		collection_id: &str,
+		query: &Query,
+	) -> Result<Vec<Document>, Error> {
+		let collections = self.collections.lock()?;
+		Ok(Self::select_group(&collections, collection_id, query))
+	}
+	async fn count(&self, collection: &str, filter: Option<&Filter>) -> Result<u64, Error> {
+		let collections = self.collections.lock()?;
+		Ok(Self::filtered(&collections, collection, filter).count() as u64)
+	}
+	async fn sum(
+		&self,
+		collection: &str,
+		filter: Option<&Filter>,
+		field: &str,
+	) -> Result<f64, Error> {
+		let collections = self.collections.lock()?;
+		Ok(Self::filtered(&collections, collection, filter)
+			.filter_map(|d| lookup(&d.data, field).and_then(Value::as_f64))
+			.sum())
+	}
+	async fn delete(&self, collection: &str, document_id: &str) -> Result<(), Error> {
+		let mut collections = self.collections.lock()?;
+		let removed = collections
+			.get_mut(collection)
+			.and_then(|documents| documents.remove(document_id));
+		if removed.is_some() {
+			let change = Change::Delete(document_id.to_string(), self.next_version());
+			self.changes.publish(collection, change);
+		}
+		Ok(())
+	}
+	async fn increment(
+		&self,
+		collection: &str,
+		document_id: &str,
+		field: &str,
+		by: i64,
+	) -> Result<(), Error> {
+		let mut collections = self.collections.lock()?;
+		let mut data = collections
+			.get(collection)
+			.and_then(|c| c.get(document_id))
+			.map(|d| d.data.clone())
+			.unwrap_or_else(|| Value::Object(Default::default()));
+		let n = lookup(&data, field)
+			.and_then(Value::as_i64)
+			.unwrap_or_default()
+			+ by;
+		assign(&mut data, field, Some(n.into()));
+		let document = self.put(
+			&mut collections,
+			collection,
+			document_id,
+			&data,
+			&Write::default(),
+		)?;
+		self.changes.publish(collection, Change::Put(document));
+		Ok(())
+	}
+	async fn commit(&self, transaction: &Transaction) -> Result<(), Error> {
+		// 確かめてから書き込むまでロックを持ち続けるので、その間に他から書き換えられることはない
+		let mut collections = self.collections.lock()?;
+		for (collection, document_id, version) in &transaction.reads {
+			let current = collections
+				.get(collection)
+				.and_then(|c| c.get(document_id))
+				.map(|d| &d.version);
+			if current != version.as_ref() {
+				return Err(Transaction::conflict(collection, document_id));
+			}
+		}
+		for (collection, query, documents) in &transaction.queries {
+			if versions(&Self::select(&collections, collection, query)) != *documents {
+				return Err(Transaction::conflict(collection, "(query)"));
+			}
+		}
+		// 途中の書き込みが前提条件で失敗したら何も反映しないように、写しに書き込んでから差し替える
+		let mut next = collections.clone();
+		let mut changes = vec![];
+		for (collection, document_id, data) in &transaction.writes {
+			match data {
+				Some((data, write)) => {
+					let document = self.put(&mut next, collection, document_id, data, write)?;
+					changes.push((collection, Change::Put(document)));
+				}
+				None => {
+					let removed = next
+						.get_mut(collection)
+						.and_then(|documents| documents.remove(document_id));
+					if removed.is_some() {
+						let change = Change::Delete(document_id.to_string(), self.next_version());
+						changes.push((collection, change));
+					}
+				}
+			}
+		}
+		*collections = next;
+		for (collection, change) in changes {
+			self.changes.publish(collection, change);
+		}
+		Ok(())
+	}
+	async fn watch(
+		&self,
+		collection: &str,
+		filter: Option<Filter>,
+		after: Option<Version>,
+	) -> Result<Watch, Error> {
+		// 書き込みと同じロックの中で始めるので、since と覚えている変更の間に抜けは無い
+		let _collections = self.collections.lock()?;
+		let since = Self::version(self.clock.load(Ordering::SeqCst));
+		Ok(self
+			.changes
+			.subscribe(collection, Some(since), filter, after)?
+			.0)
+	}
+}
+
+// "a.b.c" 形式のフィールドパスで値を取り出す
+fn lookup<'a>(document: &'a Value, path: &str) -> Option<&'a Value> {
+	path.split('.').try_fold(document, |v, key| v.get(key))
+}
+
+// "a.b.c" 形式のフィールドパスに値を書き込む、None なら削除する
+fn assign(document: &mut Value, path: &str, value: Option<Value>) {
+	let (parent, key) = match path.rsplit_once('.') {
+		Some((parent, key)) => (Some(parent), key),
+		None => (None, path),
+	};
+	let mut target = document;
+	for k in parent.into_iter().flat_map(|p| p.split('.')) {
+		if !target.get(k).is_some_and(Value::is_object) {
+			if value.is_none() {
+				return;
+			}
+			target[k] = Value::Object(Default::default());
+		}
+		target = &mut target[k];
+	}
+	match (target.as_object_mut(), value) {
+		(Some(map), Some(value)) => {
+			map.insert(key.to_string(), value);
+		}
+		(Some(map), None) => {
+			map.remove(key);
+		}
+		(None, _) => {}
+	}
+}
+
+// Firestore の型順序(null < bool < 数値 < 文字列 < 配列 < マップ)に倣った比較
+fn compare(a: Option<&Value>, b: Option<&Value>) -> std::cmp::Ordering {
+	use std::cmp::Ordering;
+	fn rank(v: &Value) -> u8 {
+		match v {
+			Value::Null => 0,
+			Value::Bool(_) => 1,
+			Value::Number(_) => 2,
+			Value::String(_) => 3,
+			Value::Array(_) => 4,
+			Value::Object(_) => 5,
+		}
+	}
+	match (a, b) {
+		(None, None) => Ordering::Equal,
+		(None, Some(_)) => Ordering::Less,
+		(Some(_), None) => Ordering::Greater,
+		(Some(a), Some(b)) => match (a, b) {
+			(Value::Bool(a), Value::Bool(b)) => a.cmp(b),
+			(Value::Number(a), Value::Number(b)) => a
+				.as_f64()
+				.unwrap_or_default()
+				.total_cmp(&b.as_f64().unwrap_or_default()),
+			(Value::String(a), Value::String(b)) => a.cmp(b),
+			(Value::Array(a), Value::Array(b)) => a
+				.iter()
+				.zip(b.iter())
+				.map(|(x, y)| compare(Some(x), Some(y)))
+				.find(|o| o.is_ne())
+				.unwrap_or_else(|| a.len().cmp(&b.len())),
+			_ => rank(a).cmp(&rank(b)),
+		},
+	}
+}
+
+// 数値は整数と小数を区別せずに比べる
+fn equals(a: &Value, b: &Value) -> bool {
+	match (a, b) {
+		(Value::Number(_), Value::Number(_)) => compare(Some(a), Some(b)).is_eq(),
+		(Value::Array(a), Value::Array(b)) => {
+			a.len() == b.len() && a.iter().zip(b).all(|(x, y)| equals(x, y))
+		}
+		_ => a == b,
+	}
+}
+
+// 大小を比べてよい組み合わせか、Firestore は違う型の値どうしの大小比較には一致させない
+fn comparable(a: &Value, b: &Value) -> bool {
+	matches!(
+		(a, b),
+		(Value::Number(_), Value::Number(_))
+			| (Value::String(_), Value::String(_))
+			| (Value::Bool(_), Value::Bool(_))
+			| (Value::Array(_), Value::Array(_))
+	)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use serde_json::json;
+
+	const INSERT: Write = Write {
+		precondition: Precondition::Missing,
+		mask: None,
+	};
+
+	#[tokio::test]
+	async fn test_memory_insert_get_delete() {
+		let db = Memory::new();
+		db.write("user", "a", &json!({"id": "a", "name": "Alice"}), &INSERT)
+			.await
+			.unwrap();
+		assert!(matches!(
+			db.write("user", "a", &json!({}), &INSERT).await,
+			Err(Error::AlreadyExists(_))
+		));
+		assert_eq!(
+			db.get("user", "a").await.unwrap().map(|d| d.data),
+			Some(json!({"id": "a", "name": "Alice"}))
+		);
+		db.delete("user", "a").await.unwrap();
+		assert_eq!(db.get("user", "a").await.unwrap().map(|d| d.data), None);
+	}
+
+	#[test]
+	#[should_panic(expected = "not serializable")]
+	fn test_filter_unserializable() {
+		// キーが文字列でないマップは JSON にできない
+		Filter::eq("key", std::collections::BTreeMap::from([((1, 2), 3)]));
+	}
+
+	#[tokio::test]
+	async fn test_memory_query_filter_order_cursor() {
+		let db = Memory::new();
+		for (id, group, score) in [("a", "x", 3), ("b", "x", 1), ("c", "y", 2), ("d", "x", 2)] {
+			db.write(
+				"page",
+				id,
+				&json!({"id": id, "group": group, "score": score}),
+				&INSERT,
+			)
+			.await
+			.unwrap();
+		}
+		let ids = |v: Vec<Document>| -> Vec<String> { v.into_iter().map(|d| d.id).collect() };
+		let query = Query {
+			filter: Some(Filter::eq("group", "x")),
+			order: vec![OrderBy::Desc("score")],
+			..Default::default()
+		};
+		assert_eq!(
+			ids(db.query("page", &query).await.unwrap()),
+			["a", "d", "b"]
+		);
+		let query = Query {
+			cursor: Some(Cursor {
+				values: vec![json!(3)],
+				id: "a".to_string(),
+			}),
+			limit: Some(1),
+			..query
+		};
+		assert_eq!(ids(db.query("page", &query).await.unwrap()), ["d"]);
+		// 同順位はドキュメントIDで並び、カーソルのIDより後ろから続く
+		let query = Query {
+			order: vec![OrderBy::Asc("score")],
+			cursor: Some(Cursor {
+				values: vec![json!(2)],
+				id: "c".to_string(),
+			}),
+			..Default::default()
+		};
+		assert_eq!(ids(db.query("page", &query).await.unwrap()), ["d", "a"]);
+	}
+
+	#[test]
+	fn test_filter_matches() {
+		let doc = json!({"n": 2, "s": "b", "tags": ["x", "y"], "m": {"k": 1.0}});
+		let f = |field, operator, value| Filter::Compare(field, operator, value);
+		assert!(f("n", Operator::Eq, json!(2.0)).matches(&doc));
+		assert!(f("m.k", Operator::Eq, json!(1)).matches(&doc));
+		assert!(f("n", Operator::Ne, json!(3)).matches(&doc));
+		assert!(!f("none", Operator::Ne, json!(3)).matches(&doc));
+		assert!(f("n", Operator::Ge, json!(2)).matches(&doc));
+		assert!(!f("n", Operator::Lt, json!("a")).matches(&doc));
+		assert!(f("s", Operator::Lt, json!("c")).matches(&doc));
+		assert!(f("s", Operator::In, json!(["a", "b"])).matches(&doc));
+		assert!(!f("s", Operator::NotIn, json!(["a", "b"])).matches(&doc));
+		assert!(f("tags", Operator::ArrayContains, json!("y")).matches(&doc));
+		assert!(f("tags", Operator::ArrayContainsAny, json!(["z", "x"])).matches(&doc));
+		assert!(!f("s", Operator::ArrayContains, json!("b")).matches(&doc));
+		assert!(
+			Filter::or([
+				f("n", Operator::Eq, json!(1)),
+				Filter::and([
+					f("s", Operator::Eq, json!("b")),
+					f("n", Operator::Gt, json!(1))
+				]),
+			])
+			.matches(&doc)
+		);
+	}
+
+	#[tokio::test]
+	async fn test_memory_update_upsert_patch() {
+		let db = Memory::new();
+		let update = Write {
+			precondition: Precondition::Exists,
+			mask: None,
+		};
+		assert!(matches!(
+			db.write("user", "a", &json!({"name": "A"}), &update).await,
+			Err(Error::NotFound(_))
+		));
+		db.write(
+			"user",
+			"a",
+			&json!({"name": "A", "n": {"x": 1, "y": 2}}),
+			&Write::default(),
+		)
+		.await
+		.unwrap();
+		db.write("user", "a", &json!({"name": "B"}), &update)
+			.await
+			.unwrap();
+		assert_eq!(
+			db.get("user", "a").await.unwrap().map(|d| d.data),
+			Some(json!({"name": "B"}))
+		);
+		// mask に無いフィールドは保たれ、data に無いフィールドは消える
+		db.write(
+			"user",
+			"a",
+			&json!({"n": {"x": 1, "y": 2}, "other": true}),
+			&Write::default(),
+		)
+		.await
+		.unwrap();
+		let patch = Write {
+			precondition: Precondition::Exists,
+			mask: Some(vec![
+				"name".into(),
+				"n.x".into(),
+				"gone".into(),
+				"other".into(),
+			]),
+		};
+		db.write(
+			"user",
+			"a",
+			&json!({"name": "C", "n": {"x": 9, "y": 0}}),
+			&patch,
+		)
+		.await
+		.unwrap();
+		assert_eq!(
+			db.get("user", "a").await.unwrap().map(|d| d.data),
+			Some(json!({"name": "C", "n": {"x": 9, "y": 2}}))
+		);
+	}
+
+	#[tokio::test]
+	async fn test_memory_version_precondition() {
+		let db = Memory::new();
+		let v1 = db
+			.write("page", "a", &json!({"name": "A"}), &INSERT)
+			.await
+			.unwrap();
+		assert_eq!(db.get("page", "a").await.unwrap().unwrap().version, v1);
+		let at = |v: &Version| Write {
+			precondition: Precondition::Version(v.clone()),
+			mask: None,
+		};
+		let v2 = db
+			.write("page", "a", &json!({"name": "B"}), &at(&v1))
+			.await
+			.unwrap();
+		assert!(v2 > v1);
+		// 古い版を前提にした書き込みは失敗し、内容は変わらない
+		assert!(matches!(
+			db.write("page", "a", &json!({"name": "C"}), &at(&v1)).await,
+			Err(Error::Conflict(_))
+		));
+		assert_eq!(
+			db.get("page", "a").await.unwrap().map(|d| d.data),
+			Some(json!({"name": "B"}))
+		);
+	}
+
+	#[tokio::test]
+	async fn test_memory_transaction_conflict() {
+		let db = Memory::new();
+		db.write("page", "a", &json!({"n": 1}), &INSERT)
+			.await
+			.unwrap();
+		// 読んでから commit までに書き換えられたら何も反映しない
+		let mut tx = Transaction::default();
+		tx.get(&db, "page", "a").await.unwrap();
+		db.write("page", "a", &json!({"n": 2}), &Write::default())
+			.await
+			.unwrap();
+		tx.write("page", "a", json!({"n": 10}), Write::default());
+		tx.write("page", "b", json!({"n": 10}), INSERT);
+		assert!(matches!(db.commit(&tx).await, Err(Error::Conflict(_))));
+		assert_eq!(
+			db.get("page", "a").await.unwrap().map(|d| d.data),
+			Some(json!({"n": 2}))
+		);
+		assert_eq!(db.get("page", "b").await.unwrap(), None);
+		// クエリの結果に入るドキュメントが後から追加された場合も競合になる
+		let query = Query {
+			filter: Some(Filter::eq("n", 3)),
+			..Default::default()
+		};
+		let mut tx = Transaction::default();
+		assert!(tx.query(&db, "page", &query).await.unwrap().is_empty());
+		db.write("page", "c", &json!({"n": 3}), &INSERT)
+			.await
+			.unwrap();
+		tx.write("page", "d", json!({"n": 3}), INSERT);
+		assert!(matches!(db.commit(&tx).await, Err(Error::Conflict(_))));
+		assert_eq!(db.get("page", "d").await.unwrap(), None);
+	}
+
+	#[tokio::test]
+	async fn test_memory_transaction_atomic() {
+		let db = Memory::new();
+		db.write("page", "a", &json!({"n": 1}), &INSERT)
+			.await
+			.unwrap();
+		// 途中の書き込みの前提条件が満たされなければ、それより前の書き込みも反映しない
+		let mut tx = Transaction::default();
+		tx.write("page", "b", json!({"n": 2}), INSERT);
+		tx.delete("page", "a");
+		tx.write("page", "a", json!({"n": 3}), INSERT);
+		tx.write("page", "c", json!({"n": 4}), INSERT);
+		tx.write("page", "b", json!({"n": 5}), INSERT);
+		assert!(matches!(db.commit(&tx).await, Err(Error::AlreadyExists(_))));
+		assert_eq!(db.get("page", "b").await.unwrap(), None);
+		assert!(db.get("page", "a").await.unwrap().is_some());
+		let mut tx = Transaction::default();
+		tx.write("page", "b", json!({"n": 2}), INSERT);
+		tx.delete("page", "a");
+		db.commit(&tx).await.unwrap();
+		assert!(db.get("page", "b").await.unwrap().is_some());
+		assert_eq!(db.get("page", "a").await.unwrap(), None);
+	}
+
+	#[tokio::test]
+	async fn test_memory_watch() {
+		let db = Memory::new();
+		db.write("page", "old", &json!({"n": 0}), &INSERT)
+			.await
+			.unwrap();
+		let mut all = db.watch("page", None, None).await.unwrap();
+		let mut even = db
+			.watch("page", Some(Filter::eq("n", 2)), None)
+			.await
+			.unwrap();
+		let a = db
+			.write("page", "a", &json!({"n": 1}), &INSERT)
+			.await
+			.unwrap();
+		let mut tx = Transaction::default();
+		tx.write("page", "b", json!({"n": 2}), INSERT);
+		tx.delete("page", "a");
+		db.commit(&tx).await.unwrap();
+		// watch を始める前の変更は流れてこない
+		let Some(Change::Put(d)) = all.next().await else {
+			panic!()
+		};
+		assert_eq!((d.id.as_str(), &d.version), ("a", &a));
+		let Some(Change::Put(b)) = all.next().await else {
+			panic!()
+		};
+		assert_eq!(b.id, "b");
+		let Some(Change::Delete(id, deleted)) = all.next().await else {
+			panic!()
+		};
+		assert_eq!(id, "a");
+		assert!(deleted > b.version);
+		// 条件に合わない書き込みと、合っていなかったドキュメントの削除は飛ばす
+		assert_eq!(even.next().await, Some(Change::Put(b.clone())));
+		db.delete("page", "b").await.unwrap();
+		let Some(Change::Delete(b_id, _)) = even.next().await else {
+			panic!()
+		};
+		assert_eq!(b_id, "b");
+		// 受け取った版から再開すると、その続きが流れてくる
+		let mut resumed = db.watch("page", None, Some(a)).await.unwrap();
+		assert_eq!(resumed.next().await, Some(Change::Put(b)));
+		assert_eq!(resumed.next().await, Some(Change::Delete(id, deleted)));
+		// 1つのドキュメントの watch には他のドキュメントの削除は流れない
+		// 始める前に書かれたドキュメントでも、id の条件で分かる
+		db.write("page", "c", &json!({"id": "c"}), &INSERT)
+			.await
+			.unwrap();
+		let mut one = db
+			.watch("page", Some(Filter::eq("id", "c")), None)
+			.await
+			.unwrap();
+		db.delete("page", "old").await.unwrap();
+		db.delete("page", "c").await.unwrap();
+		assert!(matches!(one.next().await, Some(Change::Delete(id, _)) if id == "c"));
+		// 覚えていない版からは再開できない
+		let mut reset = db
+			.watch("page", None, Some(Memory::version(0)))
+			.await
+			.unwrap();
+		assert_eq!(reset.next().await, Some(Change::Reset));
+	}
+
+	#[tokio::test]
+	async fn test_changes_start() {
+		let changes = Changes::default();
+		let v = Memory::version;
+		// 受け取り始めた版が分かるまでは、再開しようとすると読み直しになる
+		let (mut early, created) = changes.subscribe("page", None, None, Some(v(1))).unwrap();
+		assert!(created && !changes.started("page"));
+		assert_eq!(early.next().await, Some(Change::Reset));
+		changes.start("page", v(2));
+		changes.start("page", v(5));
+		assert!(changes.started("page"));
+		changes.publish("page", Change::Delete("a".to_string(), v(3)));
+		let (mut resumed, created) = changes.subscribe("page", None, None, Some(v(2))).unwrap();
+		assert!(!created);
+		assert_eq!(
+			resumed.next().await,
+			Some(Change::Delete("a".to_string(), v(3)))
+		);
+		let (mut reset, _) = changes.subscribe("page", None, None, Some(v(1))).unwrap();
+		assert_eq!(reset.next().await, Some(Change::Reset));
+	}
+
+	#[test]
+	fn test_cursor_token() {
+		let query = Query {
+			order: vec![OrderBy::Desc("score")],
+			..Default::default()
+		};
+		let cursor = Cursor {
+			values: vec![json!(3)],
+			id: "a".to_string(),
+		};
+		let token = cursor.token("page", &query);
+		assert_eq!(Cursor::from_token(&token, "page", &query), Ok(cursor));
+		// 別のクエリや別のコレクションには使えない
+		let other = Query {
+			order: vec![OrderBy::Asc("score")],
+			..Default::default()
+		};
+		assert!(Cursor::from_token(&token, "page", &other).is_err());
+		assert!(Cursor::from_token(&token, "user", &query).is_err());
+		// 中身を書き換えたものは署名が合わない
+		let other = Cursor {
+			values: vec![json!(100)],
+			id: "z".to_string(),
+		}
+		.token("page", &query);
+		let part = |t: &str, i: usize| t.split('.').nth(i).unwrap().to_string();
+		let forged = format!(
+			"{}.{}.{}",
+			part(&token, 0),
+			part(&other, 1),
+			part(&token, 2)
+		);
+		assert!(matches!(
+			Cursor::from_token(&forged, "page", &query),
+			Err(Error::Invalid(_))
+		));
+		assert!(Cursor::from_token("garbage", "page", &query).is_err());
+	}
+
+	#[test]
+	fn test_fingerprint() {
+		let query = Query {
+			filter: Some(Filter::and([
+				Filter::eq("group", "x"),
+				Filter::Compare("n", Operator::Ge, json!(2)),
+			])),
+			order: vec![OrderBy::Desc("score")],
+			..Default::default()
+		};
+		// 配ったカーソルがビルドし直しても使えるように、値そのものを固定する
+		assert_eq!(
+			query.fingerprint("page"),
+			"ed69b53c3a344bbb2e84e24af2d1d5087717929fce9e917eb5af8824073738d4"
+		);
+		// limit やカーソルの位置は含めない
+		let next = Query {
+			limit: Some(1),
+			..query.clone()
+		};
+		assert_eq!(next.fingerprint("page"), query.fingerprint("page"));
+	}
+
+	#[test]
+	fn test_version_if_match() {
+		let v = Version("00000000000000000001".to_string());
+		assert_eq!(Version::from_if_match(&v.etag()), Some(v.clone()));
+		assert_eq!(Version::from_if_match(&format!("W/{}", v.etag())), None);
+		assert_eq!(Version::from_if_match("*"), None);
+		assert_eq!(
+			Version::firestore("2026-10-19T01:02:03.456Z").unwrap().0,
+			"2026-10-19T01:02:03.456000Z"
+		);
+	}
+}
@@ -0,0 +1,333 @@
+use crate::collection::Collection;
+use crate::error::Error;
+use crate::storage::{self, Filter, Precondition, Query, Storage, Write};
+use futures::TryStreamExt;
+use serde_json::{Value, json};
+use std::collections::{BTreeMap, BTreeSet};
+
+// 一意制約の索引ドキュメントを置くコレクション
+// Collection::unique_fields() のフィールドの値ごとに1つ、その値を持つドキュメントのIDを書いておく
+// 索引は本体と同じトランザクションで書くので、同じ値で同時に書き込んでもどちらかが失敗する
+pub const COLLECTION: &str = "unique";
+
+// collection の field が value であることを表す索引ドキュメントのID
+// 値が無いか空文字列のときは None、まだ設定していないフィールドどうしは重複とみなさない
+pub fn key(collection: &str, field: &str, value: &Value) -> Option<String> {
+	let value = match value {
+		Value::Null => return None,
+		Value::String(s) if s.is_empty() => return None,
+		Value::String(s) => s.clone(),
+		v => v.to_string(),
+	};
+	// ドキュメントIDに / は使えない
+	let escape = |s: &str| s.replace('%', "%25").replace('/', "%2F");
+	Some(format!("{}:{field}:{}", escape(collection), escape(&value)))
+}
+
+fn key_of(collection: &str, field: &str, data: &Value) -> Option<String> {
+	key(collection, field, data.get(field)?)
+}
+
+// 書き込みで作る索引と消す索引
+#[derive(Debug, Default, PartialEq)]
+pub struct Changes {
+	// フィールドと索引のID
+	pub added: Vec<(&'static str, String)>,
+	pub removed: Vec<String>,
+}
+
+// old を new に書き換えるときの Changes、old が None なら新規、new が None なら削除
+// mask があればそこに挙げたフィールドだけが書き換わる
+pub fn changes(
+	collection: &str,
+	fields: &[&'static str],
+	old: Option<&Value>,
+	new: Option<&Value>,
+	mask: Option<&[String]>,
+) -> Changes {
+	let mut changes = Changes::default();
+	for field in fields {
+		if let Some(mask) = mask
+			&& !mask.iter().any(|m| m == field)
+		{
+			continue;
+		}
+		let old = old.and_then(|v| key_of(collection, field, v));
+		let new = new.and_then(|v| key_of(collection, field, v));
+		if old == new {
+			continue;
+		}
+		changes.removed.extend(old);
+		if let Some(new) = new {
+			changes.added.push((field, new));
+		}
+	}
+	changes
+}
+
+// mask の書き込みで一意なフィールドが書き換わりうるか
+pub fn affects(fields: &[&str], mask: Option<&[String]>) -> bool {
+	match mask {
+		None => !fields.is_empty(),
+		Some(mask) => fields.iter().any(|f| mask.iter().any(|m| m == f)),
+	}
+}
+
+// changes を tx に加える、precondition は作る索引の前提条件
+pub fn apply(
+	tx: &mut storage::Transaction,
+	collection: &str,
+	document_id: &str,
+	changes: &Changes,
+	precondition: Precondition,
+) {
+	for index in &changes.removed {
+		tx.delete(COLLECTION, index);
+	}
+	for (field, index) in &changes.added {
+		let entry = json!({"collection": collection, "field": field, "id": document_id});
+		let write = Write {
+			precondition: precondition.clone(),
+			mask: None,
+		};
+		tx.write(COLLECTION, index, entry, write);
+	}
+}
+
+// 索引 index が他のドキュメントのもので、そのドキュメントが今も同じ値なら AlreadyExists
+// 消し損ねた古い索引なら上書きしてよいので Ok
+pub async fn check(
+	tx: &mut storage::Transaction,
+	db: &impl Storage,
+	collection: &str,
+	document_id: &str,
+	field: &str,
+	index: &str,
+) -> Result<(), Error> {
+	let Some(entry) = tx.get(db, COLLECTION, index).await? else {
+		return Ok(());
+	};
+	let owner = entry
+		.data
+		.get("id")
+		.and_then(Value::as_str)
+		.unwrap_or_default();
+	if owner == document_id {
+		return Ok(());
+	}
+	let current = tx.get(db, collection, owner).await?;
+	if current
+		.and_then(|d| key_of(collection, field, &d.data))
+		.as_deref()
+		== Some(index)
+	{
+		return Err(Error::AlreadyExists(format!(
+			"{collection}: {field} is already used by {owner}"
+		)));
+	}
+	Ok(())
+}
+
+// repair の結果
+#[derive(Debug, Default, PartialEq)]
+pub struct Report {
+	// 読んだドキュメントの数
+	pub scanned: usize,
+	// 同じ値を持つドキュメントのIDの組、フィールドと値ごと、人が見て直す
+	pub duplicates: Vec<(String, Value, Vec<String>)>,
+	// 無かったので作った(dry_run では作るべき)索引の数
+	pub created: usize,
+	// どのドキュメントの値でもなくなっていたので消した(dry_run では消すべき)索引の数
+	pub removed: usize,
+}
+
+// C のドキュメントをすべて読み、同じ値を持つものを探して、索引を今のドキュメントに合わせる
+// 一意制約を足す前に書かれたドキュメントの索引もこれで作る、重複している値の索引はそのままにする
+pub async fn repair<C: Collection>(db: &impl Storage, dry_run: bool) -> Result<Report, Error> {
+	let collection = C::collection_name();
+	let fields = C::unique_fields();
+	let mut report = Report::default();
+	// 索引のID → フィールド、値、その値を持つドキュメントのID
+	let mut owners: BTreeMap<String, (&str, Value, Vec<String>)> = BTreeMap::new();
+	for document in all(db, collection, &Query::default()).await? {
+		report.scanned += 1;
+		for field in fields {
+			let Some(value) = document.data.get(*field) else {
+				continue;
+			};
+			if let Some(index) = key(collection, field, value) {
+				owners
+					.entry(index)
+					.or_insert_with(|| (field, value.clone(), vec![]))
+					.2
+					.push(document.id.clone());
+			}
+		}
+	}
+	let query = Query {
+		filter: Some(Filter::eq("collection", collection)),
+		..Default::default()
+	};
+	let mut indexes = BTreeSet::new();
+	for entry in all(db, COLLECTION, &query).await? {
+		let owner = entry.data.get("id").and_then(Value::as_str);
+		match owners.get(&entry.id) {
+			Some((_, _, ids)) if ids.iter().any(|id| Some(id.as_str()) == owner) => {
+				indexes.insert(entry.id);
+			}
+			_ => {
+				report.removed += 1;
+				if !dry_run {
+					db.delete(COLLECTION, &entry.id).await?;
+				}
+			}
+		}
+	}
+	for (index, (field, value, ids)) in owners {
+		if ids.len() > 1 {
+			report.duplicates.push((field.to_string(), value, ids));
+		} else if !indexes.contains(&index) {
+			report.created += 1;
+			if !dry_run {
+				let entry = json!({"collection": collection, "field": field, "id": ids[0]});
+				db.write(COLLECTION, &index, &entry, &Write::default())
+					.await?;
+			}
+		}
+	}
+	Ok(report)
+}
+
+// query に合うドキュメントをすべて読む
+async fn all(
+	db: &impl Storage,
+	collection: &str,
+	query: &Query,
+) -> Result<Vec<storage::Document>, Error> {
+	storage::stream(db, collection.to_string(), query.clone())
+		.try_collect()
+		.await
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::storage::Memory;
+	use serde::{Deserialize, Serialize};
+
+	#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, Collection)]
+	struct Account {
+		id: String,
+		#[collection(unique)]
+		email: String,
+		name: String,
+	}
+
+	fn account(id: &str, email: &str) -> Account {
+		Account {
+			id: id.to_string(),
+			email: email.to_string(),
+			name: String::new(),
+		}
+	}
+
+	#[tokio::test]
+	async fn test_unique() {
+		let db = Memory::new();
+		account("a", "x@example.com").push(&db).await.unwrap();
+		assert!(matches!(
+			account("b", "x@example.com").push(&db).await,
+			Err(Error::AlreadyExists(_))
+		));
+		assert!(db.get("account", "b").await.unwrap().is_none());
+		// 空の値どうしは重複しない
+		account("b", "").push(&db).await.unwrap();
+		account("c", "").push(&db).await.unwrap();
+		// 値を変えると前の値は他のドキュメントが使える
+		account("a", "y@example.com").update(&db).await.unwrap();
+		account("b", "x@example.com").upsert(&db).await.unwrap();
+		assert!(matches!(
+			account("c", "y@example.com").update(&db).await,
+			Err(Error::AlreadyExists(_))
+		));
+		// 一意なフィールドを含まない patch は索引に触れない
+		let mut renamed = account("c", "x@example.com");
+		renamed.name = "C".to_string();
+		renamed.patch(&db, &["name"]).await.unwrap();
+		// 消すと値が空く
+		Account::pop(&db, "a").await.unwrap();
+		account("c", "y@example.com").update(&db).await.unwrap();
+		let report = repair::<Account>(&db, true).await.unwrap();
+		assert_eq!((report.scanned, report.created, report.removed), (2, 0, 0));
+		assert!(report.duplicates.is_empty());
+	}
+
+	#[tokio::test]
+	async fn test_unique_in_transaction() {
+		let db = Memory::new();
+		crate::collection::transaction(&db, async |tx| tx.push(&account("a", "x@example.com")))
+			.await
+			.unwrap();
+		let result =
+			crate::collection::transaction(&db, async |tx| tx.push(&account("b", "x@example.com")))
+				.await;
+		assert!(matches!(result, Err(Error::AlreadyExists(_))));
+		// 読んでいないドキュメントは索引を直せないので書き換えられない
+		let result = crate::collection::transaction(&db, async |tx| {
+			tx.update(&account("a", "y@example.com"))
+		})
+		.await;
+		assert!(matches!(result, Err(Error::Internal(_))));
+		crate::collection::transaction(&db, async |tx| {
+			tx.get::<Account>("a").await?;
+			tx.update(&account("a", "y@example.com"))
+		})
+		.await
+		.unwrap();
+		account("b", "x@example.com").push(&db).await.unwrap();
+	}
+
+	#[tokio::test]
+	async fn test_repair() {
+		let db = Memory::new();
+		// 一意制約を足す前に書かれたドキュメント
+		for (id, email) in [("a", "x"), ("b", "x"), ("c", "y"), ("d", "")] {
+			db.write(
+				"account",
+				id,
+				&json!({"id": id, "email": email, "name": ""}),
+				&Write::default(),
+			)
+			.await
+			.unwrap();
+		}
+		db.write(
+			COLLECTION,
+			"account:email:z",
+			&json!({"collection": "account", "field": "email", "id": "e"}),
+			&Write::default(),
+		)
+		.await
+		.unwrap();
+		let report = repair::<Account>(&db, true).await.unwrap();
+		assert_eq!(report.scanned, 4);
+		assert_eq!(
+			report.duplicates,
+			[(
+				"email".to_string(),
+				json!("x"),
+				vec!["a".to_string(), "b".to_string()]
+			)]
+		);
+		assert_eq!((report.created, report.removed), (1, 1));
+		let report = repair::<Account>(&db, false).await.unwrap();
+		assert_eq!((report.created, report.removed), (1, 1));
+		let report = repair::<Account>(&db, false).await.unwrap();
+		assert_eq!((report.created, report.removed), (0, 0));
+		assert!(matches!(
+			account("e", "y").push(&db).await,
+			Err(Error::AlreadyExists(_))
+		));
+		account("e", "z").push(&db).await.unwrap();
+	}
+}